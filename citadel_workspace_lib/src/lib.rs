@@ -2,6 +2,8 @@ use citadel_workspace_types::{InternalServicePayload, InternalServiceResponse};
 use tokio::net::TcpStream;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
+pub mod settings;
+
 pub fn deserialize(message: &[u8]) -> Option<InternalServicePayload> {
     bincode2::deserialize(message).ok()
 }
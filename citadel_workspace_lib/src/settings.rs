@@ -0,0 +1,198 @@
+use citadel_workspace_types::{InternalServicePayload, LocalDBGetKVSuccess, Settings};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use uuid::Uuid;
+
+// settings are kept under this prefix so they can be listed with LocalDBScanKV
+pub const SETTINGS_PREFIX: &str = "settings/";
+
+// the stored value: the schema version it was written with, then the encoded settings
+#[derive(Serialize, Deserialize)]
+struct SettingsRecord {
+    version: u32,
+    value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingsError {
+    // written by a newer client; callers should not overwrite it with their defaults
+    NewerVersion(u32),
+    // written by an older version that Settings::migrate could not convert
+    MigrationFailed(u32),
+    Corrupt(String),
+    // the settings could not be serialized
+    Encode(String),
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsError::NewerVersion(version) => {
+                write!(
+                    f,
+                    "Settings were written by a newer schema version {version}"
+                )
+            }
+            SettingsError::MigrationFailed(version) => {
+                write!(
+                    f,
+                    "Failed to migrate settings from schema version {version}"
+                )
+            }
+            SettingsError::Corrupt(message) => write!(f, "Corrupt settings: {message}"),
+            SettingsError::Encode(message) => write!(f, "Failed to encode settings: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+pub fn settings_key<S: Settings>() -> String {
+    format!("{SETTINGS_PREFIX}{}", S::NAMESPACE)
+}
+
+pub fn get_settings<S: Settings>(
+    uuid: Uuid,
+    cid: u64,
+    peer_cid: Option<u64>,
+) -> InternalServicePayload {
+    InternalServicePayload::LocalDBGetKV {
+        uuid,
+        cid,
+        peer_cid,
+        key: settings_key::<S>(),
+    }
+}
+
+pub fn set_settings<S: Settings>(
+    uuid: Uuid,
+    cid: u64,
+    peer_cid: Option<u64>,
+    settings: &S,
+) -> Result<InternalServicePayload, SettingsError> {
+    Ok(InternalServicePayload::LocalDBSetKV {
+        uuid,
+        cid,
+        peer_cid,
+        key: settings_key::<S>(),
+        value: encode_settings(settings)?,
+        ttl: None,
+    })
+}
+
+// missing settings decode to their defaults; migrated settings are not written back
+pub fn settings_from_response<S: Settings>(
+    response: &LocalDBGetKVSuccess,
+) -> Result<S, SettingsError> {
+    decode_settings(response.value.as_deref())
+}
+
+pub fn encode_settings<S: Settings>(settings: &S) -> Result<Vec<u8>, SettingsError> {
+    let record = SettingsRecord {
+        version: S::VERSION,
+        value: encode_value(settings)?,
+    };
+    encode_value(&record)
+}
+
+pub fn decode_settings<S: Settings>(stored: Option<&[u8]>) -> Result<S, SettingsError> {
+    let Some(stored) = stored else {
+        return Ok(S::default());
+    };
+
+    let record: SettingsRecord = decode_value(stored)?;
+    match record.version.cmp(&S::VERSION) {
+        Ordering::Equal => decode_value(&record.value),
+        Ordering::Less => S::migrate(record.version, &record.value)
+            .ok_or(SettingsError::MigrationFailed(record.version)),
+        Ordering::Greater => Err(SettingsError::NewerVersion(record.version)),
+    }
+}
+
+// the encoding of a settings value, for use by Settings::migrate on older layouts
+pub fn encode_value<T: Serialize>(value: &T) -> Result<Vec<u8>, SettingsError> {
+    bincode2::serialize(value).map_err(|err| SettingsError::Encode(err.to_string()))
+}
+
+pub fn decode_value<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SettingsError> {
+    bincode2::deserialize(bytes).map_err(|err| SettingsError::Corrupt(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+    struct Layout {
+        font_size: u32,
+    }
+
+    impl Settings for Layout {
+        const NAMESPACE: &'static str = "layout";
+        const VERSION: u32 = 2;
+
+        // version 1 stored the font size as a u16
+        fn migrate(version: u32, stored: &[u8]) -> Option<Self> {
+            let font_size: u16 = (version == 1).then(|| decode_value(stored).ok())??;
+            Some(Layout {
+                font_size: font_size.into(),
+            })
+        }
+    }
+
+    fn stored(version: u32, value: Vec<u8>) -> Vec<u8> {
+        encode_value(&SettingsRecord { version, value }).unwrap()
+    }
+
+    #[test]
+    fn missing_settings_decode_to_defaults() {
+        assert_eq!(decode_settings::<Layout>(None), Ok(Layout::default()));
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let layout = Layout { font_size: 14 };
+        let encoded = encode_settings(&layout).unwrap();
+        assert_eq!(
+            decode_settings::<Layout>(Some(encoded.as_slice())),
+            Ok(layout)
+        );
+    }
+
+    #[test]
+    fn older_settings_are_migrated() {
+        let encoded = stored(1, encode_value(&12u16).unwrap());
+        assert_eq!(
+            decode_settings::<Layout>(Some(encoded.as_slice())),
+            Ok(Layout { font_size: 12 })
+        );
+    }
+
+    #[test]
+    fn unconvertible_settings_fail_with_migration_failed() {
+        let encoded = stored(0, encode_value(&12u16).unwrap());
+        assert_eq!(
+            decode_settings::<Layout>(Some(encoded.as_slice())),
+            Err(SettingsError::MigrationFailed(0))
+        );
+    }
+
+    #[test]
+    fn newer_settings_fail_with_newer_version() {
+        let encoded = stored(3, encode_value(&Layout::default()).unwrap());
+        assert_eq!(
+            decode_settings::<Layout>(Some(encoded.as_slice())),
+            Err(SettingsError::NewerVersion(3))
+        );
+    }
+
+    #[test]
+    fn garbage_fails_with_corrupt() {
+        assert!(matches!(
+            decode_settings::<Layout>(Some(&[0xff][..])),
+            Err(SettingsError::Corrupt(_))
+        ));
+    }
+}
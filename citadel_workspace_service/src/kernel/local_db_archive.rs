@@ -0,0 +1,73 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::collections::HashMap;
+
+// layout: MAGIC | version (u16, big endian) | salt | nonce | ciphertext
+// the header is authenticated along with the ciphertext
+const MAGIC: &[u8; 6] = b"CWSKV\0";
+const VERSION: u16 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 2 + SALT_LEN + NONCE_LEN;
+
+pub(crate) fn seal(map: &HashMap<String, Vec<u8>>, password: &[u8]) -> Result<Vec<u8>, String> {
+    let plaintext = bincode2::serialize(map).map_err(|err| err.to_string())?;
+
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut archive = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+    archive.extend_from_slice(MAGIC);
+    archive.extend_from_slice(&VERSION.to_be_bytes());
+    archive.extend_from_slice(&salt);
+    archive.extend_from_slice(&nonce);
+
+    let ciphertext = cipher(password, &salt)?
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &plaintext,
+                aad: &archive,
+            },
+        )
+        .map_err(|_| "Failed to encrypt the archive".to_string())?;
+    archive.extend_from_slice(&ciphertext);
+    Ok(archive)
+}
+
+pub(crate) fn open(archive: &[u8], password: &[u8]) -> Result<HashMap<String, Vec<u8>>, String> {
+    if archive.len() < HEADER_LEN || &archive[..MAGIC.len()] != MAGIC {
+        return Err("Not a LocalDB archive".to_string());
+    }
+
+    let (header, ciphertext) = archive.split_at(HEADER_LEN);
+    let version = u16::from_be_bytes([header[MAGIC.len()], header[MAGIC.len() + 1]]);
+    if version != VERSION {
+        return Err(format!("Unsupported LocalDB archive version {version}"));
+    }
+
+    let salt = &header[MAGIC.len() + 2..MAGIC.len() + 2 + SALT_LEN];
+    let nonce = Nonce::from_slice(&header[HEADER_LEN - NONCE_LEN..]);
+    let plaintext = cipher(password, salt)?
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| "Wrong password or corrupted archive".to_string())?;
+
+    bincode2::deserialize(&plaintext).map_err(|err| err.to_string())
+}
+
+fn cipher(password: &[u8], salt: &[u8]) -> Result<ChaCha20Poly1305, String> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password, salt, &mut key)
+        .map_err(|err| err.to_string())?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
@@ -5,27 +5,87 @@ use citadel_sdk::prelude::VirtualTargetType;
 use citadel_sdk::prelude::*;
 use citadel_workspace_lib::{deserialize, serialize_payload, wrap_tcp_conn};
 use citadel_workspace_types::{
-    Disconnected, InternalServicePayload, InternalServiceResponse, ServiceConnectionAccepted,
+    parse_resumed_file_name, ClientQueueMetrics, Disconnected, IncomingFileTransfer,
+    InternalServicePayload, InternalServiceResponse, PeerSessionInformation, Pong, ReconnectPolicy,
+    ServiceConnectionAccepted, ServiceMetrics, SessionInformation,
 };
 use futures::stream::{SplitSink, StreamExt};
 use futures::SinkExt;
-use payload_handler::payload_handler;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use payload_handler::{
+    expire_pending_file_transfers, payload_handler, poll_peer_presence, reconnect_with_backoff,
+    refresh_peer_presence, sweep_expired_kv, KV_TTL_SWEEP_INTERVAL, PEER_PRESENCE_POLL_INTERVAL,
+};
+use response_queue::{response_queue, ResponseOverflowPolicy, ResponseReceiver, ResponseSender};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use uuid::Uuid;
 
+mod local_db_archive;
 pub(crate) mod payload_handler;
+pub mod response_queue;
 
 pub struct CitadelWorkspaceService {
     pub remote: Option<NodeRemote>,
     pub bind_address: SocketAddr,
     pub server_connection_map: Arc<Mutex<HashMap<u64, Connection>>>,
-    pub tcp_connection_map: Arc<Mutex<HashMap<Uuid, UnboundedSender<InternalServiceResponse>>>>,
+    pub tcp_connection_map: Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    pub client_settings: TcpClientSettings,
+    pub defaults: ServiceDefaults,
+    // addresses allowed to connect to the TCP listener; None accepts any
+    pub allowed_clients: Option<Vec<IpAddr>>,
+}
+
+// applied when a client does not choose for itself
+#[derive(Debug, Clone, Default)]
+pub struct ServiceDefaults {
+    // where received files go until the client sends SetDownloadDirectory
+    pub download_directory: Option<PathBuf>,
+    // given to sessions whose client does not send its own security settings
+    pub security_level: Option<SecurityLevel>,
+    pub secrecy_mode: Option<SecrecyMode>,
+}
+
+impl ServiceDefaults {
+    pub fn session_security_settings(&self) -> SessionSecuritySettings {
+        let mut settings = SessionSecuritySettings::default();
+        if let Some(security_level) = self.security_level {
+            settings.security_level = security_level;
+        }
+        if let Some(secrecy_mode) = self.secrecy_mode {
+            settings.secrecy_mode = secrecy_mode;
+        }
+        settings
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TcpClientSettings {
+    // responses waiting to be written to a client before the overflow policy applies
+    pub response_queue_capacity: usize,
+    pub overflow_policy: ResponseOverflowPolicy,
+    // clients that send nothing, not even a Ping, for this long are disconnected
+    pub idle_timeout: Option<Duration>,
+    // payloads from all clients waiting for the kernel; once full, clients stop being read from
+    pub kernel_queue_capacity: usize,
+}
+
+impl Default for TcpClientSettings {
+    fn default() -> Self {
+        Self {
+            response_queue_capacity: 1024,
+            overflow_policy: ResponseOverflowPolicy::default(),
+            idle_timeout: None,
+            kernel_queue_capacity: 1024,
+        }
+    }
 }
 
 impl CitadelWorkspaceService {
@@ -35,8 +95,26 @@ impl CitadelWorkspaceService {
             bind_address,
             server_connection_map: Arc::new(Mutex::new(Default::default())),
             tcp_connection_map: Arc::new(Mutex::new(Default::default())),
+            client_settings: TcpClientSettings::default(),
+            defaults: ServiceDefaults::default(),
+            allowed_clients: None,
         }
     }
+
+    pub fn with_client_settings(mut self, client_settings: TcpClientSettings) -> Self {
+        self.client_settings = client_settings;
+        self
+    }
+
+    pub fn with_defaults(mut self, defaults: ServiceDefaults) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    pub fn with_allowed_clients(mut self, allowed_clients: Vec<IpAddr>) -> Self {
+        self.allowed_clients = Some(allowed_clients);
+        self
+    }
 }
 
 #[allow(dead_code)]
@@ -45,40 +123,241 @@ pub struct Connection {
     client_server_remote: ClientServerRemote,
     peers: HashMap<u64, PeerConnection>,
     associated_tcp_connection: Uuid,
+    username: String,
+    server_address: Option<SocketAddr>,
+    session_security_settings: SessionSecuritySettings,
+    udp_mode: UdpMode,
+    download_directory: Option<PathBuf>,
+    pending_file_transfers: HashMap<u64, PendingFileTransfer>,
+    // transfers sent from this session that are still running
+    running_file_transfers: HashSet<Uuid>,
+    // what is left of failed receptions by sender and file name, for resumed transfers to append to
+    partial_receptions: HashMap<(Option<u64>, String), PathBuf>,
+    presence_subscribers: HashSet<Uuid>,
+    // the last status reported to presence subscribers, so repeated peer signals are not echoed
+    peer_presence: HashMap<u64, bool>,
+    udp_sink: Option<Sender<Vec<u8>>>,
+    connect_parameters: Option<ConnectParameters>,
+    kv_watchers: HashSet<KvWatcher>,
+    pending_kv_imports: HashMap<Uuid, PendingKvImport>,
+    // tickets of the Disconnects that tore down peer connections replaced by a forced PeerConnect
+    superseded_peer_disconnects: HashSet<Ticket>,
+    // the sorted user keys of each store (None for the C2S one) for LocalDBScanKV, built by the
+    // first scan and kept in step with every write after it
+    kv_key_index: HashMap<Option<u64>, Vec<String>>,
+    // the server read stream and UDP channel of the session, stopped once it is dropped
+    session_tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        for task in &self.session_tasks {
+            task.abort();
+        }
+    }
+}
+
+// a TCP client watching the C2S store (peer_cid None) or a P2P store for keys under prefix
+#[derive(Hash, PartialEq, Eq)]
+struct KvWatcher {
+    uuid: Uuid,
+    peer_cid: Option<u64>,
+    prefix: String,
+}
+
+// an incoming file transfer waiting for the TCP client to accept or reject it
+struct PendingFileTransfer {
+    handle: ObjectTransferHandler,
+    offered_at: Instant,
+}
+
+// an archive a TCP client is sending in pieces for LocalDBImport
+struct PendingKvImport {
+    uuid: Uuid,
+    next_index: usize,
+    archive: Vec<u8>,
+}
+
+// everything needed to re-authenticate a session, only kept when a reconnect policy was requested
+#[derive(Clone)]
+struct ConnectParameters {
+    username: String,
+    password: SecBuffer,
+    connect_mode: ConnectMode,
+    udp_mode: UdpMode,
+    keep_alive_timeout: Option<Duration>,
+    session_security_settings: SessionSecuritySettings,
+    reconnect_policy: Option<ReconnectPolicy>,
+    download_directory: Option<PathBuf>,
+}
+
+// a C2S session that dropped and is about to be reconnected
+struct DroppedSession {
+    uuid: Uuid,
+    cid: u64,
+    connect_parameters: ConnectParameters,
+    peers: Vec<PeerSessionInformation>,
 }
 
 #[allow(dead_code)]
 struct PeerConnection {
     sink: PeerChannelSendHalf,
     remote: SymmetricIdentifierHandle,
+    username: String,
+    session_security_settings: SessionSecuritySettings,
+    udp_mode: UdpMode,
+    read_task: JoinHandle<()>,
+    udp_sink: Option<Sender<Vec<u8>>>,
+    udp_task: Option<JoinHandle<()>>,
+}
+
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        self.read_task.abort();
+        if let Some(udp_task) = &self.udp_task {
+            udp_task.abort();
+        }
+    }
 }
 
 impl Connection {
     fn new(
-        sink: PeerChannelSendHalf,
+        mut sink: PeerChannelSendHalf,
         client_server_remote: ClientServerRemote,
         associated_tcp_connection: Uuid,
+        username: String,
+        server_address: Option<SocketAddr>,
+        session_security_settings: SessionSecuritySettings,
+        udp_mode: UdpMode,
     ) -> Self {
+        sink.set_security_level(session_security_settings.security_level);
         Connection {
             peers: HashMap::new(),
             sink_to_server: sink,
             client_server_remote,
             associated_tcp_connection,
+            username,
+            server_address,
+            session_security_settings,
+            udp_mode,
+            download_directory: None,
+            pending_file_transfers: HashMap::new(),
+            running_file_transfers: HashSet::new(),
+            partial_receptions: HashMap::new(),
+            presence_subscribers: HashSet::new(),
+            peer_presence: HashMap::new(),
+            udp_sink: None,
+            connect_parameters: None,
+            kv_watchers: HashSet::new(),
+            pending_kv_imports: HashMap::new(),
+            superseded_peer_disconnects: HashSet::new(),
+            kv_key_index: HashMap::new(),
+            session_tasks: Vec::new(),
         }
     }
 
-    fn add_peer_connection(
-        &mut self,
-        peer_cid: u64,
-        sink: PeerChannelSendHalf,
-        remote: SymmetricIdentifierHandle,
-    ) {
-        self.peers.insert(peer_cid, PeerConnection { sink, remote });
+    fn add_peer_connection(&mut self, peer_cid: u64, peer_connection: PeerConnection) {
+        self.peers.insert(peer_cid, peer_connection);
+    }
+
+    fn peer_information(&self, cid: u64) -> Vec<PeerSessionInformation> {
+        let mut peers: Vec<PeerSessionInformation> = self
+            .peers
+            .iter()
+            .map(|(peer_cid, peer)| PeerSessionInformation {
+                cid,
+                peer_cid: *peer_cid,
+                peer_username: peer.username.clone(),
+                session_security_settings: peer.session_security_settings,
+                udp_mode: peer.udp_mode,
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.peer_cid);
+        peers
+    }
+
+    fn session_information(&self, cid: u64) -> SessionInformation {
+        SessionInformation {
+            cid,
+            username: self.username.clone(),
+            server_address: self.server_address,
+            session_security_settings: self.session_security_settings,
+            udp_mode: self.udp_mode,
+            peers: self.peer_information(cid),
+        }
+    }
+
+    fn has_kv_watchers(&self, peer_cid: Option<u64>) -> bool {
+        self.kv_watchers
+            .iter()
+            .any(|watcher| watcher.peer_cid == peer_cid)
+    }
+
+    fn kv_watchers(&self, peer_cid: Option<u64>, key: &str) -> Vec<Uuid> {
+        let mut uuids: Vec<Uuid> = self
+            .kv_watchers
+            .iter()
+            .filter(|watcher| watcher.peer_cid == peer_cid && key.starts_with(&watcher.prefix))
+            .map(|watcher| watcher.uuid)
+            .collect();
+        uuids.sort();
+        uuids.dedup();
+        uuids
+    }
+
+    // a store whose index was not built yet is left alone, the next scan builds it from scratch
+    fn index_kv_key(&mut self, peer_cid: Option<u64>, key: &str, present: bool) {
+        let Some(keys) = self.kv_key_index.get_mut(&peer_cid) else {
+            return;
+        };
+        match (
+            keys.binary_search_by(|indexed| indexed.as_str().cmp(key)),
+            present,
+        ) {
+            (Err(position), true) => keys.insert(position, key.to_string()),
+            (Ok(position), false) => {
+                keys.remove(position);
+            }
+            _ => {}
+        }
     }
 
     fn clear_peer_connection(&mut self, peer_cid: u64) -> Option<PeerConnection> {
         self.peers.remove(&peer_cid)
     }
+
+    // drops everything a TCP client subscribed to once it disconnects from the service
+    fn forget_tcp_client(&mut self, uuid: Uuid) {
+        self.presence_subscribers.remove(&uuid);
+        self.kv_watchers.retain(|watcher| watcher.uuid != uuid);
+        self.pending_kv_imports
+            .retain(|_, pending| pending.uuid != uuid);
+        // nobody is left to accept the offers
+        if self.associated_tcp_connection == uuid {
+            for (object_id, mut pending) in self.pending_file_transfers.drain() {
+                if let Err(err) = pending.handle.decline() {
+                    warn!(target: "citadel", "Failed to decline file transfer {object_id}: {err:?}");
+                }
+            }
+        }
+    }
+}
+
+// the sink's security level is shared by everyone sending on the session, so an override is
+// reverted to the session default before the caller releases the connection map lock
+async fn send_message_with_security_level(
+    sink: &mut PeerChannelSendHalf,
+    session_security_settings: &SessionSecuritySettings,
+    security_level: Option<SecurityLevel>,
+    message: Vec<u8>,
+) -> Result<(), NetworkError> {
+    let Some(security_level) = security_level else {
+        return sink.send_message(message.into()).await;
+    };
+    sink.set_security_level(security_level);
+    let result = sink.send_message(message.into()).await;
+    sink.set_security_level(session_security_settings.security_level);
+    result
 }
 
 impl CitadelWorkspaceService {
@@ -93,6 +372,27 @@ impl CitadelWorkspaceService {
             .get_mut(&implicated_cid)?
             .clear_peer_connection(peer_cid)
     }
+
+    async fn is_superseded_disconnect(&self, implicated_cid: u64, ticket: Ticket) -> bool {
+        self.server_connection_map
+            .lock()
+            .await
+            .get_mut(&implicated_cid)
+            .map(|conn| conn.superseded_peer_disconnects.remove(&ticket))
+            .unwrap_or(false)
+    }
+
+    // asks the server in the background, so peer events are not held up waiting on it
+    fn spawn_peer_presence_refresh(&self, cid: u64) {
+        let Some(remote) = self.remote.clone() else {
+            return;
+        };
+        let server_connection_map = self.server_connection_map.clone();
+        let tcp_connection_map = self.tcp_connection_map.clone();
+        tokio::task::spawn(async move {
+            refresh_peer_presence(&server_connection_map, &tcp_connection_map, &remote, cid).await;
+        });
+    }
 }
 
 #[async_trait]
@@ -107,20 +407,45 @@ impl NetKernel for CitadelWorkspaceService {
         let remote_for_closure = remote.clone();
         let listener = tokio::net::TcpListener::bind(self.bind_address).await?;
 
-        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<InternalServicePayload>();
+        let client_settings = self.client_settings;
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<InternalServicePayload>(
+            client_settings.kernel_queue_capacity.max(1),
+        );
 
         let tcp_connection_map = &self.tcp_connection_map.clone();
+        let server_connection_map = &self.server_connection_map.clone();
+        let allowed_clients = self.allowed_clients.clone();
         let listener_task = async move {
-            while let Ok((conn, _addr)) = listener.accept().await {
-                let (tx1, rx1) = tokio::sync::mpsc::unbounded_channel::<InternalServiceResponse>();
+            while let Ok((conn, addr)) = listener.accept().await {
+                if allowed_clients
+                    .as_ref()
+                    .is_some_and(|allowed| !allowed.contains(&addr.ip()))
+                {
+                    warn!(target: "citadel", "Rejected TCP client {addr}, not an allowed client");
+                    continue;
+                }
+
+                let (tx1, rx1) = response_queue(
+                    client_settings.response_queue_capacity,
+                    client_settings.overflow_policy,
+                );
                 let id = Uuid::new_v4();
                 tcp_connection_map.lock().await.insert(id, tx1);
-                handle_connection(conn, tx.clone(), rx1, id);
+                handle_connection(
+                    conn,
+                    tx.clone(),
+                    rx1,
+                    id,
+                    client_settings.idle_timeout,
+                    tcp_connection_map.clone(),
+                    server_connection_map.clone(),
+                );
             }
             Ok(())
         };
 
         let server_connection_map = self.server_connection_map.clone();
+        let defaults = self.defaults.clone();
 
         let inbound_command_task = async move {
             while let Some(command) = rx.recv().await {
@@ -129,15 +454,45 @@ impl NetKernel for CitadelWorkspaceService {
                     &server_connection_map,
                     &mut remote,
                     tcp_connection_map,
+                    &defaults,
                 )
                 .await;
             }
             Ok(())
         };
 
+        let server_connection_map = self.server_connection_map.clone();
+        let tcp_connection_map = self.tcp_connection_map.clone();
+        let kv_ttl_sweep_task = async move {
+            let mut interval = tokio::time::interval(KV_TTL_SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                sweep_expired_kv(&server_connection_map, &tcp_connection_map).await;
+                expire_pending_file_transfers(&server_connection_map, &tcp_connection_map).await;
+            }
+        };
+
+        let server_connection_map = self.server_connection_map.clone();
+        let tcp_connection_map = self.tcp_connection_map.clone();
+        let presence_remote = remote_for_closure.clone();
+        let peer_presence_task = async move {
+            let mut interval = tokio::time::interval(PEER_PRESENCE_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                poll_peer_presence(
+                    &server_connection_map,
+                    &tcp_connection_map,
+                    &presence_remote,
+                )
+                .await;
+            }
+        };
+
         let res = tokio::select! {
             res0 = listener_task => res0,
             res1 = inbound_command_task => res1,
+            _ = kv_ttl_sweep_task => Ok(()),
+            _ = peer_presence_task => Ok(()),
         };
 
         citadel_logging::warn!(target: "citadel", "Shutting down service because a critical task finished. {res:?}");
@@ -152,9 +507,29 @@ impl NetKernel for CitadelWorkspaceService {
                     match conn {
                         VirtualTargetType::LocalGroupServer { implicated_cid } => {
                             let mut server_connection_map = self.server_connection_map.lock().await;
-                            server_connection_map.remove(&implicated_cid);
+                            let Some(mut conn) = server_connection_map.remove(&implicated_cid)
+                            else {
+                                return Ok(());
+                            };
                             // TODO: send disconnect signal to the TCP connection
                             // interested in this c2s connection
+                            if let (Some(connect_parameters), Some(remote)) =
+                                (conn.connect_parameters.take(), self.remote.clone())
+                            {
+                                let session = DroppedSession {
+                                    uuid: conn.associated_tcp_connection,
+                                    cid: implicated_cid,
+                                    connect_parameters,
+                                    peers: conn.peer_information(implicated_cid),
+                                };
+                                tokio::spawn(reconnect_with_backoff(
+                                    remote,
+                                    self.server_connection_map.clone(),
+                                    self.tcp_connection_map.clone(),
+                                    self.defaults.clone(),
+                                    session,
+                                ));
+                            }
                         }
                         VirtualTargetType::LocalGroupPeer {
                             implicated_cid,
@@ -171,19 +546,29 @@ impl NetKernel for CitadelWorkspaceService {
                     }
                 }
             }
-            NodeResult::PeerEvent(event) => {
-                if let PeerSignal::Disconnect(
+            NodeResult::PeerEvent(event) => match event.event {
+                PeerSignal::Disconnect(
                     PeerConnectionType::LocalGroupPeer {
                         implicated_cid,
                         peer_cid,
                     },
                     _,
-                ) = event.event
-                {
+                ) => {
+                    // the old connection is already gone, and clearing by peer_cid would remove
+                    // the one that replaced it
+                    if self
+                        .is_superseded_disconnect(implicated_cid, event.ticket)
+                        .await
+                    {
+                        return Ok(());
+                    }
+
                     let _did_remove = self
                         .clear_peer_connection(implicated_cid, peer_cid)
                         .await
                         .is_some();
+                    // the peer may only have closed the channel, so ask whether it is still online
+                    self.spawn_peer_presence_refresh(implicated_cid);
 
                     let server_conn_map = self.server_connection_map.clone();
                     let lock = server_conn_map.lock().await;
@@ -196,6 +581,81 @@ impl NetKernel for CitadelWorkspaceService {
                         send_response_to_tcp_client(&self.tcp_connection_map, response, uuid).await;
                     }
                 }
+                // a peer asking to register or connect may have just come online, whichever
+                // side of the signal we are
+                PeerSignal::PostConnect(
+                    PeerConnectionType::LocalGroupPeer {
+                        implicated_cid,
+                        peer_cid,
+                    },
+                    ..,
+                )
+                | PeerSignal::PostRegister(
+                    PeerConnectionType::LocalGroupPeer {
+                        implicated_cid,
+                        peer_cid,
+                    },
+                    ..,
+                ) => {
+                    let cid = if self
+                        .server_connection_map
+                        .lock()
+                        .await
+                        .contains_key(&implicated_cid)
+                    {
+                        implicated_cid
+                    } else {
+                        peer_cid
+                    };
+                    self.spawn_peer_presence_refresh(cid);
+                }
+                _ => {}
+            },
+
+            NodeResult::ObjectTransferHandle(ObjectTransferHandle { handle, .. }) => {
+                if !matches!(
+                    handle.orientation,
+                    ObjectTransferOrientation::Receiver { .. }
+                ) {
+                    return Ok(());
+                }
+
+                let implicated_cid = handle.receiver;
+                let mut server_connection_map = self.server_connection_map.lock().await;
+                if let Some(conn) = server_connection_map.get_mut(&implicated_cid) {
+                    let peer_cid = conn
+                        .peers
+                        .contains_key(&handle.source)
+                        .then_some(handle.source);
+                    let object_id = handle.metadata.object_id;
+                    let (file_name, resume_offset) =
+                        match parse_resumed_file_name(&handle.metadata.name) {
+                            Some((file_name, offset)) => (file_name.to_string(), Some(offset)),
+                            None => (handle.metadata.name.clone(), None),
+                        };
+                    let response =
+                        InternalServiceResponse::IncomingFileTransfer(IncomingFileTransfer {
+                            cid: implicated_cid,
+                            peer_cid,
+                            object_id,
+                            file_name,
+                            resume_offset,
+                            file_size: handle.metadata.plaintext_length,
+                            transfer_type: handle.metadata.transfer_type.clone(),
+                        });
+                    let uuid = conn.associated_tcp_connection;
+                    // the handle stays here until the TCP client accepts or rejects it
+                    conn.pending_file_transfers.insert(
+                        object_id,
+                        PendingFileTransfer {
+                            handle,
+                            offered_at: Instant::now(),
+                        },
+                    );
+                    send_response_to_tcp_client(&self.tcp_connection_map, response, uuid).await;
+                } else {
+                    warn!(target: "citadel", "Received a file transfer for unknown session {implicated_cid}");
+                }
             }
 
             _ => {}
@@ -210,17 +670,35 @@ impl NetKernel for CitadelWorkspaceService {
 }
 
 async fn send_response_to_tcp_client(
-    hash_map: &Arc<tokio::sync::Mutex<HashMap<Uuid, UnboundedSender<InternalServiceResponse>>>>,
+    hash_map: &Arc<tokio::sync::Mutex<HashMap<Uuid, ResponseSender>>>,
     response: InternalServiceResponse,
     uuid: Uuid,
 ) {
-    hash_map
-        .lock()
-        .await
-        .get(&uuid)
-        .unwrap()
-        .send(response)
-        .unwrap()
+    // the client may have disconnected or been dropped for not reading its responses
+    match hash_map.lock().await.get(&uuid) {
+        Some(entry) => {
+            if entry.send(response).is_err() {
+                info!(target: "citadel", "tx not sent");
+            }
+        }
+        None => info!(target: "citadel", "Hash map connection not found"),
+    }
+}
+
+// Unlike send_response_to_tcp_client, this skips clients that are no longer connected
+async fn broadcast_response_to_tcp_clients(
+    hash_map: &Arc<tokio::sync::Mutex<HashMap<Uuid, ResponseSender>>>,
+    response: InternalServiceResponse,
+    uuids: impl IntoIterator<Item = Uuid>,
+) {
+    let lock = hash_map.lock().await;
+    for uuid in uuids {
+        if let Some(entry) = lock.get(&uuid) {
+            if entry.send(response.clone()).is_err() {
+                info!(target: "citadel", "tx not sent");
+            }
+        }
+    }
 }
 
 fn create_client_server_remote(
@@ -241,24 +719,69 @@ async fn sink_send_payload(
     }
 }
 
-fn send_to_kernel(
+// waits for room in the kernel queue, which in turn stops the caller from reading the socket
+async fn send_to_kernel(
     payload_to_send: &[u8],
-    sender: &UnboundedSender<InternalServicePayload>,
+    conn_id: Uuid,
+    sender: &Sender<InternalServicePayload>,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
 ) -> Result<(), NetworkError> {
-    if let Some(payload) = deserialize(payload_to_send) {
-        sender.send(payload)?;
-        Ok(())
-    } else {
-        error!(target: "citadel", "w task: failed to deserialize payload");
-        Ok(())
+    match deserialize(payload_to_send) {
+        // answered here so the metrics and liveness checks work while the kernel is backed up.
+        // The reply goes to the connection that asked, whatever uuid the payload carries
+        Some(InternalServicePayload::GetServiceMetrics { .. }) => {
+            let response = service_metrics(sender, tcp_connection_map).await;
+            send_response_to_tcp_client(tcp_connection_map, response, conn_id).await;
+            Ok(())
+        }
+        Some(InternalServicePayload::Ping { nonce, .. }) => {
+            let response = InternalServiceResponse::Pong(Pong { id: conn_id, nonce });
+            send_response_to_tcp_client(tcp_connection_map, response, conn_id).await;
+            Ok(())
+        }
+        Some(payload) => {
+            sender.send(payload).await?;
+            Ok(())
+        }
+        None => {
+            error!(target: "citadel", "w task: failed to deserialize payload");
+            Ok(())
+        }
     }
 }
 
+async fn service_metrics(
+    sender: &Sender<InternalServicePayload>,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+) -> InternalServiceResponse {
+    let mut clients: Vec<ClientQueueMetrics> = tcp_connection_map
+        .lock()
+        .await
+        .iter()
+        .map(|(id, queue)| ClientQueueMetrics {
+            id: *id,
+            depth: queue.len(),
+            capacity: queue.capacity(),
+            dropped: queue.dropped(),
+        })
+        .collect();
+    clients.sort_by_key(|client| client.id);
+
+    InternalServiceResponse::ServiceMetrics(ServiceMetrics {
+        kernel_queue_depth: sender.max_capacity() - sender.capacity(),
+        kernel_queue_capacity: sender.max_capacity(),
+        clients,
+    })
+}
+
 fn handle_connection(
     conn: TcpStream,
-    to_kernel: UnboundedSender<InternalServicePayload>,
-    mut from_kernel: tokio::sync::mpsc::UnboundedReceiver<InternalServiceResponse>,
+    to_kernel: Sender<InternalServicePayload>,
+    mut from_kernel: ResponseReceiver,
     conn_id: Uuid,
+    idle_timeout: Option<Duration>,
+    tcp_connection_map: Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    server_connection_map: Arc<Mutex<HashMap<u64, Connection>>>,
 ) {
     tokio::task::spawn(async move {
         let framed = wrap_tcp_conn(conn);
@@ -277,11 +800,35 @@ fn handle_connection(
             }
         };
 
+        let tcp_connection_map_for_read = tcp_connection_map.clone();
         let read_task = async move {
-            while let Some(message) = stream.next().await {
+            loop {
+                let message = match idle_timeout {
+                    Some(idle_timeout) => {
+                        match tokio::time::timeout(idle_timeout, stream.next()).await {
+                            Ok(message) => message,
+                            Err(_) => {
+                                warn!(target: "citadel", "Client {conn_id} idle for {idle_timeout:?}, disconnecting");
+                                break;
+                            }
+                        }
+                    }
+                    None => stream.next().await,
+                };
+                let Some(message) = message else {
+                    break;
+                };
+
                 match message {
                     Ok(message) => {
-                        if let Err(err) = send_to_kernel(&message, &to_kernel) {
+                        if let Err(err) = send_to_kernel(
+                            &message,
+                            conn_id,
+                            &to_kernel,
+                            &tcp_connection_map_for_read,
+                        )
+                        .await
+                        {
                             error!(target: "citadel", "Failed to send to kernel: {:?}", err);
                             break;
                         }
@@ -298,5 +845,10 @@ fn handle_connection(
             res0 = write_task => res0,
             res1 = read_task => res1,
         }
+
+        tcp_connection_map.lock().await.remove(&conn_id);
+        for conn in server_connection_map.lock().await.values_mut() {
+            conn.forget_tcp_client(conn_id);
+        }
     });
 }
@@ -1,32 +1,91 @@
-use crate::kernel::{create_client_server_remote, send_response_to_tcp_client, Connection};
+use crate::kernel::local_db_archive;
+use crate::kernel::response_queue::ResponseSender;
+use crate::kernel::{
+    broadcast_response_to_tcp_clients, create_client_server_remote,
+    send_message_with_security_level, send_response_to_tcp_client, ConnectParameters, Connection,
+    DroppedSession, KvWatcher, PeerConnection, PendingFileTransfer, PendingKvImport,
+    ServiceDefaults,
+};
 use async_recursion::async_recursion;
-use citadel_logging::{error, info};
+use citadel_logging::{error, info, warn};
 use citadel_sdk::prefabs::ClientServerRemote;
 use citadel_sdk::prelude::*;
 use citadel_workspace_types::{
-    ConnectionFailure, DisconnectFailure, Disconnected, InternalServicePayload,
-    InternalServiceResponse, LocalDBClearAllKVFailure, LocalDBClearAllKVSuccess,
-    LocalDBDeleteKVFailure, LocalDBDeleteKVSuccess, LocalDBGetAllKVFailure, LocalDBGetAllKVSuccess,
-    LocalDBGetKVFailure, LocalDBGetKVSuccess, LocalDBSetKVFailure, LocalDBSetKVSuccess,
+    parse_resumed_file_name, resumed_file_name, AcceptFileTransferFailure,
+    AcceptFileTransferSuccess, ConnectionFailure, DeleteVirtualFileFailure,
+    DeleteVirtualFileSuccess, DeregisterFailure, DeregisterSuccess, DisconnectFailure,
+    Disconnected, DownloadFileFailure, DownloadFileSuccess, FileReceiveFailure, FileReceived,
+    FileTransferInfo, GetSessionInfoFailure, GetSessionInfoSuccess, HypernodePeerInformation,
+    InternalServicePayload, InternalServiceResponse, ListAllHypernodePeersFailure,
+    ListAllHypernodePeersSuccess, ListConnectionsSuccess, ListFileTransfersFailure,
+    ListFileTransfersSuccess, ListPeersFailure, ListPeersSuccess, ListRegisteredPeersFailure,
+    ListRegisteredPeersSuccess, ListVirtualDirectoryFailure, ListVirtualDirectorySuccess,
+    LocalDBBatchFailure, LocalDBBatchOperation, LocalDBBatchSuccess, LocalDBChanged,
+    LocalDBClearAllKVFailure, LocalDBClearAllKVSuccess, LocalDBCompareAndSwapFailure,
+    LocalDBCompareAndSwapSuccess, LocalDBDeleteKVFailure, LocalDBDeleteKVSuccess, LocalDBErrorCode,
+    LocalDBExportChunk, LocalDBExportFailure, LocalDBExportSuccess, LocalDBGetAllKVFailure,
+    LocalDBGetAllKVSuccess, LocalDBGetChunkFailure, LocalDBGetChunkSuccess, LocalDBGetKVFailure,
+    LocalDBGetKVSuccess, LocalDBImportChunkReceived, LocalDBImportFailure, LocalDBImportMode,
+    LocalDBImportSource, LocalDBImportSuccess, LocalDBPutChunkFailure, LocalDBPutChunkSuccess,
+    LocalDBScanKVFailure, LocalDBScanKVSuccess, LocalDBSetKVFailure, LocalDBSetKVSuccess,
+    LocalDBUnwatchFailure, LocalDBUnwatchSuccess, LocalDBWatchFailure, LocalDBWatchSuccess,
     MessageReceived, MessageSendError, MessageSent, PeerConnectFailure, PeerConnectSuccess,
-    PeerDisconnectFailure, PeerDisconnectSuccess, PeerRegisterFailure, PeerRegisterSuccess,
-    SendFileFailure, SendFileSuccess,
+    PeerDeregisterFailure, PeerDeregisterSuccess, PeerDisconnectFailure, PeerDisconnectSuccess,
+    PeerPresenceChanged, PeerRegisterFailure, PeerRegisterSuccess, PeersRestored, ReconnectFailure,
+    Reconnected, Reconnecting, RejectFileTransferFailure, RejectFileTransferSuccess,
+    SendFileFailure, SendFileProgress, SendFileSuccess, SendUnreliableFailure, SessionInformation,
+    SetDownloadDirectoryFailure, SetDownloadDirectorySuccess, StatVirtualFileFailure,
+    StatVirtualFileSuccess, SubscribePeerPresenceFailure, SubscribePeerPresenceSuccess,
+    UnreliableMessageReceived, UnreliableMessageSent, UnsubscribePeerPresenceFailure,
+    UnsubscribePeerPresenceSuccess, VirtualFileMetadata,
 };
 use futures::StreamExt;
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::mpsc::UnboundedSender;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncSeekExt;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
 use uuid::Uuid;
+
+// keys under this prefix are managed by the service and hidden from LocalDBGetAllKV
+const INTERNAL_KV_PREFIX: &str = "__citadel/";
+// a log of the RE-VFS uploads made through this service, since the server cannot list them
+const VIRTUAL_FS_INDEX_PREFIX: &str = "__citadel/revfs/";
+const FILE_TRANSFER_PREFIX: &str = "__citadel/transfers/";
+// holds the expiry of each key set with a ttl
+const KV_TTL_PREFIX: &str = "__citadel/ttl/";
+// values stored with LocalDBPutChunk: a manifest per key, pointing at the chunks of one upload
+const KV_CHUNK_MANIFEST_PREFIX: &str = "__citadel/chunked/";
+const KV_CHUNK_PREFIX: &str = "__citadel/chunks/";
+// uploads that are still receiving chunks
+const KV_CHUNK_STAGING_PREFIX: &str = "__citadel/staging/";
+// an upload that receives no chunk for this long is dropped by the sweep
+const KV_CHUNK_STAGING_TTL: Duration = Duration::from_secs(60 * 60);
+// bounds the size of the record tracking which chunks of an upload arrived
+const KV_MAX_CHUNKS: usize = 1 << 20;
+pub(crate) const KV_TTL_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// an IncomingFileTransfer the TCP client has not answered by then is declined
+const PENDING_FILE_TRANSFER_TIMEOUT: Duration = Duration::from_secs(300);
+// progress of a running transfer is written to the LocalDB at most this often
+const FILE_TRANSFER_PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+// datagrams beyond this are rejected instead of queued, since UDP delivery is best effort anyway
+const UDP_SEND_QUEUE_CAPACITY: usize = 128;
+// keeps each LocalDBExportChunk well under the frame limit of the TCP codec
+const KV_EXPORT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
 #[async_recursion]
 pub async fn payload_handler(
     command: InternalServicePayload,
     server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
     remote: &mut NodeRemote,
-    tcp_connection_map: &Arc<
-        tokio::sync::Mutex<HashMap<Uuid, UnboundedSender<InternalServiceResponse>>>,
-    >,
+    tcp_connection_map: &Arc<tokio::sync::Mutex<HashMap<Uuid, ResponseSender>>>,
+    defaults: &ServiceDefaults,
 ) {
     match command {
         InternalServicePayload::Connect {
@@ -37,57 +96,43 @@ pub async fn payload_handler(
             udp_mode,
             keep_alive_timeout,
             session_security_settings,
+            reconnect_policy,
         } => {
-            match remote
-                .connect(
-                    AuthenticationRequest::credentialed(username, password),
-                    connect_mode,
-                    udp_mode,
-                    keep_alive_timeout,
-                    session_security_settings,
-                )
-                .await
+            let session_security_settings =
+                session_security_settings.unwrap_or_else(|| defaults.session_security_settings());
+            let connect_parameters = ConnectParameters {
+                username,
+                password,
+                connect_mode,
+                udp_mode,
+                keep_alive_timeout,
+                session_security_settings,
+                reconnect_policy,
+                download_directory: defaults.download_directory.clone(),
+            };
+            match connect_to_server(
+                remote,
+                server_connection_map,
+                tcp_connection_map,
+                uuid,
+                connect_parameters,
+            )
+            .await
             {
-                Ok(conn_success) => {
-                    let cid = conn_success.cid;
-
-                    let (sink, mut stream) = conn_success.channel.split();
-                    let client_server_remote =
-                        create_client_server_remote(stream.vconn_type, remote.clone());
-                    let connection_struct = Connection::new(sink, client_server_remote, uuid);
-                    server_connection_map
-                        .lock()
-                        .await
-                        .insert(cid, connection_struct);
-
-                    let hm_for_conn = tcp_connection_map.clone();
-
+                Ok((cid, stream)) => {
                     let response = InternalServiceResponse::ConnectSuccess(
                         citadel_workspace_types::ConnectSuccess { cid },
                     );
 
                     send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
-
-                    let connection_read_stream = async move {
-                        while let Some(message) = stream.next().await {
-                            let message =
-                                InternalServiceResponse::MessageReceived(MessageReceived {
-                                    message: message.into_buffer(),
-                                    cid,
-                                    peer_cid: 0,
-                                });
-                            match hm_for_conn.lock().await.get(&uuid) {
-                                Some(entry) => match entry.send(message) {
-                                    Ok(res) => res,
-                                    Err(_) => info!(target: "citadel", "tx not sent"),
-                                },
-                                None => {
-                                    info!(target:"citadel","Hash map connection not found")
-                                }
-                            }
-                        }
-                    };
-                    tokio::spawn(connection_read_stream);
+                    spawn_server_read_stream(
+                        stream,
+                        server_connection_map,
+                        tcp_connection_map.clone(),
+                        uuid,
+                        cid,
+                    )
+                    .await;
                 }
 
                 Err(err) => {
@@ -107,6 +152,8 @@ pub async fn payload_handler(
             connect_after_register,
             default_security_settings,
         } => {
+            let default_security_settings =
+                default_security_settings.unwrap_or_else(|| defaults.session_security_settings());
             info!(target: "citadel", "About to connect to server {server_addr:?} for user {username}");
             match remote
                 .register(
@@ -135,7 +182,8 @@ pub async fn payload_handler(
                                 keep_alive_timeout: None,
                                 udp_mode: Default::default(),
                                 connect_mode: Default::default(),
-                                session_security_settings: default_security_settings,
+                                session_security_settings: Some(default_security_settings),
+                                reconnect_policy: None,
                             };
 
                             payload_handler(
@@ -143,6 +191,7 @@ pub async fn payload_handler(
                                 server_connection_map,
                                 remote,
                                 tcp_connection_map,
+                                defaults,
                             )
                             .await
                         }
@@ -158,6 +207,55 @@ pub async fn payload_handler(
                 }
             };
         }
+        InternalServicePayload::Deregister { uuid, cid } => {
+            let client_to_server_remote = ClientServerRemote::new(
+                VirtualTargetType::LocalGroupServer {
+                    implicated_cid: cid,
+                },
+                remote.clone(),
+            );
+            let result = if server_connection_map.lock().await.contains_key(&cid) {
+                // peers can only be resolved while the account still exists
+                let mut peer_remotes = Vec::new();
+                match remote.get_local_group_mutual_peers(cid).await {
+                    Ok(peers) => {
+                        for peer_cid in peers.into_iter().map(|peer| peer.cid) {
+                            match client_to_server_remote.find_target(cid, peer_cid).await {
+                                Ok(peer_remote) => peer_remotes.push((peer_cid, peer_remote)),
+                                Err(err) => {
+                                    warn!(target: "citadel", "Failed to resolve peer {peer_cid}: {err:?}");
+                                }
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        warn!(target: "citadel", "Failed to list the peers of {cid}: {err:?}");
+                    }
+                }
+                client_to_server_remote
+                    .deregister()
+                    .await
+                    .map(|_| peer_remotes)
+                    .map_err(|err| err.into_string())
+            } else {
+                Err("Server connection not found".to_string())
+            };
+
+            let response = match result {
+                Ok(peer_remotes) => {
+                    // dropping the connection stops its read streams, UDP channels and peer
+                    // connections, and takes its presence subscribers and KV watchers with it
+                    server_connection_map.lock().await.remove(&cid);
+                    clear_local_db(&client_to_server_remote, &peer_remotes).await;
+                    InternalServiceResponse::DeregisterSuccess(DeregisterSuccess { cid })
+                }
+                Err(message) => {
+                    InternalServiceResponse::DeregisterFailure(DeregisterFailure { cid, message })
+                }
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
         InternalServicePayload::Message {
             uuid,
             message,
@@ -165,54 +263,87 @@ pub async fn payload_handler(
             peer_cid,
             security_level,
         } => {
-            match server_connection_map.lock().await.get_mut(&cid) {
+            let result = match server_connection_map.lock().await.get_mut(&cid) {
                 Some(conn) => {
                     if let Some(peer_cid) = peer_cid {
                         // send to peer
-                        if let Some(peer_conn) = conn.peers.get_mut(&peer_cid) {
-                            peer_conn.sink.set_security_level(security_level);
-                            // TODO no unwraps on send_message. We need to handle errors properly
-                            peer_conn.sink.send_message(message.into()).await.unwrap();
-                        } else {
-                            // TODO: refactor all connection not found messages, we have too many duplicates
-                            info!(target: "citadel","connection not found");
-                            send_response_to_tcp_client(
-                                tcp_connection_map,
-                                InternalServiceResponse::MessageSendError(MessageSendError {
-                                    cid,
-                                    message: format!("Connection for {cid} not found"),
-                                }),
-                                uuid,
+                        match conn.peers.get_mut(&peer_cid) {
+                            Some(peer_conn) => send_message_with_security_level(
+                                &mut peer_conn.sink,
+                                &peer_conn.session_security_settings,
+                                security_level,
+                                message,
                             )
-                            .await;
+                            .await
+                            .map_err(|err| err.into_string()),
+                            None => Err(format!("Peer connection for {peer_cid} not found")),
                         }
                     } else {
                         // send to server
-                        conn.sink_to_server.set_security_level(security_level);
-                        conn.sink_to_server
-                            .send_message(message.into())
-                            .await
-                            .unwrap();
+                        send_message_with_security_level(
+                            &mut conn.sink_to_server,
+                            &conn.session_security_settings,
+                            security_level,
+                            message,
+                        )
+                        .await
+                        .map_err(|err| err.into_string())
                     }
+                }
+                None => Err(format!("Connection for {cid} not found")),
+            };
 
-                    let response =
-                        InternalServiceResponse::MessageSent(MessageSent { cid, peer_cid });
-                    send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
-                    info!(target: "citadel", "Into the message handler command send")
+            let response = match result {
+                Ok(()) => InternalServiceResponse::MessageSent(MessageSent { cid, peer_cid }),
+                Err(message) => {
+                    info!(target: "citadel", "Message not sent: {message}");
+                    InternalServiceResponse::MessageSendError(MessageSendError { cid, message })
                 }
-                None => {
-                    info!(target: "citadel","connection not found");
-                    send_response_to_tcp_client(
-                        tcp_connection_map,
-                        InternalServiceResponse::MessageSendError(MessageSendError {
-                            cid,
-                            message: format!("Connection for {cid} not found"),
-                        }),
-                        uuid,
-                    )
-                    .await;
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
+        InternalServicePayload::SendUnreliable {
+            uuid,
+            cid,
+            peer_cid,
+            payload,
+        } => {
+            let result = match server_connection_map.lock().await.get(&cid) {
+                Some(conn) => match peer_cid {
+                    Some(peer_cid) => conn
+                        .peers
+                        .get(&peer_cid)
+                        .map(|peer| peer.udp_sink.clone())
+                        .ok_or_else(|| "Peer connection not found".to_string()),
+                    None => Ok(conn.udp_sink.clone()),
+                }
+                .and_then(|udp_sink| {
+                    udp_sink.ok_or_else(|| "UDP is not enabled for this connection".to_string())
+                })
+                .and_then(|udp_sink| {
+                    udp_sink.try_send(payload).map_err(|err| match err {
+                        TrySendError::Full(_) => "UDP send queue is full".to_string(),
+                        TrySendError::Closed(_) => "UDP channel closed".to_string(),
+                    })
+                }),
+                None => Err("Server connection not found".to_string()),
+            };
+
+            let response = match result {
+                Ok(()) => InternalServiceResponse::UnreliableMessageSent(UnreliableMessageSent {
+                    cid,
+                    peer_cid,
+                }),
+                Err(message) => {
+                    InternalServiceResponse::SendUnreliableFailure(SendUnreliableFailure {
+                        cid,
+                        peer_cid,
+                        message,
+                    })
                 }
             };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
         }
 
         InternalServicePayload::Disconnect { cid, uuid } => {
@@ -249,8 +380,35 @@ pub async fn payload_handler(
             uuid,
             source,
             cid,
+            peer_cid,
             chunk_size,
             transfer_type,
+        } => {
+            let transfer = FileTransferInfo {
+                transfer_id: Uuid::new_v4(),
+                cid,
+                peer_cid,
+                source,
+                chunk_size,
+                transfer_type,
+                chunks_confirmed: 0,
+                total_chunks: 0,
+                interrupted: false,
+            };
+            start_file_transfer(
+                transfer,
+                server_connection_map,
+                remote,
+                tcp_connection_map,
+                uuid,
+            )
+            .await;
+        }
+
+        InternalServicePayload::ResumeFileTransfer {
+            uuid,
+            cid,
+            transfer_id,
         } => {
             let client_to_server_remote = ClientServerRemote::new(
                 VirtualTargetType::LocalGroupServer {
@@ -258,25 +416,51 @@ pub async fn payload_handler(
                 },
                 remote.clone(),
             );
-            match client_to_server_remote
-                .send_file_with_custom_opts(source, chunk_size, transfer_type)
-                .await
-            {
-                Ok(_) => {
+            match get_file_transfer(&client_to_server_remote, transfer_id).await {
+                // the SDK cannot append to a file on the server, and starting over would
+                // report a resume that did not happen
+                Ok(Some(transfer)) if transfer.peer_cid.is_none() => {
+                    if let Err(err) =
+                        remove_file_transfer(&client_to_server_remote, transfer_id).await
+                    {
+                        warn!(target: "citadel", "Failed to remove file transfer {transfer_id}: {err}");
+                    }
                     send_response_to_tcp_client(
                         tcp_connection_map,
-                        InternalServiceResponse::SendFileSuccess(SendFileSuccess { cid }),
+                        InternalServiceResponse::SendFileFailure(SendFileFailure {
+                            cid,
+                            peer_cid: None,
+                            transfer_id: None,
+                            message: format!(
+                                "File transfer {transfer_id} was an upload to the server, which the SDK cannot resume. Send the file again"
+                            ),
+                        }),
                         uuid,
                     )
                     .await;
                 }
-
-                Err(err) => {
+                Ok(Some(transfer)) => {
+                    start_file_transfer(
+                        transfer,
+                        server_connection_map,
+                        remote,
+                        tcp_connection_map,
+                        uuid,
+                    )
+                    .await;
+                }
+                result => {
+                    let message = match result {
+                        Ok(_) => format!("File transfer {transfer_id} not found"),
+                        Err(message) => message,
+                    };
                     send_response_to_tcp_client(
                         tcp_connection_map,
                         InternalServiceResponse::SendFileFailure(SendFileFailure {
                             cid,
-                            message: err.into_string(),
+                            peer_cid: None,
+                            transfer_id: None,
+                            message,
                         }),
                         uuid,
                     )
@@ -285,22 +469,339 @@ pub async fn payload_handler(
             }
         }
 
+        InternalServicePayload::AcceptFileTransfer {
+            uuid,
+            cid,
+            object_id,
+        } => {
+            let pending = match server_connection_map.lock().await.get_mut(&cid) {
+                Some(conn) => match conn.pending_file_transfers.remove(&object_id) {
+                    Some(PendingFileTransfer { handle, .. }) => {
+                        let peer_cid = conn
+                            .peers
+                            .contains_key(&handle.source)
+                            .then_some(handle.source);
+                        Ok((handle, peer_cid, conn.download_directory.clone()))
+                    }
+                    None => Err(format!("File transfer {object_id} not found")),
+                },
+                None => Err("Server connection not found".to_string()),
+            };
+
+            let pending = match pending {
+                Ok((mut handle, peer_cid, download_directory)) => {
+                    match resume_target(server_connection_map, cid, peer_cid, &handle.metadata.name)
+                        .await
+                    {
+                        Ok(resume_onto) => Ok((handle, peer_cid, download_directory, resume_onto)),
+                        Err(message) => {
+                            if let Err(err) = handle.decline() {
+                                warn!(target: "citadel", "Failed to decline file transfer {object_id}: {err:?}");
+                            }
+                            Err(message)
+                        }
+                    }
+                }
+                Err(message) => Err(message),
+            };
+
+            match pending {
+                Ok((mut handle, peer_cid, download_directory, resume_onto)) => {
+                    match handle.accept() {
+                        Ok(_) => {
+                            send_response_to_tcp_client(
+                                tcp_connection_map,
+                                InternalServiceResponse::AcceptFileTransferSuccess(
+                                    AcceptFileTransferSuccess { cid, object_id },
+                                ),
+                                uuid,
+                            )
+                            .await;
+                            let file_name = handle.metadata.name.clone();
+                            let file_name = parse_resumed_file_name(&file_name)
+                                .map(|(file_name, _)| file_name.to_string())
+                                .unwrap_or(file_name);
+                            let server_connection_map = server_connection_map.clone();
+                            let tcp_connection_map = tcp_connection_map.clone();
+                            tokio::task::spawn(async move {
+                                let partial = receive_file(
+                                    handle,
+                                    download_directory,
+                                    resume_onto,
+                                    &tcp_connection_map,
+                                    uuid,
+                                    cid,
+                                    peer_cid,
+                                )
+                                .await;
+                                if let Some(conn) = server_connection_map.lock().await.get_mut(&cid)
+                                {
+                                    match partial {
+                                        Some(partial) => {
+                                            conn.partial_receptions
+                                                .insert((peer_cid, file_name), partial);
+                                        }
+                                        None => {
+                                            conn.partial_receptions.remove(&(peer_cid, file_name));
+                                        }
+                                    }
+                                }
+                            });
+                        }
+                        Err(err) => {
+                            send_response_to_tcp_client(
+                                tcp_connection_map,
+                                InternalServiceResponse::AcceptFileTransferFailure(
+                                    AcceptFileTransferFailure {
+                                        cid,
+                                        object_id,
+                                        message: err.into_string(),
+                                    },
+                                ),
+                                uuid,
+                            )
+                            .await;
+                        }
+                    }
+                }
+                Err(message) => {
+                    send_response_to_tcp_client(
+                        tcp_connection_map,
+                        InternalServiceResponse::AcceptFileTransferFailure(
+                            AcceptFileTransferFailure {
+                                cid,
+                                object_id,
+                                message,
+                            },
+                        ),
+                        uuid,
+                    )
+                    .await;
+                }
+            }
+        }
+
+        InternalServicePayload::RejectFileTransfer {
+            uuid,
+            cid,
+            object_id,
+        } => {
+            let pending = match server_connection_map.lock().await.get_mut(&cid) {
+                Some(conn) => conn
+                    .pending_file_transfers
+                    .remove(&object_id)
+                    .map(|pending| pending.handle)
+                    .ok_or_else(|| format!("File transfer {object_id} not found")),
+                None => Err("Server connection not found".to_string()),
+            };
+
+            let response =
+                match pending
+                    .and_then(|mut handle| handle.decline().map_err(|err| err.into_string()))
+                {
+                    Ok(_) => InternalServiceResponse::RejectFileTransferSuccess(
+                        RejectFileTransferSuccess { cid, object_id },
+                    ),
+                    Err(message) => InternalServiceResponse::RejectFileTransferFailure(
+                        RejectFileTransferFailure {
+                            cid,
+                            object_id,
+                            message,
+                        },
+                    ),
+                };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
+        InternalServicePayload::SetDownloadDirectory { uuid, cid, path } => {
+            // the directory is created before the connection map is locked
+            let response = match tokio::fs::create_dir_all(&path).await {
+                Ok(_) => match server_connection_map.lock().await.get_mut(&cid) {
+                    Some(conn) => {
+                        conn.download_directory = Some(path.clone());
+                        // kept across reconnects
+                        if let Some(connect_parameters) = conn.connect_parameters.as_mut() {
+                            connect_parameters.download_directory = Some(path.clone());
+                        }
+                        InternalServiceResponse::SetDownloadDirectorySuccess(
+                            SetDownloadDirectorySuccess { cid, path },
+                        )
+                    }
+                    None => InternalServiceResponse::SetDownloadDirectoryFailure(
+                        SetDownloadDirectoryFailure {
+                            cid,
+                            message: "Server connection not found".to_string(),
+                        },
+                    ),
+                },
+                Err(err) => InternalServiceResponse::SetDownloadDirectoryFailure(
+                    SetDownloadDirectoryFailure {
+                        cid,
+                        message: err.to_string(),
+                    },
+                ),
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
         InternalServicePayload::DownloadFile {
-            virtual_path: _,
-            transfer_security_level: _,
-            delete_on_pull: _,
-            cid: _,
-            uuid: _,
+            virtual_path,
+            transfer_security_level,
+            delete_on_pull,
+            cid,
+            uuid,
+        } => {
+            let download_directory = server_connection_map
+                .lock()
+                .await
+                .get(&cid)
+                .map(|conn| conn.download_directory.clone());
+            let client_to_server_remote = ClientServerRemote::new(
+                VirtualTargetType::LocalGroupServer {
+                    implicated_cid: cid,
+                },
+                remote.clone(),
+            );
+            let tcp_connection_map = tcp_connection_map.clone();
+            // the pull may take a while, so we do not block the inbound command loop on it
+            tokio::task::spawn(async move {
+                let result = match download_directory {
+                    Some(download_directory) => {
+                        download_virtual_file(
+                            &client_to_server_remote,
+                            &virtual_path,
+                            transfer_security_level,
+                            delete_on_pull,
+                            download_directory,
+                        )
+                        .await
+                    }
+                    None => Err("Server connection not found".to_string()),
+                };
+                let response = match result {
+                    Ok(path) => InternalServiceResponse::DownloadFileSuccess(DownloadFileSuccess {
+                        cid,
+                        virtual_path,
+                        path,
+                    }),
+                    Err(message) => {
+                        InternalServiceResponse::DownloadFileFailure(DownloadFileFailure {
+                            cid,
+                            virtual_path,
+                            message,
+                        })
+                    }
+                };
+                send_response_to_tcp_client(&tcp_connection_map, response, uuid).await;
+            });
+        }
+
+        InternalServicePayload::ListVirtualDirectory {
+            uuid,
+            cid,
+            virtual_directory,
         } => {
-            // let mut client_to_server_remote = ClientServerRemote::new(VirtualTargetType::LocalGroupServer { implicated_cid: cid }, remote.clone());
-            // match client_to_server_remote.(virtual_path, transfer_security_level, delete_on_pull).await {
-            //     Ok(_) => {
+            let response = match server_connection_map.lock().await.get(&cid) {
+                Some(conn) => {
+                    match list_virtual_directory(&conn.client_server_remote, &virtual_directory)
+                        .await
+                    {
+                        Ok((directories, files)) => {
+                            InternalServiceResponse::ListVirtualDirectorySuccess(
+                                ListVirtualDirectorySuccess {
+                                    cid,
+                                    virtual_directory,
+                                    directories,
+                                    files,
+                                },
+                            )
+                        }
+                        Err(message) => InternalServiceResponse::ListVirtualDirectoryFailure(
+                            ListVirtualDirectoryFailure { cid, message },
+                        ),
+                    }
+                }
+                None => InternalServiceResponse::ListVirtualDirectoryFailure(
+                    ListVirtualDirectoryFailure {
+                        cid,
+                        message: "Server connection not found".to_string(),
+                    },
+                ),
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
 
-            //     },
-            //     Err(err) => {
+        InternalServicePayload::StatVirtualFile {
+            uuid,
+            cid,
+            virtual_path,
+        } => {
+            let result = match server_connection_map.lock().await.get(&cid) {
+                Some(conn) => get_virtual_file(&conn.client_server_remote, &virtual_path)
+                    .await
+                    .and_then(|metadata| {
+                        metadata.ok_or_else(|| "Virtual file not found".to_string())
+                    }),
+                None => Err("Server connection not found".to_string()),
+            };
+            let response = match result {
+                Ok(metadata) => {
+                    InternalServiceResponse::StatVirtualFileSuccess(StatVirtualFileSuccess {
+                        cid,
+                        metadata,
+                    })
+                }
+                Err(message) => {
+                    InternalServiceResponse::StatVirtualFileFailure(StatVirtualFileFailure {
+                        cid,
+                        virtual_path,
+                        message,
+                    })
+                }
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
 
-            //     }
-            // }
+        InternalServicePayload::DeleteVirtualFile {
+            uuid,
+            cid,
+            virtual_path,
+        } => {
+            let result = if server_connection_map.lock().await.contains_key(&cid) {
+                let client_to_server_remote = ClientServerRemote::new(
+                    VirtualTargetType::LocalGroupServer {
+                        implicated_cid: cid,
+                    },
+                    remote.clone(),
+                );
+                match client_to_server_remote
+                    .remote_encrypted_virtual_filesystem_delete(virtual_path.clone())
+                    .await
+                {
+                    Ok(_) => client_to_server_remote
+                        .remove(&virtual_file_key(&virtual_path))
+                        .await
+                        .map(|_| ())
+                        .map_err(|err| err.into_string()),
+                    Err(err) => Err(err.into_string()),
+                }
+            } else {
+                Err("Server connection not found".to_string())
+            };
+            let response =
+                match result {
+                    Ok(_) => InternalServiceResponse::DeleteVirtualFileSuccess(
+                        DeleteVirtualFileSuccess { cid, virtual_path },
+                    ),
+                    Err(message) => InternalServiceResponse::DeleteVirtualFileFailure(
+                        DeleteVirtualFileFailure {
+                            cid,
+                            virtual_path,
+                            message,
+                        },
+                    ),
+                };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
         }
 
         InternalServicePayload::StartGroup {
@@ -329,6 +830,8 @@ pub async fn payload_handler(
             cid,
             peer_id: peer_username,
             connect_after_register,
+            udp_mode,
+            session_security_settings,
         } => {
             let client_to_server_remote = ClientServerRemote::new(
                 VirtualTargetType::LocalGroupServer {
@@ -345,47 +848,98 @@ pub async fn payload_handler(
                     match symmetric_identifier_handle_ref.register_to_peer().await {
                         Ok(_peer_register_success) => {
                             let account_manager = symmetric_identifier_handle_ref.account_manager();
-                            if let Ok(target_information) = account_manager
+                            match account_manager
                                 .find_target_information(cid, peer_username.clone())
                                 .await
                             {
-                                let (peer_cid, mutual_peer) = target_information.unwrap();
-                                match connect_after_register {
-                                    true => {
-                                        let connect_command = InternalServicePayload::PeerConnect {
-                                            uuid,
-                                            cid,
-                                            username: mutual_peer.username.unwrap(),
-                                            peer_cid,
-                                            peer_username: String::from("peer.a"),
-                                            udp_mode: Default::default(),
-                                            session_security_settings: Default::default(),
-                                        };
-
-                                        payload_handler(
-                                            connect_command,
-                                            server_connection_map,
-                                            remote,
-                                            tcp_connection_map,
-                                        )
-                                        .await;
-                                    }
-                                    false => {
-                                        println!("{:?}", mutual_peer);
-                                        send_response_to_tcp_client(
-                                            tcp_connection_map,
-                                            InternalServiceResponse::PeerRegisterSuccess(
-                                                PeerRegisterSuccess {
-                                                    cid,
-                                                    peer_cid,
-                                                    username: mutual_peer.username.unwrap(),
-                                                },
-                                            ),
-                                            uuid,
-                                        )
-                                        .await;
+                                // the first field is our own cid, the peer's is on mutual_peer
+                                Ok(Some((_, mutual_peer))) => {
+                                    let peer_cid = mutual_peer.cid;
+                                    let peer_username = mutual_peer.username.unwrap_or_default();
+                                    send_response_to_tcp_client(
+                                        tcp_connection_map,
+                                        InternalServiceResponse::PeerRegisterSuccess(
+                                            PeerRegisterSuccess {
+                                                cid,
+                                                peer_cid,
+                                                username: peer_username.clone(),
+                                            },
+                                        ),
+                                        uuid,
+                                    )
+                                    .await;
+
+                                    // the connect reports back through its own responses
+                                    if connect_after_register {
+                                        let local_username = server_connection_map
+                                            .lock()
+                                            .await
+                                            .get(&cid)
+                                            .map(|conn| conn.username.clone());
+                                        match local_username {
+                                            Some(username) if !peer_username.is_empty() => {
+                                                let connect_command =
+                                                    InternalServicePayload::PeerConnect {
+                                                        uuid,
+                                                        cid,
+                                                        username,
+                                                        peer_cid,
+                                                        peer_username,
+                                                        udp_mode,
+                                                        session_security_settings,
+                                                        force: false,
+                                                    };
+                                                payload_handler(
+                                                    connect_command,
+                                                    server_connection_map,
+                                                    remote,
+                                                    tcp_connection_map,
+                                                    defaults,
+                                                )
+                                                .await;
+                                            }
+                                            _ => {
+                                                let message = "Could not resolve the usernames \
+                                                    needed to connect to the peer"
+                                                    .to_string();
+                                                send_response_to_tcp_client(
+                                                    tcp_connection_map,
+                                                    InternalServiceResponse::PeerConnectFailure(
+                                                        PeerConnectFailure { cid, message },
+                                                    ),
+                                                    uuid,
+                                                )
+                                                .await;
+                                            }
+                                        }
                                     }
                                 }
+                                Ok(None) => {
+                                    send_response_to_tcp_client(
+                                        tcp_connection_map,
+                                        InternalServiceResponse::PeerRegisterFailure(
+                                            PeerRegisterFailure {
+                                                cid,
+                                                message: "Registered peer not found".to_string(),
+                                            },
+                                        ),
+                                        uuid,
+                                    )
+                                    .await;
+                                }
+                                Err(err) => {
+                                    send_response_to_tcp_client(
+                                        tcp_connection_map,
+                                        InternalServiceResponse::PeerRegisterFailure(
+                                            PeerRegisterFailure {
+                                                cid,
+                                                message: err.into_string(),
+                                            },
+                                        ),
+                                        uuid,
+                                    )
+                                    .await;
+                                }
                             }
                         }
 
@@ -425,8 +979,56 @@ pub async fn payload_handler(
             peer_username,
             udp_mode,
             session_security_settings,
+            force,
         } => {
-            // TODO: check to see if peer is already in the hashmap
+            let mut already_connected = false;
+            let mut teardown = None;
+            if let Some(conn) = server_connection_map.lock().await.get_mut(&cid) {
+                if force {
+                    // dropping the old PeerConnection also stops its read task. The ticket of its
+                    // Disconnect is recorded first, so the event cannot race the new connection
+                    if conn.clear_peer_connection(peer_cid).is_some() {
+                        let ticket = remote.get_next_ticket();
+                        conn.superseded_peer_disconnects.insert(ticket);
+                        teardown = Some(ticket);
+                    }
+                } else {
+                    already_connected = conn.peers.contains_key(&peer_cid);
+                }
+            }
+
+            if let Some(ticket) = teardown {
+                let request = NodeRequest::PeerCommand(PeerCommand {
+                    implicated_cid: cid,
+                    command: PeerSignal::Disconnect(
+                        PeerConnectionType::LocalGroupPeer {
+                            implicated_cid: cid,
+                            peer_cid,
+                        },
+                        None,
+                    ),
+                });
+                if let Err(err) = remote.send_with_custom_ticket(ticket, request).await {
+                    warn!(target: "citadel", "Failed to tear down peer connection {peer_cid}: {err:?}");
+                    if let Some(conn) = server_connection_map.lock().await.get_mut(&cid) {
+                        conn.superseded_peer_disconnects.remove(&ticket);
+                    }
+                }
+            }
+
+            if already_connected {
+                send_response_to_tcp_client(
+                    tcp_connection_map,
+                    InternalServiceResponse::PeerConnectSuccess(PeerConnectSuccess {
+                        cid,
+                        already_connected: true,
+                    }),
+                    uuid,
+                )
+                .await;
+                return;
+            }
+
             let client_to_server_remote = ClientServerRemote::new(
                 VirtualTargetType::LocalGroupPeer {
                     implicated_cid: cid,
@@ -435,7 +1037,7 @@ pub async fn payload_handler(
                 remote.clone(),
             );
             match client_to_server_remote
-                .find_target(username, peer_username)
+                .find_target(username, peer_username.clone())
                 .await
             {
                 // username or cid?
@@ -446,17 +1048,23 @@ pub async fn payload_handler(
                     {
                         Ok(peer_connect_success) => {
                             let connection_cid = peer_connect_success.channel.get_peer_cid();
-                            let (sink, mut stream) = peer_connect_success.channel.split();
-                            server_connection_map
-                                .lock()
-                                .await
-                                .get_mut(&cid)
-                                .unwrap()
-                                .add_peer_connection(
-                                    peer_cid,
-                                    sink,
-                                    symmetric_identifier_handle_ref.into_owned(),
-                                );
+                            let (mut sink, mut stream) = peer_connect_success.channel.split();
+                            sink.set_security_level(session_security_settings.security_level);
+                            let mut server_connection_map = server_connection_map.lock().await;
+                            let Some(conn) = server_connection_map.get_mut(&cid) else {
+                                send_response_to_tcp_client(
+                                    tcp_connection_map,
+                                    InternalServiceResponse::PeerConnectFailure(
+                                        PeerConnectFailure {
+                                            cid,
+                                            message: "Server connection not found".to_string(),
+                                        },
+                                    ),
+                                    uuid,
+                                )
+                                .await;
+                                return;
+                            };
 
                             let hm_for_conn = tcp_connection_map.clone();
 
@@ -464,6 +1072,7 @@ pub async fn payload_handler(
                                 tcp_connection_map,
                                 InternalServiceResponse::PeerConnectSuccess(PeerConnectSuccess {
                                     cid,
+                                    already_connected: false,
                                 }),
                                 uuid,
                             )
@@ -491,7 +1100,32 @@ pub async fn payload_handler(
                                     }
                                 }
                             };
-                            tokio::spawn(connection_read_stream);
+                            let read_task = tokio::spawn(connection_read_stream);
+                            let (udp_sink, udp_task) = peer_connect_success
+                                .udp_rx_opt
+                                .map(|udp_rx| {
+                                    spawn_udp_channel(
+                                        udp_rx,
+                                        tcp_connection_map.clone(),
+                                        uuid,
+                                        cid,
+                                        Some(peer_cid),
+                                    )
+                                })
+                                .unzip();
+                            conn.add_peer_connection(
+                                peer_cid,
+                                PeerConnection {
+                                    sink,
+                                    remote: symmetric_identifier_handle_ref.into_owned(),
+                                    username: peer_username,
+                                    session_security_settings,
+                                    udp_mode,
+                                    read_task,
+                                    udp_sink,
+                                    udp_task,
+                                },
+                            );
                         }
 
                         Err(err) => {
@@ -543,22 +1177,36 @@ pub async fn payload_handler(
                         tcp_connection_map,
                         InternalServiceResponse::PeerDisconnectFailure(PeerDisconnectFailure {
                             cid,
+                            peer_cid,
                             message: "Server connection not found".to_string(),
                         }),
                         uuid,
                     )
                     .await;
                 }
-                Some(conn) => match conn.peers.get_mut(&cid) {
+                Some(conn) => match conn.peers.get_mut(&peer_cid) {
                     None => {
-                        // TODO: handle none case
+                        send_response_to_tcp_client(
+                            tcp_connection_map,
+                            InternalServiceResponse::PeerDisconnectFailure(PeerDisconnectFailure {
+                                cid,
+                                peer_cid,
+                                message: "Peer connection not found".to_string(),
+                            }),
+                            uuid,
+                        )
+                        .await;
                     }
                     Some(target_peer) => match target_peer.remote.send(request).await {
                         Ok(ticket) => {
                             conn.clear_peer_connection(peer_cid);
                             let peer_disconnect_success =
                                 InternalServiceResponse::PeerDisconnectSuccess(
-                                    PeerDisconnectSuccess { cid, ticket: 0 },
+                                    PeerDisconnectSuccess {
+                                        cid,
+                                        peer_cid,
+                                        ticket: ticket.0,
+                                    },
                                 );
                             send_response_to_tcp_client(
                                 tcp_connection_map,
@@ -575,6 +1223,7 @@ pub async fn payload_handler(
                                 InternalServiceResponse::PeerDisconnectFailure(
                                     PeerDisconnectFailure {
                                         cid,
+                                        peer_cid,
                                         message: error_message,
                                     },
                                 );
@@ -589,6 +1238,243 @@ pub async fn payload_handler(
                 },
             }
         }
+        InternalServicePayload::PeerDeregister {
+            uuid,
+            cid,
+            peer_cid,
+        } => {
+            let client_to_server_remote = ClientServerRemote::new(
+                VirtualTargetType::LocalGroupServer {
+                    implicated_cid: cid,
+                },
+                remote.clone(),
+            );
+            let result = match client_to_server_remote.find_target(cid, peer_cid).await {
+                Ok(symmetric_identifier_handle_ref) => {
+                    match symmetric_identifier_handle_ref.deregister().await {
+                        Ok(_) => {
+                            if let Err(err) = symmetric_identifier_handle_ref.remove_all().await {
+                                warn!(target: "citadel", "Failed to clear local data for peer {peer_cid}: {err:?}");
+                            }
+                            Ok(())
+                        }
+                        Err(err) => Err(err.into_string()),
+                    }
+                }
+                Err(err) => Err(err.into_string()),
+            };
+
+            let response = match result {
+                Ok(_) => {
+                    if let Some(conn) = server_connection_map.lock().await.get_mut(&cid) {
+                        conn.clear_peer_connection(peer_cid);
+                    }
+                    InternalServiceResponse::PeerDeregisterSuccess(PeerDeregisterSuccess {
+                        cid,
+                        peer_cid,
+                    })
+                }
+                Err(message) => {
+                    InternalServiceResponse::PeerDeregisterFailure(PeerDeregisterFailure {
+                        cid,
+                        peer_cid,
+                        message,
+                    })
+                }
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
+        InternalServicePayload::ListConnections { uuid } => {
+            let mut sessions: Vec<SessionInformation> = server_connection_map
+                .lock()
+                .await
+                .iter()
+                .map(|(cid, conn)| conn.session_information(*cid))
+                .collect();
+            sessions.sort_by_key(|session| session.cid);
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::ListConnectionsSuccess(ListConnectionsSuccess {
+                    sessions,
+                }),
+                uuid,
+            )
+            .await;
+        }
+
+        InternalServicePayload::Ping { .. } | InternalServicePayload::GetServiceMetrics { .. } => {
+            // unreachable: send_to_kernel answers both on the connection that sent them and
+            // never forwards them to the kernel
+        }
+
+        InternalServicePayload::ListRegisteredPeers { uuid, cid } => {
+            let peers = if server_connection_map.lock().await.contains_key(&cid) {
+                remote
+                    .get_local_group_mutual_peers(cid)
+                    .await
+                    .map_err(|err| err.into_string())
+            } else {
+                Err("Server connection not found".to_string())
+            };
+            let response = match peers {
+                Ok(peers) => InternalServiceResponse::ListRegisteredPeersSuccess(
+                    ListRegisteredPeersSuccess {
+                        cid,
+                        peers: hypernode_peer_information(peers),
+                    },
+                ),
+                Err(message) => InternalServiceResponse::ListRegisteredPeersFailure(
+                    ListRegisteredPeersFailure { cid, message },
+                ),
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
+        InternalServicePayload::ListAllHypernodePeers { uuid, cid } => {
+            let peers = if server_connection_map.lock().await.contains_key(&cid) {
+                remote
+                    .get_local_group_peers(cid, None)
+                    .await
+                    .map_err(|err| err.into_string())
+            } else {
+                Err("Server connection not found".to_string())
+            };
+            let response = match peers {
+                Ok(peers) => InternalServiceResponse::ListAllHypernodePeersSuccess(
+                    ListAllHypernodePeersSuccess {
+                        cid,
+                        peers: hypernode_peer_information(peers),
+                    },
+                ),
+                Err(message) => InternalServiceResponse::ListAllHypernodePeersFailure(
+                    ListAllHypernodePeersFailure { cid, message },
+                ),
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
+        InternalServicePayload::SubscribePeerPresence { uuid, cid } => {
+            let response = match server_connection_map.lock().await.get_mut(&cid) {
+                Some(conn) => {
+                    conn.presence_subscribers.insert(uuid);
+                    InternalServiceResponse::SubscribePeerPresenceSuccess(
+                        SubscribePeerPresenceSuccess { cid },
+                    )
+                }
+                None => InternalServiceResponse::SubscribePeerPresenceFailure(
+                    SubscribePeerPresenceFailure {
+                        cid,
+                        message: "Server connection not found".to_string(),
+                    },
+                ),
+            };
+            let subscribed = matches!(
+                response,
+                InternalServiceResponse::SubscribePeerPresenceSuccess(..)
+            );
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+
+            // later changes are only sent on a change, so a new subscriber starts from a snapshot
+            if subscribed {
+                match remote.get_local_group_mutual_peers(cid).await {
+                    Ok(peers) => {
+                        if let Some(conn) = server_connection_map.lock().await.get_mut(&cid) {
+                            for peer in &peers {
+                                conn.peer_presence.insert(peer.cid, peer.online_status);
+                            }
+                        }
+                        for peer in peers {
+                            let response =
+                                InternalServiceResponse::PeerPresenceChanged(PeerPresenceChanged {
+                                    cid,
+                                    peer_cid: peer.cid,
+                                    online: peer.online_status,
+                                });
+                            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+                        }
+                    }
+                    Err(err) => {
+                        warn!(target: "citadel", "Failed to get peer presence for {cid}: {err:?}");
+                    }
+                }
+            }
+        }
+
+        InternalServicePayload::UnsubscribePeerPresence { uuid, cid } => {
+            let response = match server_connection_map.lock().await.get_mut(&cid) {
+                Some(conn) => {
+                    conn.presence_subscribers.remove(&uuid);
+                    InternalServiceResponse::UnsubscribePeerPresenceSuccess(
+                        UnsubscribePeerPresenceSuccess { cid },
+                    )
+                }
+                None => InternalServiceResponse::UnsubscribePeerPresenceFailure(
+                    UnsubscribePeerPresenceFailure {
+                        cid,
+                        message: "Server connection not found".to_string(),
+                    },
+                ),
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
+        InternalServicePayload::ListPeers { uuid, cid } => {
+            let response = match server_connection_map.lock().await.get(&cid) {
+                Some(conn) => InternalServiceResponse::ListPeersSuccess(ListPeersSuccess {
+                    cid,
+                    peers: conn.peer_information(cid),
+                }),
+                None => InternalServiceResponse::ListPeersFailure(ListPeersFailure {
+                    cid,
+                    message: "Server connection not found".to_string(),
+                }),
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
+        InternalServicePayload::GetSessionInfo { uuid, cid } => {
+            let response = match server_connection_map.lock().await.get(&cid) {
+                Some(conn) => {
+                    InternalServiceResponse::GetSessionInfoSuccess(GetSessionInfoSuccess {
+                        session: conn.session_information(cid),
+                    })
+                }
+                None => InternalServiceResponse::GetSessionInfoFailure(GetSessionInfoFailure {
+                    cid,
+                    message: "Server connection not found".to_string(),
+                }),
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
+        InternalServicePayload::ListFileTransfers { uuid, cid } => {
+            let client_to_server_remote = ClientServerRemote::new(
+                VirtualTargetType::LocalGroupServer {
+                    implicated_cid: cid,
+                },
+                remote.clone(),
+            );
+            let response =
+                match list_file_transfers(&client_to_server_remote).await {
+                    Ok(mut transfers) => {
+                        if let Some(conn) = server_connection_map.lock().await.get(&cid) {
+                            for transfer in transfers.iter_mut() {
+                                transfer.interrupted =
+                                    !conn.running_file_transfers.contains(&transfer.transfer_id);
+                            }
+                        }
+                        InternalServiceResponse::ListFileTransfersSuccess(
+                            ListFileTransfersSuccess { cid, transfers },
+                        )
+                    }
+                    Err(message) => InternalServiceResponse::ListFileTransfersFailure(
+                        ListFileTransfersFailure { cid, message },
+                    ),
+                };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+
         InternalServicePayload::LocalDBGetKV {
             uuid,
             cid,
@@ -601,17 +1487,26 @@ pub async fn payload_handler(
                     InternalServiceResponse::LocalDBGetKVFailure(LocalDBGetKVFailure {
                         cid,
                         peer_cid,
+                        key,
                         message: "Server connection not found".to_string(),
+                        code: LocalDBErrorCode::ConnectionNotFound,
                     }),
                     uuid,
                 )
                 .await;
             }
             Some(conn) => {
+                let watch = KvWatchContext {
+                    conn,
+                    tcp_connection_map,
+                    cid,
+                    peer_cid,
+                };
                 if let Some(peer_cid) = peer_cid {
-                    if let Some(peer) = conn.peers.get_mut(&peer_cid) {
+                    if let Some(peer) = conn.peers.get(&peer_cid) {
                         backend_handler_get(
                             &peer.remote,
+                            &watch,
                             tcp_connection_map,
                             uuid,
                             cid,
@@ -625,7 +1520,9 @@ pub async fn payload_handler(
                             InternalServiceResponse::LocalDBGetKVFailure(LocalDBGetKVFailure {
                                 cid,
                                 peer_cid: Some(peer_cid),
+                                key,
                                 message: "Peer connection not found".to_string(),
+                                code: LocalDBErrorCode::PeerConnectionNotFound,
                             }),
                             uuid,
                         )
@@ -634,6 +1531,7 @@ pub async fn payload_handler(
                 } else {
                     backend_handler_get(
                         &conn.client_server_remote,
+                        &watch,
                         tcp_connection_map,
                         uuid,
                         cid,
@@ -650,6 +1548,7 @@ pub async fn payload_handler(
             peer_cid,
             key,
             value,
+            ttl,
         } => match server_connection_map.lock().await.get_mut(&cid) {
             None => {
                 send_response_to_tcp_client(
@@ -657,48 +1556,49 @@ pub async fn payload_handler(
                     InternalServiceResponse::LocalDBSetKVFailure(LocalDBSetKVFailure {
                         cid,
                         peer_cid,
+                        key,
                         message: "Server connection not found".to_string(),
+                        code: LocalDBErrorCode::ConnectionNotFound,
                     }),
                     uuid,
                 )
                 .await;
             }
             Some(conn) => {
-                if let Some(peer_cid) = peer_cid {
+                // values are only copied when a client is watching this store
+                let changes = conn
+                    .has_kv_watchers(peer_cid)
+                    .then(|| vec![(key.clone(), Some(value.clone()))]);
+                let response = if let Some(peer_cid) = peer_cid {
                     if let Some(peer) = conn.peers.get_mut(&peer_cid) {
-                        backend_handler_set(
-                            &peer.remote,
-                            tcp_connection_map,
-                            uuid,
+                        backend_handler_set(&peer.remote, cid, Some(peer_cid), key, value, ttl)
+                            .await
+                    } else {
+                        InternalServiceResponse::LocalDBSetKVFailure(LocalDBSetKVFailure {
                             cid,
-                            Some(peer_cid),
+                            peer_cid: Some(peer_cid),
                             key,
-                            value,
-                        )
-                        .await;
-                    } else {
-                        send_response_to_tcp_client(
-                            tcp_connection_map,
-                            InternalServiceResponse::LocalDBSetKVFailure(LocalDBSetKVFailure {
-                                cid,
-                                peer_cid: Some(peer_cid),
-                                message: "Peer connection not found".to_string(),
-                            }),
-                            uuid,
-                        )
-                        .await;
+                            message: "Peer connection not found".to_string(),
+                            code: LocalDBErrorCode::PeerConnectionNotFound,
+                        })
                     }
                 } else {
-                    backend_handler_set(
-                        &conn.client_server_remote,
-                        tcp_connection_map,
-                        uuid,
-                        cid,
-                        peer_cid,
+                    backend_handler_set(&conn.client_server_remote, cid, peer_cid, key, value, ttl)
+                        .await
+                };
+                let success = match &response {
+                    InternalServiceResponse::LocalDBSetKVSuccess(LocalDBSetKVSuccess {
                         key,
-                        value,
-                    )
-                    .await;
+                        ..
+                    }) => {
+                        conn.index_kv_key(peer_cid, key, true);
+                        true
+                    }
+                    _ => false,
+                };
+                send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+                if let (true, Some(changes)) = (success, changes) {
+                    notify_kv_watchers(conn, tcp_connection_map, cid, peer_cid, changes).await;
                 }
             }
         },
@@ -714,14 +1614,21 @@ pub async fn payload_handler(
                     InternalServiceResponse::LocalDBDeleteKVFailure(LocalDBDeleteKVFailure {
                         cid,
                         peer_cid,
+                        key,
                         message: "Server connection not found".to_string(),
+                        code: LocalDBErrorCode::ConnectionNotFound,
                     }),
                     uuid,
                 )
                 .await;
             }
             Some(conn) => {
-                if let Some(peer_cid) = peer_cid {
+                let deleted_key = key.clone();
+                // values are only copied when a client is watching this store
+                let changes = conn
+                    .has_kv_watchers(peer_cid)
+                    .then(|| vec![(key.clone(), None)]);
+                let success = if let Some(peer_cid) = peer_cid {
                     if let Some(peer) = conn.peers.get_mut(&peer_cid) {
                         backend_handler_delete(
                             &peer.remote,
@@ -731,7 +1638,7 @@ pub async fn payload_handler(
                             Some(peer_cid),
                             key,
                         )
-                        .await;
+                        .await
                     } else {
                         send_response_to_tcp_client(
                             tcp_connection_map,
@@ -739,12 +1646,15 @@ pub async fn payload_handler(
                                 LocalDBDeleteKVFailure {
                                     cid,
                                     peer_cid: Some(peer_cid),
+                                    key,
                                     message: "Peer connection not found".to_string(),
+                                    code: LocalDBErrorCode::PeerConnectionNotFound,
                                 },
                             ),
                             uuid,
                         )
                         .await;
+                        false
                     }
                 } else {
                     backend_handler_delete(
@@ -755,7 +1665,13 @@ pub async fn payload_handler(
                         peer_cid,
                         key,
                     )
-                    .await;
+                    .await
+                };
+                if success {
+                    conn.index_kv_key(peer_cid, &deleted_key, false);
+                }
+                if let (true, Some(changes)) = (success, changes) {
+                    notify_kv_watchers(conn, tcp_connection_map, cid, peer_cid, changes).await;
                 }
             }
         },
@@ -771,6 +1687,7 @@ pub async fn payload_handler(
                         cid,
                         peer_cid,
                         message: "Server connection not found".to_string(),
+                        code: LocalDBErrorCode::ConnectionNotFound,
                     }),
                     uuid,
                 )
@@ -795,6 +1712,7 @@ pub async fn payload_handler(
                                     cid,
                                     peer_cid: Some(peer_cid),
                                     message: "Peer connection not found".to_string(),
+                                    code: LocalDBErrorCode::PeerConnectionNotFound,
                                 },
                             ),
                             uuid,
@@ -825,15 +1743,20 @@ pub async fn payload_handler(
                         cid,
                         peer_cid,
                         message: "Server connection not found".to_string(),
+                        code: LocalDBErrorCode::ConnectionNotFound,
                     }),
                     uuid,
                 )
                 .await;
             }
             Some(conn) => {
-                if let Some(peer_cid) = peer_cid {
+                // the cleared keys would otherwise linger in the scan index
+                conn.kv_key_index.remove(&peer_cid);
+                let watched = conn.has_kv_watchers(peer_cid);
+                let (success, changes) = if let Some(peer_cid) = peer_cid {
                     if let Some(peer) = conn.peers.get_mut(&peer_cid) {
-                        backend_handler_clear_all(
+                        let changes = kv_removals(&peer.remote, watched).await;
+                        let success = backend_handler_clear_all(
                             &peer.remote,
                             tcp_connection_map,
                             uuid,
@@ -841,6 +1764,7 @@ pub async fn payload_handler(
                             Some(peer_cid),
                         )
                         .await;
+                        (success, changes)
                     } else {
                         send_response_to_tcp_client(
                             tcp_connection_map,
@@ -849,14 +1773,17 @@ pub async fn payload_handler(
                                     cid,
                                     peer_cid: Some(peer_cid),
                                     message: "Peer connection not found".to_string(),
+                                    code: LocalDBErrorCode::PeerConnectionNotFound,
                                 },
                             ),
                             uuid,
                         )
                         .await;
+                        (false, vec![])
                     }
                 } else {
-                    backend_handler_clear_all(
+                    let changes = kv_removals(&conn.client_server_remote, watched).await;
+                    let success = backend_handler_clear_all(
                         &conn.client_server_remote,
                         tcp_connection_map,
                         uuid,
@@ -864,204 +1791,2881 @@ pub async fn payload_handler(
                         peer_cid,
                     )
                     .await;
+                    (success, changes)
+                };
+                if success {
+                    notify_kv_watchers(conn, tcp_connection_map, cid, peer_cid, changes).await;
                 }
             }
         },
-    }
-}
-
-async fn backend_handler_get(
-    remote: &impl BackendHandler,
-    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, UnboundedSender<InternalServiceResponse>>>>,
-    uuid: Uuid,
-    cid: u64,
-    peer_cid: Option<u64>,
-    key: String,
-) {
-    match remote.get(&key).await {
-        Ok(value) => {
-            if let Some(value) = value {
+        InternalServicePayload::LocalDBScanKV {
+            uuid,
+            cid,
+            peer_cid,
+            prefix,
+            start_after,
+            limit,
+        } => match server_connection_map.lock().await.get_mut(&cid) {
+            None => {
                 send_response_to_tcp_client(
                     tcp_connection_map,
-                    InternalServiceResponse::LocalDBGetKVSuccess(LocalDBGetKVSuccess {
+                    InternalServiceResponse::LocalDBScanKVFailure(LocalDBScanKVFailure {
                         cid,
                         peer_cid,
-                        key,
-                        value,
+                        message: "Server connection not found".to_string(),
+                        code: LocalDBErrorCode::ConnectionNotFound,
                     }),
                     uuid,
                 )
                 .await;
-            } else {
+            }
+            Some(conn) => {
+                if let Some(peer_cid) = peer_cid {
+                    if let Some(peer) = conn.peers.get(&peer_cid) {
+                        let response = backend_handler_scan(
+                            &peer.remote,
+                            &mut conn.kv_key_index,
+                            cid,
+                            Some(peer_cid),
+                            prefix,
+                            start_after,
+                            limit,
+                        )
+                        .await;
+                        send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+                    } else {
+                        send_response_to_tcp_client(
+                            tcp_connection_map,
+                            InternalServiceResponse::LocalDBScanKVFailure(LocalDBScanKVFailure {
+                                cid,
+                                peer_cid: Some(peer_cid),
+                                message: "Peer connection not found".to_string(),
+                                code: LocalDBErrorCode::PeerConnectionNotFound,
+                            }),
+                            uuid,
+                        )
+                        .await;
+                    }
+                } else {
+                    let response = backend_handler_scan(
+                        &conn.client_server_remote,
+                        &mut conn.kv_key_index,
+                        cid,
+                        peer_cid,
+                        prefix,
+                        start_after,
+                        limit,
+                    )
+                    .await;
+                    send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+                }
+            }
+        },
+        InternalServicePayload::LocalDBBatch {
+            uuid,
+            cid,
+            peer_cid,
+            operations,
+        } => match server_connection_map.lock().await.get_mut(&cid) {
+            None => {
                 send_response_to_tcp_client(
                     tcp_connection_map,
-                    InternalServiceResponse::LocalDBGetKVFailure(LocalDBGetKVFailure {
+                    InternalServiceResponse::LocalDBBatchFailure(LocalDBBatchFailure {
                         cid,
                         peer_cid,
-                        message: "Key not found".to_string(),
+                        message: "Server connection not found".to_string(),
+                        code: LocalDBErrorCode::ConnectionNotFound,
+                        rolled_back: true,
                     }),
                     uuid,
                 )
                 .await;
             }
-        }
-        Err(err) => {
-            send_response_to_tcp_client(
-                tcp_connection_map,
-                InternalServiceResponse::LocalDBGetKVFailure(LocalDBGetKVFailure {
-                    cid,
+            Some(conn) => {
+                let indexed: Vec<(String, bool)> = operations
+                    .iter()
+                    .map(|operation| match operation {
+                        LocalDBBatchOperation::Set { key, .. } => (key.clone(), true),
+                        LocalDBBatchOperation::Delete { key } => (key.clone(), false),
+                    })
+                    .collect();
+                // values are only copied when a client is watching this store
+                let changes = conn.has_kv_watchers(peer_cid).then(|| {
+                    operations
+                        .iter()
+                        .map(|operation| match operation {
+                            LocalDBBatchOperation::Set { key, value } => {
+                                (key.clone(), Some(value.clone()))
+                            }
+                            LocalDBBatchOperation::Delete { key } => (key.clone(), None),
+                        })
+                        .collect::<Vec<_>>()
+                });
+                let response = if let Some(peer_cid) = peer_cid {
+                    if let Some(peer) = conn.peers.get_mut(&peer_cid) {
+                        backend_handler_batch(&peer.remote, cid, Some(peer_cid), operations).await
+                    } else {
+                        InternalServiceResponse::LocalDBBatchFailure(LocalDBBatchFailure {
+                            cid,
+                            peer_cid: Some(peer_cid),
+                            message: "Peer connection not found".to_string(),
+                            code: LocalDBErrorCode::PeerConnectionNotFound,
+                            rolled_back: true,
+                        })
+                    }
+                } else {
+                    backend_handler_batch(&conn.client_server_remote, cid, peer_cid, operations)
+                        .await
+                };
+                let success = matches!(response, InternalServiceResponse::LocalDBBatchSuccess(..));
+                // a key that is indexed but missing is skipped by scans, so a failed batch only
+                // indexes its sets in case they were not rolled back
+                for (key, present) in indexed {
+                    if present || success {
+                        conn.index_kv_key(peer_cid, &key, present);
+                    }
+                }
+                send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+                if let (true, Some(changes)) = (success, changes) {
+                    notify_kv_watchers(conn, tcp_connection_map, cid, peer_cid, changes).await;
+                }
+            }
+        },
+        InternalServicePayload::LocalDBCompareAndSwap {
+            uuid,
+            cid,
+            peer_cid,
+            key,
+            expected,
+            new,
+        } => match server_connection_map.lock().await.get_mut(&cid) {
+            None => {
+                send_response_to_tcp_client(
+                    tcp_connection_map,
+                    InternalServiceResponse::LocalDBCompareAndSwapFailure(
+                        LocalDBCompareAndSwapFailure {
+                            cid,
+                            peer_cid,
+                            message: "Server connection not found".to_string(),
+                            code: LocalDBErrorCode::ConnectionNotFound,
+                        },
+                    ),
+                    uuid,
+                )
+                .await;
+            }
+            Some(conn) => {
+                // values are only copied when a client is watching this store
+                let changes = conn
+                    .has_kv_watchers(peer_cid)
+                    .then(|| vec![(key.clone(), Some(new.clone()))]);
+                let watch = KvWatchContext {
+                    conn,
+                    tcp_connection_map,
+                    cid,
+                    peer_cid,
+                };
+                let response = if let Some(peer_cid) = peer_cid {
+                    if let Some(peer) = conn.peers.get(&peer_cid) {
+                        backend_handler_compare_and_swap(
+                            &peer.remote,
+                            &watch,
+                            cid,
+                            Some(peer_cid),
+                            key,
+                            expected,
+                            new,
+                        )
+                        .await
+                    } else {
+                        InternalServiceResponse::LocalDBCompareAndSwapFailure(
+                            LocalDBCompareAndSwapFailure {
+                                cid,
+                                peer_cid: Some(peer_cid),
+                                message: "Peer connection not found".to_string(),
+                                code: LocalDBErrorCode::PeerConnectionNotFound,
+                            },
+                        )
+                    }
+                } else {
+                    backend_handler_compare_and_swap(
+                        &conn.client_server_remote,
+                        &watch,
+                        cid,
+                        peer_cid,
+                        key,
+                        expected,
+                        new,
+                    )
+                    .await
+                };
+                let success = match &response {
+                    InternalServiceResponse::LocalDBCompareAndSwapSuccess(
+                        LocalDBCompareAndSwapSuccess {
+                            key, swapped: true, ..
+                        },
+                    ) => {
+                        conn.index_kv_key(peer_cid, key, true);
+                        true
+                    }
+                    _ => false,
+                };
+                send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+                if let (true, Some(changes)) = (success, changes) {
+                    notify_kv_watchers(conn, tcp_connection_map, cid, peer_cid, changes).await;
+                }
+            }
+        },
+        InternalServicePayload::LocalDBWatch {
+            uuid,
+            cid,
+            peer_cid,
+            prefix,
+        } => {
+            let response = match server_connection_map.lock().await.get_mut(&cid) {
+                Some(conn) => {
+                    if peer_cid.is_some_and(|peer_cid| !conn.peers.contains_key(&peer_cid)) {
+                        InternalServiceResponse::LocalDBWatchFailure(LocalDBWatchFailure {
+                            cid,
+                            peer_cid,
+                            message: "Peer connection not found".to_string(),
+                            code: LocalDBErrorCode::PeerConnectionNotFound,
+                        })
+                    } else {
+                        conn.kv_watchers.insert(KvWatcher {
+                            uuid,
+                            peer_cid,
+                            prefix: prefix.clone(),
+                        });
+                        InternalServiceResponse::LocalDBWatchSuccess(LocalDBWatchSuccess {
+                            cid,
+                            peer_cid,
+                            prefix,
+                        })
+                    }
+                }
+                None => InternalServiceResponse::LocalDBWatchFailure(LocalDBWatchFailure {
+                    cid,
+                    peer_cid,
+                    message: "Server connection not found".to_string(),
+                    code: LocalDBErrorCode::ConnectionNotFound,
+                }),
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+        InternalServicePayload::LocalDBUnwatch {
+            uuid,
+            cid,
+            peer_cid,
+            prefix,
+        } => {
+            let response = match server_connection_map.lock().await.get_mut(&cid) {
+                Some(conn) => {
+                    conn.kv_watchers.remove(&KvWatcher {
+                        uuid,
+                        peer_cid,
+                        prefix: prefix.clone(),
+                    });
+                    InternalServiceResponse::LocalDBUnwatchSuccess(LocalDBUnwatchSuccess {
+                        cid,
+                        peer_cid,
+                        prefix,
+                    })
+                }
+                None => InternalServiceResponse::LocalDBUnwatchFailure(LocalDBUnwatchFailure {
+                    cid,
+                    peer_cid,
+                    message: "Server connection not found".to_string(),
+                    code: LocalDBErrorCode::ConnectionNotFound,
+                }),
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+        InternalServicePayload::LocalDBPutChunk {
+            uuid,
+            cid,
+            peer_cid,
+            key,
+            upload_id,
+            index,
+            total,
+            data,
+        } => {
+            // watchers are not told about chunked values, since the value may not fit in a frame
+            let result = match server_connection_map.lock().await.get_mut(&cid) {
+                Some(conn) => {
+                    let result = if let Some(peer_cid) = peer_cid {
+                        if let Some(peer) = conn.peers.get_mut(&peer_cid) {
+                            put_chunk(&peer.remote, upload_id, &key, index, total, data).await
+                        } else {
+                            Err((
+                                "Peer connection not found".to_string(),
+                                LocalDBErrorCode::PeerConnectionNotFound,
+                            ))
+                        }
+                    } else {
+                        put_chunk(
+                            &conn.client_server_remote,
+                            upload_id,
+                            &key,
+                            index,
+                            total,
+                            data,
+                        )
+                        .await
+                    };
+                    // scans skip the key until the last chunk makes it visible
+                    if result.is_ok() {
+                        conn.index_kv_key(peer_cid, &key, true);
+                    }
+                    result
+                }
+                None => Err((
+                    "Server connection not found".to_string(),
+                    LocalDBErrorCode::ConnectionNotFound,
+                )),
+            };
+            let response = match result {
+                Ok(()) => InternalServiceResponse::LocalDBPutChunkSuccess(LocalDBPutChunkSuccess {
+                    cid,
+                    peer_cid,
+                    key,
+                    index,
+                    total,
+                }),
+                Err((message, code)) => {
+                    InternalServiceResponse::LocalDBPutChunkFailure(LocalDBPutChunkFailure {
+                        cid,
+                        peer_cid,
+                        key,
+                        index,
+                        message,
+                        code,
+                    })
+                }
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+        InternalServicePayload::LocalDBGetChunk {
+            uuid,
+            cid,
+            peer_cid,
+            key,
+            index,
+        } => {
+            let response = match server_connection_map.lock().await.get(&cid) {
+                Some(conn) => {
+                    let watch = KvWatchContext {
+                        conn,
+                        tcp_connection_map,
+                        cid,
+                        peer_cid,
+                    };
+                    if let Some(peer_cid) = peer_cid {
+                        if let Some(peer) = conn.peers.get(&peer_cid) {
+                            backend_handler_get_chunk(
+                                &peer.remote,
+                                &watch,
+                                cid,
+                                Some(peer_cid),
+                                key,
+                                index,
+                            )
+                            .await
+                        } else {
+                            InternalServiceResponse::LocalDBGetChunkFailure(
+                                LocalDBGetChunkFailure {
+                                    cid,
+                                    peer_cid: Some(peer_cid),
+                                    key,
+                                    index,
+                                    message: "Peer connection not found".to_string(),
+                                    code: LocalDBErrorCode::PeerConnectionNotFound,
+                                },
+                            )
+                        }
+                    } else {
+                        backend_handler_get_chunk(
+                            &conn.client_server_remote,
+                            &watch,
+                            cid,
+                            peer_cid,
+                            key,
+                            index,
+                        )
+                        .await
+                    }
+                }
+                None => InternalServiceResponse::LocalDBGetChunkFailure(LocalDBGetChunkFailure {
+                    cid,
+                    peer_cid,
+                    key,
+                    index,
+                    message: "Server connection not found".to_string(),
+                    code: LocalDBErrorCode::ConnectionNotFound,
+                }),
+            };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+        }
+        InternalServicePayload::LocalDBExport {
+            uuid,
+            cid,
+            peer_cid,
+            password,
+            path,
+        } => {
+            let export = match kv_peer_remote(server_connection_map, cid, peer_cid).await {
+                Ok(Some(peer_remote)) => export_kv_archive(&peer_remote, password).await,
+                Ok(None) => {
+                    let client_server_remote = ClientServerRemote::new(
+                        VirtualTargetType::LocalGroupServer {
+                            implicated_cid: cid,
+                        },
+                        remote.clone(),
+                    );
+                    export_kv_archive(&client_server_remote, password).await
+                }
+                Err(err) => Err(err),
+            };
+            send_kv_export(tcp_connection_map, uuid, cid, peer_cid, export, path).await;
+        }
+        InternalServicePayload::LocalDBImport {
+            uuid,
+            cid,
+            peer_cid,
+            password,
+            source,
+            mode,
+        } => {
+            let failure = |message: String, code: LocalDBErrorCode| {
+                InternalServiceResponse::LocalDBImportFailure(LocalDBImportFailure {
+                    cid,
+                    peer_cid,
+                    message,
+                    code,
+                    rolled_back: true,
+                })
+            };
+
+            let source = match source {
+                LocalDBImportSource::Chunk {
+                    upload_id,
+                    index,
+                    total,
+                    data,
+                } => {
+                    let staged = match server_connection_map.lock().await.get_mut(&cid) {
+                        Some(conn) => stage_kv_import(conn, uuid, upload_id, index, total, data),
+                        None => Err((
+                            "Server connection not found".to_string(),
+                            LocalDBErrorCode::ConnectionNotFound,
+                        )),
+                    };
+                    match staged {
+                        Ok(Some(archive)) => LocalDBImportSource::Archive(archive),
+                        Ok(None) => {
+                            let response = InternalServiceResponse::LocalDBImportChunkReceived(
+                                LocalDBImportChunkReceived {
+                                    cid,
+                                    peer_cid,
+                                    upload_id,
+                                    index,
+                                    total,
+                                },
+                            );
+                            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+                            return;
+                        }
+                        Err((message, code)) => {
+                            let response = failure(message, code);
+                            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+                            return;
+                        }
+                    }
+                }
+                source => source,
+            };
+
+            let (response, changes) =
+                match kv_peer_remote(server_connection_map, cid, peer_cid).await {
+                    Ok(Some(peer_remote)) => {
+                        backend_handler_import(&peer_remote, cid, peer_cid, password, source, mode)
+                            .await
+                    }
+                    Ok(None) => {
+                        let client_server_remote = ClientServerRemote::new(
+                            VirtualTargetType::LocalGroupServer {
+                                implicated_cid: cid,
+                            },
+                            remote.clone(),
+                        );
+                        backend_handler_import(
+                            &client_server_remote,
+                            cid,
+                            peer_cid,
+                            password,
+                            source,
+                            mode,
+                        )
+                        .await
+                    }
+                    Err((message, code)) => (failure(message, code), vec![]),
+                };
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+            if let Some(conn) = server_connection_map.lock().await.get_mut(&cid) {
+                conn.kv_key_index.remove(&peer_cid);
+                notify_kv_watchers(conn, tcp_connection_map, cid, peer_cid, changes).await;
+            }
+        }
+    }
+}
+
+async fn start_file_transfer(
+    mut transfer: FileTransferInfo,
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    remote: &mut NodeRemote,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    uuid: Uuid,
+) {
+    let cid = transfer.cid;
+    let peer_cid = transfer.peer_cid;
+    let transfer_id = transfer.transfer_id;
+    let resuming = transfer.interrupted;
+    let v_conn_type = match server_connection_map.lock().await.get_mut(&cid) {
+        Some(conn) => {
+            let v_conn_type = if let Some(peer_cid) = peer_cid {
+                conn.peers.get(&peer_cid).map(|peer| *peer.remote.user())
+            } else {
+                Some(*conn.client_server_remote.user())
+            };
+            match v_conn_type {
+                Some(_) if !conn.running_file_transfers.insert(transfer_id) => {
+                    Err(format!("File transfer {transfer_id} is already running"))
+                }
+                Some(v_conn_type) => Ok(v_conn_type),
+                None => Err("Peer connection not found".to_string()),
+            }
+        }
+        None => Err("Server connection not found".to_string()),
+    };
+
+    let v_conn_type = match v_conn_type {
+        Ok(v_conn_type) => v_conn_type,
+        Err(message) => {
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::SendFileFailure(SendFileFailure {
+                    cid,
+                    peer_cid,
+                    transfer_id: resuming.then_some(transfer_id),
+                    message,
+                }),
+                uuid,
+            )
+            .await;
+            return;
+        }
+    };
+
+    let virtual_file = match (&transfer.transfer_type, peer_cid) {
+        (
+            TransferType::RemoteEncryptedVirtualFilesystem {
+                virtual_path,
+                security_level,
+            },
+            None,
+        ) => Some(VirtualFileMetadata {
+            virtual_path: virtual_path.clone(),
+            size: tokio::fs::metadata(&transfer.source)
+                .await
+                .map(|metadata| metadata.len())
+                .unwrap_or_default(),
+            security_level: *security_level,
+            uploaded_at: SystemTime::now(),
+        }),
+        _ => None,
+    };
+
+    let client_server_remote = ClientServerRemote::new(
+        VirtualTargetType::LocalGroupServer {
+            implicated_cid: cid,
+        },
+        remote.clone(),
+    );
+
+    let offset = (transfer.chunks_confirmed * transfer.chunk_size) as u64;
+    let source = if offset == 0 {
+        transfer.source.clone()
+    } else {
+        match write_remaining_source(&transfer, offset).await {
+            Ok(source) => source,
+            Err(err) => {
+                // the source is gone or unreadable, so resuming again would not help
+                stop_file_transfer(server_connection_map, cid, peer_cid, transfer_id).await;
+                if let Err(err) = remove_file_transfer(&client_server_remote, transfer_id).await {
+                    warn!(target: "citadel", "Failed to remove file transfer {transfer_id}: {err}");
+                }
+                send_response_to_tcp_client(
+                    tcp_connection_map,
+                    InternalServiceResponse::SendFileFailure(SendFileFailure {
+                        cid,
+                        peer_cid,
+                        transfer_id: None,
+                        message: err.to_string(),
+                    }),
+                    uuid,
+                )
+                .await;
+                return;
+            }
+        }
+    };
+
+    // whether it is running is only known in memory, so a persisted transfer can always be
+    // resumed after the service restarts
+    transfer.interrupted = true;
+    if let Err(err) = record_file_transfer(&client_server_remote, &transfer).await {
+        warn!(target: "citadel", "Failed to persist file transfer {transfer_id}: {err}");
+    }
+
+    let request = NodeRequest::SendObject(SendObject {
+        source: Box::new(source),
+        chunk_size: Some(transfer.chunk_size),
+        implicated_cid: cid,
+        v_conn_type,
+        transfer_type: transfer.transfer_type.clone(),
+    });
+
+    // the transfer may take a while, so we do not block the inbound command loop on it
+    tokio::task::spawn(send_file_with_progress(
+        remote.clone(),
+        request,
+        transfer,
+        virtual_file,
+        server_connection_map.clone(),
+        tcp_connection_map.clone(),
+        uuid,
+    ));
+}
+
+async fn send_file_with_progress(
+    mut remote: NodeRemote,
+    request: NodeRequest,
+    mut transfer: FileTransferInfo,
+    virtual_file: Option<VirtualFileMetadata>,
+    server_connection_map: Arc<Mutex<HashMap<u64, Connection>>>,
+    tcp_connection_map: Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    uuid: Uuid,
+) {
+    let cid = transfer.cid;
+    let peer_cid = transfer.peer_cid;
+    let transfer_id = transfer.transfer_id;
+    let client_server_remote = ClientServerRemote::new(
+        VirtualTargetType::LocalGroupServer {
+            implicated_cid: cid,
+        },
+        remote.clone(),
+    );
+    // chunks confirmed before this attempt, if we are resuming
+    let base_chunks = transfer.chunks_confirmed;
+    let mut last_persisted = Instant::now();
+
+    let mut result = Err("Transfer ended before completing".to_string());
+
+    match remote.send_callback_subscription(request).await {
+        Ok(mut subscription) => {
+            while let Some(event) = subscription.next().await {
+                match event {
+                    NodeResult::ObjectTransferHandle(ObjectTransferHandle {
+                        mut handle, ..
+                    }) => {
+                        while let Some(status) = handle.next().await {
+                            match status {
+                                // the sender ticks as the receiver acknowledges each chunk,
+                                // so a resume never skips bytes the receiver does not have
+                                ObjectTransferStatus::TransferTick(
+                                    chunks_sent,
+                                    total_chunks,
+                                    _,
+                                ) => {
+                                    transfer.chunks_confirmed = base_chunks + chunks_sent;
+                                    transfer.total_chunks = base_chunks + total_chunks;
+                                    if last_persisted.elapsed() >= FILE_TRANSFER_PERSIST_INTERVAL {
+                                        last_persisted = Instant::now();
+                                        if let Err(err) =
+                                            record_file_transfer(&client_server_remote, &transfer)
+                                                .await
+                                        {
+                                            warn!(target: "citadel", "Failed to persist file transfer {transfer_id}: {err}");
+                                        }
+                                    }
+                                    send_response_to_tcp_client(
+                                        &tcp_connection_map,
+                                        InternalServiceResponse::SendFileProgress(
+                                            SendFileProgress {
+                                                cid,
+                                                peer_cid,
+                                                transfer_id,
+                                                chunks_sent: transfer.chunks_confirmed,
+                                                total_chunks: transfer.total_chunks,
+                                            },
+                                        ),
+                                        uuid,
+                                    )
+                                    .await;
+                                }
+                                ObjectTransferStatus::TransferComplete => {
+                                    result = Ok(());
+                                    break;
+                                }
+                                ObjectTransferStatus::Fail(err) => {
+                                    result = Err(err);
+                                    break;
+                                }
+                                _ => {}
+                            }
+                        }
+                        break;
+                    }
+                    NodeResult::InternalServerError(InternalServerError { message, .. }) => {
+                        result = Err(message);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(err) => result = Err(err.into_string()),
+    }
+
+    if base_chunks != 0 {
+        let _ = tokio::fs::remove_dir_all(resume_directory(transfer_id)).await;
+    }
+    let connected = stop_file_transfer(&server_connection_map, cid, peer_cid, transfer_id).await;
+
+    let response = match result {
+        Ok(()) => {
+            if let Err(err) = remove_file_transfer(&client_server_remote, transfer_id).await {
+                warn!(target: "citadel", "Failed to remove file transfer {transfer_id}: {err}");
+            }
+
+            if let Some(mut virtual_file) = virtual_file {
+                // keep an index of what we pushed so the RE-VFS can be browsed later
+                virtual_file.uploaded_at = SystemTime::now();
+                if let Err(err) = record_virtual_file(&client_server_remote, &virtual_file).await {
+                    warn!(target: "citadel", "Failed to index virtual file {:?}: {err}", virtual_file.virtual_path);
+                }
+            }
+
+            InternalServiceResponse::SendFileSuccess(SendFileSuccess {
+                cid,
+                peer_cid,
+                transfer_id,
+            })
+        }
+        // only a dropped peer session leaves something to resume. Anything else, like the
+        // receiver declining, would fail the same way again, and an upload to the server
+        // cannot be resumed at all
+        Err(message) if connected || peer_cid.is_none() => {
+            if let Err(err) = remove_file_transfer(&client_server_remote, transfer_id).await {
+                warn!(target: "citadel", "Failed to remove file transfer {transfer_id}: {err}");
+            }
+
+            InternalServiceResponse::SendFileFailure(SendFileFailure {
+                cid,
+                peer_cid,
+                transfer_id: None,
+                message,
+            })
+        }
+        Err(message) => {
+            if let Err(err) = record_file_transfer(&client_server_remote, &transfer).await {
+                warn!(target: "citadel", "Failed to persist file transfer {transfer_id}: {err}");
+            }
+
+            InternalServiceResponse::SendFileFailure(SendFileFailure {
+                cid,
+                peer_cid,
+                transfer_id: Some(transfer_id),
+                message,
+            })
+        }
+    };
+    send_response_to_tcp_client(&tcp_connection_map, response, uuid).await;
+}
+
+// Forgets a transfer that stopped running and tells whether its session is still connected
+async fn stop_file_transfer(
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    cid: u64,
+    peer_cid: Option<u64>,
+    transfer_id: Uuid,
+) -> bool {
+    match server_connection_map.lock().await.get_mut(&cid) {
+        Some(conn) => {
+            conn.running_file_transfers.remove(&transfer_id);
+            match peer_cid {
+                Some(peer_cid) => conn.peers.contains_key(&peer_cid),
+                None => true,
+            }
+        }
+        None => false,
+    }
+}
+
+fn resume_directory(transfer_id: Uuid) -> PathBuf {
+    std::env::temp_dir().join(format!("citadel-resume-{transfer_id}"))
+}
+
+// The SDK only sends whole files, so the rest of a resumed transfer is copied into a file
+// named with resumed_file_name. The receiving service appends it to what it already has
+async fn write_remaining_source(
+    transfer: &FileTransferInfo,
+    offset: u64,
+) -> std::io::Result<PathBuf> {
+    let file_name = transfer
+        .source
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid file name"))?
+        .to_string_lossy();
+    let directory = resume_directory(transfer.transfer_id);
+    tokio::fs::create_dir_all(&directory).await?;
+    let destination = directory.join(resumed_file_name(&file_name, offset));
+
+    let mut source = tokio::fs::File::open(&transfer.source).await?;
+    source.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut remaining = tokio::fs::File::create(&destination).await?;
+    tokio::io::copy(&mut source, &mut remaining).await?;
+    Ok(destination)
+}
+
+// Finds what is left of an earlier reception for a resumed transfer and cuts it to the offset
+// the sender continues from. Without it, the transfer is declined so the sender does not
+// report a file that the receiver cannot put back together
+async fn resume_target(
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    cid: u64,
+    peer_cid: Option<u64>,
+    file_name: &str,
+) -> Result<Option<PathBuf>, String> {
+    let Some((file_name, offset)) = parse_resumed_file_name(file_name) else {
+        return Ok(None);
+    };
+
+    let head = server_connection_map
+        .lock()
+        .await
+        .get(&cid)
+        .and_then(|conn| {
+            conn.partial_receptions
+                .get(&(peer_cid, file_name.to_string()))
+                .cloned()
+        })
+        .ok_or_else(|| format!("No partial file to resume {file_name} onto"))?;
+
+    let len = tokio::fs::metadata(&head)
+        .await
+        .map_err(|err| err.to_string())?
+        .len();
+    if len < offset {
+        return Err(format!(
+            "Only {len} of the {offset} bytes before the rest of {file_name} were received"
+        ));
+    }
+    // chunks the sender did not see acknowledged are sent again
+    if len > offset {
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&head)
+            .await
+            .map_err(|err| err.to_string())?;
+        file.set_len(offset).await.map_err(|err| err.to_string())?;
+    }
+    Ok(Some(head))
+}
+
+async fn append_file(head: &Path, tail: &Path) -> std::io::Result<()> {
+    let mut head = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(head)
+        .await?;
+    let mut tail_file = tokio::fs::File::open(tail).await?;
+    tokio::io::copy(&mut tail_file, &mut head).await?;
+    tokio::fs::remove_file(tail).await
+}
+
+fn file_transfer_key(transfer_id: Uuid) -> String {
+    format!("{FILE_TRANSFER_PREFIX}{transfer_id}")
+}
+
+async fn record_file_transfer(
+    remote: &impl BackendHandler,
+    transfer: &FileTransferInfo,
+) -> Result<(), String> {
+    let value = bincode2::serialize(transfer).map_err(|err| err.to_string())?;
+    remote
+        .set(&file_transfer_key(transfer.transfer_id), value)
+        .await
+        .map(|_| ())
+        .map_err(|err| err.into_string())
+}
+
+async fn get_file_transfer(
+    remote: &impl BackendHandler,
+    transfer_id: Uuid,
+) -> Result<Option<FileTransferInfo>, String> {
+    match remote.get(&file_transfer_key(transfer_id)).await {
+        Ok(Some(value)) => bincode2::deserialize(&value)
+            .map(Some)
+            .map_err(|err| err.to_string()),
+        Ok(None) => Ok(None),
+        Err(err) => Err(err.into_string()),
+    }
+}
+
+async fn list_file_transfers(
+    remote: &impl BackendHandler,
+) -> Result<Vec<FileTransferInfo>, String> {
+    let map = remote.get_all().await.map_err(|err| err.into_string())?;
+    let mut transfers = map
+        .into_iter()
+        .filter(|(key, _)| key.starts_with(FILE_TRANSFER_PREFIX))
+        .map(|(_, value)| bincode2::deserialize(&value).map_err(|err| err.to_string()))
+        .collect::<Result<Vec<FileTransferInfo>, String>>()?;
+    transfers.sort_by_key(|transfer| transfer.transfer_id);
+    Ok(transfers)
+}
+
+async fn remove_file_transfer(
+    remote: &impl BackendHandler,
+    transfer_id: Uuid,
+) -> Result<(), String> {
+    remote
+        .remove(&file_transfer_key(transfer_id))
+        .await
+        .map(|_| ())
+        .map_err(|err| err.into_string())
+}
+
+// Returns what is left of the file if the reception fails, so a resumed transfer can be
+// appended to it
+async fn receive_file(
+    mut handle: ObjectTransferHandler,
+    download_directory: Option<PathBuf>,
+    resume_onto: Option<PathBuf>,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    uuid: Uuid,
+    cid: u64,
+    peer_cid: Option<u64>,
+) -> Option<PathBuf> {
+    let object_id = handle.metadata.object_id;
+    let mut received_path = None;
+    let mut result = Err("Transfer ended before completing".to_string());
+
+    while let Some(status) = handle.next().await {
+        match status {
+            ObjectTransferStatus::ReceptionBeginning(path, _) => {
+                received_path = Some(path);
+            }
+            ObjectTransferStatus::ReceptionComplete => {
+                result = received_path
+                    .take()
+                    .ok_or_else(|| "Received file has no path".to_string());
+                break;
+            }
+            ObjectTransferStatus::Fail(err) => {
+                result = Err(err);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    let mut partial = None;
+    let result = match (result, download_directory) {
+        (Ok(path), Some(directory)) => move_received_file(&path, &directory)
+            .await
+            .map_err(|err| err.to_string()),
+        (Ok(path), None) => Ok(path),
+        (Err(message), directory) => {
+            partial = match (received_path, directory) {
+                (Some(path), Some(directory)) => move_received_file(&path, &directory).await.ok(),
+                (path, _) => path,
+            };
+            Err(message)
+        }
+    };
+
+    let result = match (result, &resume_onto) {
+        (Ok(tail), Some(head)) => append_file(head, &tail)
+            .await
+            .map(|_| head.clone())
+            .map_err(|err| err.to_string()),
+        (result, _) => result,
+    };
+    // whatever part of the rest did arrive still counts towards the next resume
+    if let (Some(tail), Some(head)) = (partial.take(), resume_onto) {
+        partial = append_file(&head, &tail).await.ok().map(|_| head);
+    }
+
+    let response = match result {
+        Ok(path) => InternalServiceResponse::FileReceived(FileReceived {
+            cid,
+            peer_cid,
+            object_id,
+            path,
+        }),
+        Err(message) => InternalServiceResponse::FileReceiveFailure(FileReceiveFailure {
+            cid,
+            peer_cid,
+            object_id,
+            message,
+        }),
+    };
+    send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+    partial
+}
+
+// The pull runs in one piece, since the SDK has no way to continue an interrupted one
+async fn download_virtual_file(
+    remote: &ClientServerRemote,
+    virtual_path: &Path,
+    transfer_security_level: SecurityLevel,
+    delete_on_pull: bool,
+    download_directory: Option<PathBuf>,
+) -> Result<PathBuf, String> {
+    let path = remote
+        .remote_encrypted_virtual_filesystem_pull(
+            virtual_path.to_path_buf(),
+            transfer_security_level,
+            delete_on_pull,
+        )
+        .await
+        .map_err(|err| err.into_string())?;
+    if delete_on_pull {
+        if let Err(err) = remote.remove(&virtual_file_key(virtual_path)).await {
+            warn!(target: "citadel", "Failed to unindex virtual file {virtual_path:?}: {}", err.into_string());
+        }
+    }
+    match download_directory {
+        Some(directory) => move_received_file(&path, &directory)
+            .await
+            .map_err(|err| err.to_string()),
+        None => Ok(path),
+    }
+}
+
+async fn move_received_file(source: &Path, directory: &Path) -> std::io::Result<PathBuf> {
+    let file_name = source.file_name().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid file name")
+    })?;
+    tokio::fs::create_dir_all(directory).await?;
+    let destination = directory.join(file_name);
+    // rename fails across filesystems, so fall back to copying the file over
+    if tokio::fs::rename(source, &destination).await.is_err() {
+        tokio::fs::copy(source, &destination).await?;
+        tokio::fs::remove_file(source).await?;
+    }
+    Ok(destination)
+}
+
+fn hypernode_peer_information(peers: Vec<PeerInformation>) -> Vec<HypernodePeerInformation> {
+    let mut peers: Vec<HypernodePeerInformation> = peers
+        .into_iter()
+        .map(|peer| HypernodePeerInformation {
+            peer_cid: peer.cid,
+            username: peer.username,
+            full_name: peer.name,
+            online: peer.online_status,
+        })
+        .collect();
+    peers.sort_by_key(|peer| peer.peer_cid);
+    peers
+}
+
+fn virtual_file_key(virtual_path: &Path) -> String {
+    format!("{VIRTUAL_FS_INDEX_PREFIX}{}", virtual_path.display())
+}
+
+async fn record_virtual_file(
+    remote: &impl BackendHandler,
+    virtual_file: &VirtualFileMetadata,
+) -> Result<(), String> {
+    let value = bincode2::serialize(virtual_file).map_err(|err| err.to_string())?;
+    remote
+        .set(&virtual_file_key(&virtual_file.virtual_path), value)
+        .await
+        .map(|_| ())
+        .map_err(|err| err.into_string())
+}
+
+async fn get_virtual_file(
+    remote: &impl BackendHandler,
+    virtual_path: &Path,
+) -> Result<Option<VirtualFileMetadata>, String> {
+    match remote.get(&virtual_file_key(virtual_path)).await {
+        Ok(Some(value)) => bincode2::deserialize(&value)
+            .map(Some)
+            .map_err(|err| err.to_string()),
+        Ok(None) => Ok(None),
+        Err(err) => Err(err.into_string()),
+    }
+}
+
+async fn list_virtual_directory(
+    remote: &impl BackendHandler,
+    virtual_directory: &Path,
+) -> Result<(Vec<PathBuf>, Vec<VirtualFileMetadata>), String> {
+    let map = remote.get_all().await.map_err(|err| err.into_string())?;
+    let mut directories = BTreeSet::new();
+    let mut files = Vec::new();
+
+    for (key, value) in map {
+        if !key.starts_with(VIRTUAL_FS_INDEX_PREFIX) {
+            continue;
+        }
+
+        let virtual_file: VirtualFileMetadata =
+            bincode2::deserialize(&value).map_err(|err| err.to_string())?;
+        let Ok(relative_path) = virtual_file.virtual_path.strip_prefix(virtual_directory) else {
+            continue;
+        };
+
+        let mut components = relative_path.components();
+        match (components.next(), components.next()) {
+            (Some(_), None) => files.push(virtual_file),
+            (Some(child), Some(_)) => {
+                directories.insert(virtual_directory.join(child));
+            }
+            _ => {}
+        }
+    }
+
+    files.sort_by(|a, b| a.virtual_path.cmp(&b.virtual_path));
+    Ok((directories.into_iter().collect(), files))
+}
+
+async fn backend_handler_get(
+    remote: &impl BackendHandler,
+    watch: &KvWatchContext<'_>,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    uuid: Uuid,
+    cid: u64,
+    peer_cid: Option<u64>,
+    key: String,
+) {
+    if let Err(message) = check_user_key(&key) {
+        send_response_to_tcp_client(
+            tcp_connection_map,
+            InternalServiceResponse::LocalDBGetKVFailure(LocalDBGetKVFailure {
+                cid,
+                peer_cid,
+                key,
+                message,
+                code: LocalDBErrorCode::ReservedKey,
+            }),
+            uuid,
+        )
+        .await;
+        return;
+    }
+
+    // a missing key is not an error, it is reported as None
+    let result = match get_unexpired(remote, &key, watch).await {
+        Ok(Some((value, expires_at))) => Ok((Some(value), expires_at, None)),
+        Ok(None) => get_chunk_manifest(remote, &key)
+            .await
+            .map(|manifest| (None, None, manifest.map(|(_, total)| total))),
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok((value, expires_at, chunks)) => {
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::LocalDBGetKVSuccess(LocalDBGetKVSuccess {
+                    cid,
+                    peer_cid,
+                    key,
+                    value,
+                    expires_at,
+                    chunks,
+                }),
+                uuid,
+            )
+            .await;
+        }
+        Err(err) => {
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::LocalDBGetKVFailure(LocalDBGetKVFailure {
+                    cid,
+                    peer_cid,
+                    key,
+                    message: err.into_string(),
+                    code: LocalDBErrorCode::Backend,
+                }),
+                uuid,
+            )
+            .await;
+        }
+    }
+}
+
+// backend_handler_set
+async fn backend_handler_set(
+    remote: &impl BackendHandler,
+    cid: u64,
+    peer_cid: Option<u64>,
+    key: String,
+    value: Vec<u8>,
+    ttl: Option<Duration>,
+) -> InternalServiceResponse {
+    if let Err(message) = check_user_key(&key) {
+        return InternalServiceResponse::LocalDBSetKVFailure(LocalDBSetKVFailure {
+            cid,
+            peer_cid,
+            key,
+            message,
+            code: LocalDBErrorCode::ReservedKey,
+        });
+    }
+
+    // the expiry is recorded first, so a failure can only leave an expiry for a missing key
+    let result = match ttl {
+        Some(ttl) => match remote
+            .set(&ttl_key(&key), encode_expiry(SystemTime::now() + ttl))
+            .await
+        {
+            Ok(_) => remote.set(&key, value).await.map(|_| ()),
+            Err(err) => Err(err),
+        },
+        // overwriting without a ttl makes the key permanent again
+        None => match remote.set(&key, value).await {
+            Ok(_) => remote.remove(&ttl_key(&key)).await.map(|_| ()),
+            Err(err) => Err(err),
+        },
+    };
+    // a plain value replaces a chunked one
+    let result = match result {
+        Ok(()) => remove_chunked_value(remote, &key).await,
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(()) => {
+            InternalServiceResponse::LocalDBSetKVSuccess(LocalDBSetKVSuccess { cid, peer_cid, key })
+        }
+        Err(err) => InternalServiceResponse::LocalDBSetKVFailure(LocalDBSetKVFailure {
+            cid,
+            peer_cid,
+            key,
+            message: err.into_string(),
+            code: LocalDBErrorCode::Backend,
+        }),
+    }
+}
+
+// backend handler delete
+async fn backend_handler_delete(
+    remote: &impl BackendHandler,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    uuid: Uuid,
+    cid: u64,
+    peer_cid: Option<u64>,
+    key: String,
+) -> bool {
+    if let Err(message) = check_user_key(&key) {
+        send_response_to_tcp_client(
+            tcp_connection_map,
+            InternalServiceResponse::LocalDBDeleteKVFailure(LocalDBDeleteKVFailure {
+                cid,
+                peer_cid,
+                key,
+                message,
+                code: LocalDBErrorCode::ReservedKey,
+            }),
+            uuid,
+        )
+        .await;
+        return false;
+    }
+
+    let result = match remote.remove(&key).await {
+        Ok(_) => remote.remove(&ttl_key(&key)).await,
+        Err(err) => Err(err),
+    };
+    let result = match result {
+        Ok(_) => remove_chunked_value(remote, &key).await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(_) => {
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::LocalDBDeleteKVSuccess(LocalDBDeleteKVSuccess {
+                    cid,
+                    peer_cid,
+                    key,
+                }),
+                uuid,
+            )
+            .await;
+            true
+        }
+        Err(err) => {
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::LocalDBDeleteKVFailure(LocalDBDeleteKVFailure {
+                    cid,
+                    peer_cid,
+                    key,
+                    message: err.into_string(),
+                    code: LocalDBErrorCode::Backend,
+                }),
+                uuid,
+            )
+            .await;
+            false
+        }
+    }
+}
+
+// backend handler get_all
+async fn backend_handler_get_all(
+    remote: &impl BackendHandler,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    uuid: Uuid,
+    cid: u64,
+    peer_cid: Option<u64>,
+) {
+    match remote.get_all().await {
+        Ok(mut map) => {
+            retain_unexpired(&mut map);
+            let chunked = chunked_values(&map);
+            map.retain(|key, _| !key.starts_with(INTERNAL_KV_PREFIX));
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::LocalDBGetAllKVSuccess(LocalDBGetAllKVSuccess {
+                    cid,
+                    peer_cid,
+                    map,
+                    chunked,
+                }),
+                uuid,
+            )
+            .await;
+        }
+        Err(err) => {
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::LocalDBGetAllKVFailure(LocalDBGetAllKVFailure {
+                    cid,
+                    peer_cid,
+                    message: err.into_string(),
+                    code: LocalDBErrorCode::Backend,
+                }),
+                uuid,
+            )
+            .await;
+        }
+    }
+}
+
+// backend handler clear all
+async fn backend_handler_clear_all(
+    remote: &impl BackendHandler,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    uuid: Uuid,
+    cid: u64,
+    peer_cid: Option<u64>,
+) -> bool {
+    match clear_user_kv(remote).await {
+        Ok(_) => {
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::LocalDBClearAllKVSuccess(LocalDBClearAllKVSuccess {
+                    cid,
+                    peer_cid,
+                }),
+                uuid,
+            )
+            .await;
+            true
+        }
+        Err(err) => {
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::LocalDBClearAllKVFailure(LocalDBClearAllKVFailure {
+                    cid,
                     peer_cid,
                     message: err.into_string(),
+                    code: LocalDBErrorCode::Backend,
+                }),
+                uuid,
+            )
+            .await;
+            false
+        }
+    }
+}
+
+// removes what clients stored, with its expiries and chunks, but not the service's own records
+async fn clear_user_kv(remote: &impl BackendHandler) -> Result<(), NetworkError> {
+    let map = remote.get_all().await?;
+    for key in user_kv_keys(&map) {
+        remote.remove(&key).await?;
+        remote.remove(&ttl_key(&key)).await?;
+        remove_chunked_value(remote, &key).await?;
+    }
+    // expiries left behind by keys that are already gone, and chunks of unfinished uploads
+    for record in map.keys() {
+        if record.starts_with(KV_TTL_PREFIX)
+            || record.starts_with(KV_CHUNK_PREFIX)
+            || record.starts_with(KV_CHUNK_STAGING_PREFIX)
+        {
+            remote.remove(record).await?;
+        }
+    }
+    Ok(())
+}
+
+// backend handler scan
+// Pages are read from a sorted index of the store's keys, so a scan costs a few reads per
+// returned key instead of a get_all. The index is built from get_all by the first scan and
+// updated by each write after it; keys that expired since are skipped when the page is read
+async fn backend_handler_scan(
+    remote: &impl BackendHandler,
+    key_index: &mut HashMap<Option<u64>, Vec<String>>,
+    cid: u64,
+    peer_cid: Option<u64>,
+    prefix: String,
+    start_after: Option<String>,
+    limit: usize,
+) -> InternalServiceResponse {
+    let failure = |message: String| {
+        InternalServiceResponse::LocalDBScanKVFailure(LocalDBScanKVFailure {
+            cid,
+            peer_cid,
+            message,
+            code: LocalDBErrorCode::Backend,
+        })
+    };
+
+    let keys = match key_index.entry(peer_cid) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => match remote.get_all().await {
+            Ok(map) => entry.insert(user_kv_keys(&map).into_iter().collect()),
+            Err(err) => return failure(err.into_string()),
+        },
+    };
+
+    // keys sharing the prefix are contiguous in the index
+    let first = keys.partition_point(|key| {
+        key < &prefix || start_after.as_ref().is_some_and(|start| key <= start)
+    });
+    let limit = limit.max(1);
+    let mut entries = Vec::new();
+    let mut chunked = Vec::new();
+    let mut last_key: Option<&String> = None;
+    let mut next_start_after = None;
+    for key in keys[first..]
+        .iter()
+        .take_while(|key| key.starts_with(&prefix))
+    {
+        let value = match get_chunk_manifest(remote, key).await {
+            Ok(Some((_, total))) => ScannedValue::Chunked(total),
+            Ok(None) => match get_live(remote, key).await {
+                Ok(Some(value)) => ScannedValue::Value(value),
+                Ok(None) => continue,
+                Err(err) => return failure(err.into_string()),
+            },
+            Err(err) => return failure(err.into_string()),
+        };
+
+        // another key exists past the page
+        if entries.len() + chunked.len() == limit {
+            next_start_after = last_key.cloned();
+            break;
+        }
+        match value {
+            ScannedValue::Chunked(total) => chunked.push((key.clone(), total)),
+            ScannedValue::Value(value) => entries.push((key.clone(), value)),
+        }
+        last_key = Some(key);
+    }
+
+    InternalServiceResponse::LocalDBScanKVSuccess(LocalDBScanKVSuccess {
+        cid,
+        peer_cid,
+        prefix,
+        entries,
+        chunked,
+        next_start_after,
+    })
+}
+
+enum ScannedValue {
+    Value(Vec<u8>),
+    Chunked(usize),
+}
+
+// backend handler batch
+// payloads are handled one at a time, so no other KV request can observe a half-applied batch
+async fn backend_handler_batch(
+    remote: &impl BackendHandler,
+    cid: u64,
+    peer_cid: Option<u64>,
+    operations: Vec<LocalDBBatchOperation>,
+) -> InternalServiceResponse {
+    let reserved = operations.iter().find_map(|operation| {
+        let (LocalDBBatchOperation::Set { key, .. } | LocalDBBatchOperation::Delete { key }) =
+            operation;
+        check_user_key(key).err()
+    });
+    if let Some(message) = reserved {
+        // nothing was written yet
+        return InternalServiceResponse::LocalDBBatchFailure(LocalDBBatchFailure {
+            cid,
+            peer_cid,
+            message,
+            code: LocalDBErrorCode::ReservedKey,
+            rolled_back: true,
+        });
+    }
+
+    if let Err((message, rolled_back)) = apply_kv_batch(remote, &operations).await {
+        return InternalServiceResponse::LocalDBBatchFailure(LocalDBBatchFailure {
+            cid,
+            peer_cid,
+            message,
+            code: LocalDBErrorCode::Backend,
+            rolled_back,
+        });
+    }
+
+    // keys written by a batch no longer expire, and replace a chunked value like a plain set
+    for operation in &operations {
+        let (LocalDBBatchOperation::Set { key, .. } | LocalDBBatchOperation::Delete { key }) =
+            operation;
+        if let Err(err) = remote.remove(&ttl_key(key)).await {
+            warn!(target: "citadel", "Failed to clear the ttl of {key}: {err:?}");
+        }
+        if let Err(err) = remove_chunked_value(remote, key).await {
+            warn!(target: "citadel", "Failed to remove the chunks of {key}: {err:?}");
+        }
+    }
+
+    InternalServiceResponse::LocalDBBatchSuccess(LocalDBBatchSuccess {
+        cid,
+        peer_cid,
+        applied: operations.len(),
+    })
+}
+
+// on failure, returns the error and whether the previous values were restored
+async fn apply_kv_batch(
+    remote: &impl BackendHandler,
+    operations: &[LocalDBBatchOperation],
+) -> Result<(), (String, bool)> {
+    // snapshot every touched key first so a failed write can be undone
+    let mut previous = Vec::with_capacity(operations.len());
+    for operation in operations {
+        let (LocalDBBatchOperation::Set { key, .. } | LocalDBBatchOperation::Delete { key }) =
+            operation;
+        match remote.get(key).await {
+            Ok(value) => previous.push((key.clone(), value)),
+            Err(err) => return Err((err.into_string(), true)),
+        }
+    }
+
+    for (applied, operation) in operations.iter().enumerate() {
+        let result = match operation {
+            LocalDBBatchOperation::Set { key, value } => {
+                remote.set(key, value.clone()).await.map(|_| ())
+            }
+            LocalDBBatchOperation::Delete { key } => remote.remove(key).await.map(|_| ()),
+        };
+
+        if let Err(err) = result {
+            let mut rolled_back = true;
+            // the failed operation may have been partially applied, so it is restored too
+            for (key, value) in previous[..=applied].iter().rev() {
+                let restored = match value {
+                    Some(value) => remote.set(key, value.clone()).await.map(|_| ()),
+                    None => remote.remove(key).await.map(|_| ()),
+                };
+                if let Err(err) = restored {
+                    error!(target: "citadel", "Failed to roll back {key}: {err:?}");
+                    rolled_back = false;
+                }
+            }
+            return Err((err.into_string(), rolled_back));
+        }
+    }
+
+    Ok(())
+}
+
+// drops the values, chunks and ttl records kept for the account and each of its peers
+async fn clear_local_db(remote: &impl BackendHandler, peer_remotes: &[(u64, impl BackendHandler)]) {
+    if let Err(err) = remote.remove_all().await {
+        warn!(target: "citadel", "Failed to clear local data: {err:?}");
+    }
+    for (peer_cid, peer_remote) in peer_remotes {
+        if let Err(err) = peer_remote.remove_all().await {
+            warn!(target: "citadel", "Failed to clear local data for peer {peer_cid}: {err:?}");
+        }
+    }
+}
+
+// The peer store a LocalDB request targets, or None for the server's. It is cloned out of the
+// connection map so that slow work, like deriving the key of an archive, does not hold the lock
+async fn kv_peer_remote(
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    cid: u64,
+    peer_cid: Option<u64>,
+) -> Result<Option<SymmetricIdentifierHandle>, (String, LocalDBErrorCode)> {
+    let server_connection_map = server_connection_map.lock().await;
+    let conn = server_connection_map.get(&cid).ok_or_else(|| {
+        (
+            "Server connection not found".to_string(),
+            LocalDBErrorCode::ConnectionNotFound,
+        )
+    })?;
+    match peer_cid {
+        Some(peer_cid) => conn
+            .peers
+            .get(&peer_cid)
+            .map(|peer| Some(peer.remote.clone()))
+            .ok_or_else(|| {
+                (
+                    "Peer connection not found".to_string(),
+                    LocalDBErrorCode::PeerConnectionNotFound,
+                )
+            }),
+        None => Ok(None),
+    }
+}
+
+// collects the pieces of a chunked import, returning the archive once the last one arrived
+fn stage_kv_import(
+    conn: &mut Connection,
+    uuid: Uuid,
+    upload_id: Uuid,
+    index: usize,
+    total: usize,
+    data: Vec<u8>,
+) -> Result<Option<Vec<u8>>, (String, LocalDBErrorCode)> {
+    let pending = conn
+        .pending_kv_imports
+        .entry(upload_id)
+        .or_insert_with(|| PendingKvImport {
+            uuid,
+            next_index: 0,
+            archive: Vec::new(),
+        });
+    let expected = pending.next_index;
+    if index != expected || index >= total {
+        conn.pending_kv_imports.remove(&upload_id);
+        let message = format!("Expected piece {expected} of the archive, got {index} of {total}");
+        return Err((message, LocalDBErrorCode::InvalidChunk));
+    }
+
+    pending.archive.extend_from_slice(&data);
+    pending.next_index += 1;
+    if index + 1 < total {
+        return Ok(None);
+    }
+    Ok(conn
+        .pending_kv_imports
+        .remove(&upload_id)
+        .map(|pending| pending.archive))
+}
+
+// builds the archive of a store; in-flight file transfers are tied to this machine and left out
+async fn export_kv_archive(
+    remote: &impl BackendHandler,
+    password: SecBuffer,
+) -> Result<(Vec<u8>, usize), (String, LocalDBErrorCode)> {
+    let backend = |message: String| (message, LocalDBErrorCode::Backend);
+    let mut map = remote
+        .get_all()
+        .await
+        .map_err(|err| backend(err.into_string()))?;
+    retain_unexpired(&mut map);
+    // ttl records of keys that expired are dropped along with them
+    let orphaned: Vec<String> = map
+        .keys()
+        .filter(|record| {
+            record
+                .strip_prefix(KV_TTL_PREFIX)
+                .is_some_and(|key| !map.contains_key(key))
+        })
+        .cloned()
+        .collect();
+    for record in orphaned {
+        map.remove(&record);
+    }
+    map.retain(|key, _| !key.starts_with(FILE_TRANSFER_PREFIX));
+    let entries = map
+        .keys()
+        .filter(|key| !key.starts_with(INTERNAL_KV_PREFIX))
+        .count();
+    // deriving the key is deliberately slow, so it stays off the runtime threads
+    let archive =
+        tokio::task::spawn_blocking(move || local_db_archive::seal(&map, password.as_ref()))
+            .await
+            .map_err(|err| backend(err.to_string()))?
+            .map_err(backend)?;
+    Ok((archive, entries))
+}
+
+async fn send_kv_export(
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    uuid: Uuid,
+    cid: u64,
+    peer_cid: Option<u64>,
+    export: Result<(Vec<u8>, usize), (String, LocalDBErrorCode)>,
+    path: Option<PathBuf>,
+) {
+    let failure = |message: String, code: LocalDBErrorCode| {
+        InternalServiceResponse::LocalDBExportFailure(LocalDBExportFailure {
+            cid,
+            peer_cid,
+            message,
+            code,
+        })
+    };
+
+    let (archive, entries) = match export {
+        Ok(export) => export,
+        Err((message, code)) => {
+            send_response_to_tcp_client(tcp_connection_map, failure(message, code), uuid).await;
+            return;
+        }
+    };
+
+    if let Some(path) = &path {
+        if let Err(err) = tokio::fs::write(path, &archive).await {
+            let response = failure(err.to_string(), LocalDBErrorCode::Io);
+            send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+            return;
+        }
+    } else {
+        let total = archive.len().div_ceil(KV_EXPORT_CHUNK_SIZE).max(1);
+        for (index, data) in archive.chunks(KV_EXPORT_CHUNK_SIZE).enumerate() {
+            send_response_to_tcp_client(
+                tcp_connection_map,
+                InternalServiceResponse::LocalDBExportChunk(LocalDBExportChunk {
+                    cid,
+                    peer_cid,
+                    index,
+                    total,
+                    data: data.to_vec(),
                 }),
                 uuid,
             )
             .await;
         }
     }
+
+    send_response_to_tcp_client(
+        tcp_connection_map,
+        InternalServiceResponse::LocalDBExportSuccess(LocalDBExportSuccess {
+            cid,
+            peer_cid,
+            entries,
+            size: archive.len(),
+            path,
+        }),
+        uuid,
+    )
+    .await;
+}
+
+// backend handler import
+// the archive is applied as one batch, so a failed import leaves the store as it was
+async fn backend_handler_import(
+    remote: &impl BackendHandler,
+    cid: u64,
+    peer_cid: Option<u64>,
+    password: SecBuffer,
+    source: LocalDBImportSource,
+    mode: LocalDBImportMode,
+) -> (InternalServiceResponse, Vec<(String, Option<Vec<u8>>)>) {
+    let failure = |message: String, code: LocalDBErrorCode, rolled_back: bool| {
+        InternalServiceResponse::LocalDBImportFailure(LocalDBImportFailure {
+            cid,
+            peer_cid,
+            message,
+            code,
+            rolled_back,
+        })
+    };
+
+    let archive = match source {
+        LocalDBImportSource::Path(path) => match tokio::fs::read(&path).await {
+            Ok(archive) => archive,
+            Err(err) => return (failure(err.to_string(), LocalDBErrorCode::Io, true), vec![]),
+        },
+        LocalDBImportSource::Archive(archive) => archive,
+        LocalDBImportSource::Chunk { .. } => {
+            let message = "The archive is incomplete".to_string();
+            return (
+                failure(message, LocalDBErrorCode::InvalidChunk, true),
+                vec![],
+            );
+        }
+    };
+    let archived =
+        tokio::task::spawn_blocking(move || local_db_archive::open(&archive, password.as_ref()))
+            .await
+            .map_err(|err| (err.to_string(), LocalDBErrorCode::Backend))
+            .and_then(|archived| {
+                archived.map_err(|message| (message, LocalDBErrorCode::InvalidArchive))
+            });
+    let archived = match archived {
+        Ok(archived) => archived,
+        Err((message, code)) => return (failure(message, code, true), vec![]),
+    };
+
+    let mut target: HashMap<String, Option<Vec<u8>>> = archived
+        .into_iter()
+        .filter(|(key, _)| !key.starts_with(FILE_TRANSFER_PREFIX))
+        .map(|(key, value)| (key, Some(value)))
+        .collect();
+    // an imported key without an archived ttl must not inherit the ttl of the key it replaces
+    let untimed: Vec<String> = target
+        .keys()
+        .filter(|key| !key.starts_with(INTERNAL_KV_PREFIX))
+        .map(|key| ttl_key(key))
+        .filter(|record| !target.contains_key(record))
+        .collect();
+    for record in untimed {
+        target.insert(record, None);
+    }
+    if mode == LocalDBImportMode::Overwrite {
+        match remote.get_all().await {
+            Ok(existing) => {
+                for key in existing.into_keys() {
+                    if !key.starts_with(FILE_TRANSFER_PREFIX) {
+                        target.entry(key).or_insert(None);
+                    }
+                }
+            }
+            Err(err) => {
+                return (
+                    failure(err.into_string(), LocalDBErrorCode::Backend, true),
+                    vec![],
+                )
+            }
+        }
+    }
+
+    let operations: Vec<LocalDBBatchOperation> = target
+        .iter()
+        .map(|(key, value)| match value {
+            Some(value) => LocalDBBatchOperation::Set {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            None => LocalDBBatchOperation::Delete { key: key.clone() },
+        })
+        .collect();
+    if let Err((message, rolled_back)) = apply_kv_batch(remote, &operations).await {
+        return (
+            failure(message, LocalDBErrorCode::Backend, rolled_back),
+            vec![],
+        );
+    }
+
+    let imported = target
+        .iter()
+        .filter(|(key, value)| value.is_some() && !key.starts_with(INTERNAL_KV_PREFIX))
+        .count();
+    let changes = target
+        .into_iter()
+        .filter(|(key, _)| !key.starts_with(INTERNAL_KV_PREFIX))
+        .collect();
+    (
+        InternalServiceResponse::LocalDBImportSuccess(LocalDBImportSuccess {
+            cid,
+            peer_cid,
+            imported,
+        }),
+        changes,
+    )
+}
+
+// backend handler compare and swap
+async fn backend_handler_compare_and_swap(
+    remote: &impl BackendHandler,
+    watch: &KvWatchContext<'_>,
+    cid: u64,
+    peer_cid: Option<u64>,
+    key: String,
+    expected: Option<Vec<u8>>,
+    new: Vec<u8>,
+) -> InternalServiceResponse {
+    let failure = |message: String, code: LocalDBErrorCode| {
+        InternalServiceResponse::LocalDBCompareAndSwapFailure(LocalDBCompareAndSwapFailure {
+            cid,
+            peer_cid,
+            message,
+            code,
+        })
+    };
+
+    if let Err(message) = check_user_key(&key) {
+        return failure(message, LocalDBErrorCode::ReservedKey);
+    }
+
+    // a chunked value is compared as a whole
+    let current = match get_unexpired(remote, &key, watch).await {
+        Ok(Some((value, _))) => Ok(Some(value)),
+        Ok(None) => get_chunked_value(remote, &key).await,
+        Err(err) => Err(err),
+    };
+    let current = match current {
+        Ok(current) => current,
+        Err(err) => return failure(err.into_string(), LocalDBErrorCode::Backend),
+    };
+
+    if current != expected {
+        return InternalServiceResponse::LocalDBCompareAndSwapSuccess(
+            LocalDBCompareAndSwapSuccess {
+                cid,
+                peer_cid,
+                key,
+                swapped: false,
+                current,
+            },
+        );
+    }
+
+    let result = match remote.set(&key, new).await {
+        Ok(_) => remote.remove(&ttl_key(&key)).await.map(|_| ()),
+        Err(err) => Err(err),
+    };
+    let result = match result {
+        Ok(()) => remove_chunked_value(remote, &key).await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(()) => {
+            InternalServiceResponse::LocalDBCompareAndSwapSuccess(LocalDBCompareAndSwapSuccess {
+                cid,
+                peer_cid,
+                key,
+                swapped: true,
+                current: None,
+            })
+        }
+        Err(err) => failure(err.into_string(), LocalDBErrorCode::Backend),
+    }
+}
+
+// chunks are staged under the id of the upload and only become visible once the manifest points at them
+async fn put_chunk(
+    remote: &impl BackendHandler,
+    upload: Uuid,
+    key: &str,
+    index: usize,
+    total: usize,
+    data: Vec<u8>,
+) -> Result<(), (String, LocalDBErrorCode)> {
+    check_user_key(key).map_err(|message| (message, LocalDBErrorCode::ReservedKey))?;
+    let invalid = |message: &str| (message.to_string(), LocalDBErrorCode::InvalidChunk);
+    if index >= total || total > KV_MAX_CHUNKS {
+        return Err(invalid("Chunk index out of range"));
+    }
+
+    let backend = |err: NetworkError| (err.into_string(), LocalDBErrorCode::Backend);
+    let staging = staging_key(upload, key);
+    let mut staged = match remote.get(&staging).await.map_err(backend)? {
+        Some(bytes) => StagedUpload::decode(&bytes)
+            .filter(|staged| staged.total == total)
+            .ok_or_else(|| invalid("The chunk count does not match the upload"))?,
+        None => {
+            // the chunks of a finished upload are the visible value, so they are not overwritten
+            let current = get_chunk_manifest(remote, key).await.map_err(backend)?;
+            if current.is_some_and(|(current, _)| current == upload) {
+                return Err(invalid("The upload is already complete"));
+            }
+            StagedUpload::new(total)
+        }
+    };
+
+    remote
+        .set(&chunk_key(upload, index, key), data)
+        .await
+        .map_err(backend)?;
+    staged.receive(index);
+    if !staged.is_complete() {
+        staged.expires_at = SystemTime::now() + KV_CHUNK_STAGING_TTL;
+        return remote
+            .set(&staging, staged.encode())
+            .await
+            .map(|_| ())
+            .map_err(backend);
+    }
+
+    let previous = get_chunk_manifest(remote, key).await.map_err(backend)?;
+    remote
+        .set(
+            &chunk_manifest_key(key),
+            encode_chunk_manifest(upload, total),
+        )
+        .await
+        .map_err(backend)?;
+    remote.remove(&staging).await.map_err(backend)?;
+    remote.remove(key).await.map_err(backend)?;
+    remote.remove(&ttl_key(key)).await.map_err(backend)?;
+    match previous {
+        Some((previous, previous_total)) => {
+            remove_chunks(remote, key, previous, 0..previous_total).await
+        }
+        None => Ok(()),
+    }
+    .map_err(backend)
+}
+
+// an upload still receiving chunks: which of them arrived, and when it is given up on
+struct StagedUpload {
+    expires_at: SystemTime,
+    total: usize,
+    received: Vec<u8>,
+}
+
+impl StagedUpload {
+    fn new(total: usize) -> Self {
+        StagedUpload {
+            expires_at: SystemTime::now() + KV_CHUNK_STAGING_TTL,
+            total,
+            received: vec![0; total.div_ceil(8)],
+        }
+    }
+
+    fn receive(&mut self, index: usize) {
+        self.received[index / 8] |= 1 << (index % 8);
+    }
+
+    fn is_received(&self, index: usize) -> bool {
+        self.received[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn is_complete(&self) -> bool {
+        (0..self.total).all(|index| self.is_received(index))
+    }
+
+    // expiry | total (u64, big endian) | one bit per chunk
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = encode_expiry(self.expires_at);
+        bytes.extend_from_slice(&(self.total as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.received);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 16 {
+            return None;
+        }
+        let (header, received) = bytes.split_at(16);
+        let expires_at = decode_expiry(&header[..8])?;
+        let total = u64::from_be_bytes(header[8..].try_into().ok()?) as usize;
+        if received.len() != total.div_ceil(8) {
+            return None;
+        }
+        Some(StagedUpload {
+            expires_at,
+            total,
+            received: received.to_vec(),
+        })
+    }
+}
+
+fn staging_key(upload: Uuid, key: &str) -> String {
+    format!("{KV_CHUNK_STAGING_PREFIX}{upload}/{key}")
+}
+
+// keys holding a chunked value, with the number of chunks
+fn chunked_values(map: &HashMap<String, Vec<u8>>) -> HashMap<String, usize> {
+    map.iter()
+        .filter_map(|(record, bytes)| {
+            let key = record.strip_prefix(KV_CHUNK_MANIFEST_PREFIX)?;
+            let (_, total) = decode_chunk_manifest(bytes)?;
+            Some((key.to_string(), total))
+        })
+        .collect()
+}
+
+// backend handler get chunk
+async fn backend_handler_get_chunk(
+    remote: &impl BackendHandler,
+    watch: &KvWatchContext<'_>,
+    cid: u64,
+    peer_cid: Option<u64>,
+    key: String,
+    index: usize,
+) -> InternalServiceResponse {
+    let failure = |key: String, message: String, code: LocalDBErrorCode| {
+        InternalServiceResponse::LocalDBGetChunkFailure(LocalDBGetChunkFailure {
+            cid,
+            peer_cid,
+            key,
+            index,
+            message,
+            code,
+        })
+    };
+    let out_of_range = |key: String| {
+        failure(
+            key,
+            "Chunk index out of range".to_string(),
+            LocalDBErrorCode::InvalidChunk,
+        )
+    };
+
+    if let Err(message) = check_user_key(&key) {
+        return failure(key, message, LocalDBErrorCode::ReservedKey);
+    }
+
+    let chunk = match get_chunk_manifest(remote, &key).await {
+        Ok(Some((upload, total))) if index < total => remote
+            .get(&chunk_key(upload, index, &key))
+            .await
+            .map(|data| (total, data)),
+        Ok(Some(_)) => return out_of_range(key),
+        Ok(None) => match get_unexpired(remote, &key, watch).await {
+            Ok(Some((value, _))) if index == 0 => Ok((1, Some(value))),
+            Ok(Some(_)) => return out_of_range(key),
+            Ok(None) => Ok((0, None)),
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+
+    match chunk {
+        Ok((total, data)) => {
+            InternalServiceResponse::LocalDBGetChunkSuccess(LocalDBGetChunkSuccess {
+                cid,
+                peer_cid,
+                key,
+                index,
+                total,
+                data,
+            })
+        }
+        Err(err) => failure(key, err.into_string(), LocalDBErrorCode::Backend),
+    }
+}
+
+fn chunk_manifest_key(key: &str) -> String {
+    format!("{KV_CHUNK_MANIFEST_PREFIX}{key}")
+}
+
+fn chunk_key(upload: Uuid, index: usize, key: &str) -> String {
+    format!("{KV_CHUNK_PREFIX}{upload}/{index}/{key}")
+}
+
+// a manifest holds the upload id of the chunks followed by their count
+fn encode_chunk_manifest(upload: Uuid, total: usize) -> Vec<u8> {
+    let mut manifest = upload.as_bytes().to_vec();
+    manifest.extend_from_slice(&(total as u64).to_be_bytes());
+    manifest
+}
+
+fn decode_chunk_manifest(bytes: &[u8]) -> Option<(Uuid, usize)> {
+    let upload = Uuid::from_slice(bytes.get(..16)?).ok()?;
+    let total = u64::from_be_bytes(bytes.get(16..)?.try_into().ok()?);
+    Some((upload, total as usize))
+}
+
+async fn get_chunk_manifest(
+    remote: &impl BackendHandler,
+    key: &str,
+) -> Result<Option<(Uuid, usize)>, NetworkError> {
+    Ok(remote
+        .get(&chunk_manifest_key(key))
+        .await?
+        .and_then(|bytes| decode_chunk_manifest(&bytes)))
+}
+
+async fn remove_chunks(
+    remote: &impl BackendHandler,
+    key: &str,
+    upload: Uuid,
+    indices: std::ops::Range<usize>,
+) -> Result<(), NetworkError> {
+    for index in indices {
+        remote.remove(&chunk_key(upload, index, key)).await?;
+    }
+    Ok(())
+}
+
+// reassembles a chunked value, None if the key has no finished upload
+async fn get_chunked_value(
+    remote: &impl BackendHandler,
+    key: &str,
+) -> Result<Option<Vec<u8>>, NetworkError> {
+    let Some((upload, total)) = get_chunk_manifest(remote, key).await? else {
+        return Ok(None);
+    };
+    let mut value = Vec::new();
+    for index in 0..total {
+        match remote.get(&chunk_key(upload, index, key)).await? {
+            Some(chunk) => value.extend_from_slice(&chunk),
+            None => {
+                return Err(NetworkError::msg(format!(
+                    "Chunk {index} of {key} is missing"
+                )))
+            }
+        }
+    }
+    Ok(Some(value))
 }
 
-// backend_handler_set
-async fn backend_handler_set(
-    remote: &impl BackendHandler,
-    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, UnboundedSender<InternalServiceResponse>>>>,
-    uuid: Uuid,
+async fn remove_chunked_value(remote: &impl BackendHandler, key: &str) -> Result<(), NetworkError> {
+    if let Some((upload, total)) = get_chunk_manifest(remote, key).await? {
+        remote.remove(&chunk_manifest_key(key)).await?;
+        remove_chunks(remote, key, upload, 0..total).await?;
+    }
+    Ok(())
+}
+
+// sends LocalDBChanged for each changed key to the clients watching one of its prefixes
+async fn notify_kv_watchers(
+    conn: &Connection,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
     cid: u64,
     peer_cid: Option<u64>,
-    key: String,
-    value: Vec<u8>,
+    changes: Vec<(String, Option<Vec<u8>>)>,
 ) {
-    match remote.set(&key, value).await {
-        Ok(_) => {
-            send_response_to_tcp_client(
-                tcp_connection_map,
-                InternalServiceResponse::LocalDBSetKVSuccess(LocalDBSetKVSuccess {
-                    cid,
-                    peer_cid,
-                    key,
-                }),
-                uuid,
-            )
-            .await;
+    for (key, new_value) in changes {
+        let watchers = conn.kv_watchers(peer_cid, &key);
+        if watchers.is_empty() {
+            continue;
         }
+
+        let response = InternalServiceResponse::LocalDBChanged(LocalDBChanged {
+            cid,
+            peer_cid,
+            key,
+            new_value,
+        });
+        broadcast_response_to_tcp_clients(tcp_connection_map, response, watchers).await;
+    }
+}
+
+// the keys a clear is about to remove, so watchers can be told about each of them
+async fn kv_removals(
+    remote: &impl BackendHandler,
+    watched: bool,
+) -> Vec<(String, Option<Vec<u8>>)> {
+    if !watched {
+        return vec![];
+    }
+
+    match remote.get_all().await {
+        Ok(map) => user_kv_keys(&map)
+            .into_iter()
+            .map(|key| (key, None))
+            .collect(),
         Err(err) => {
-            send_response_to_tcp_client(
-                tcp_connection_map,
-                InternalServiceResponse::LocalDBSetKVFailure(LocalDBSetKVFailure {
-                    cid,
-                    peer_cid,
-                    message: err.into_string(),
-                }),
-                uuid,
-            )
-            .await;
+            warn!(target: "citadel", "Failed to list keys for watchers: {err:?}");
+            vec![]
         }
     }
 }
 
-// backend handler delete
-async fn backend_handler_delete(
-    remote: &impl BackendHandler,
-    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, UnboundedSender<InternalServiceResponse>>>>,
-    uuid: Uuid,
+fn ttl_key(key: &str) -> String {
+    format!("{KV_TTL_PREFIX}{key}")
+}
+
+// clients may not read or write the records the service keeps under INTERNAL_KV_PREFIX
+fn check_user_key(key: &str) -> Result<(), String> {
+    if key.starts_with(INTERNAL_KV_PREFIX) {
+        Err(format!(
+            "Keys starting with {INTERNAL_KV_PREFIX} are reserved"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// the keys a client has stored, including the ones only present as a chunk manifest
+fn user_kv_keys(map: &HashMap<String, Vec<u8>>) -> BTreeSet<String> {
+    map.keys()
+        .filter_map(
+            |record| match record.strip_prefix(KV_CHUNK_MANIFEST_PREFIX) {
+                Some(key) => Some(key.to_string()),
+                None => (!record.starts_with(INTERNAL_KV_PREFIX)).then(|| record.clone()),
+            },
+        )
+        .collect()
+}
+
+fn encode_expiry(expires_at: SystemTime) -> Vec<u8> {
+    let millis = expires_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    millis.to_be_bytes().to_vec()
+}
+
+fn decode_expiry(bytes: &[u8]) -> Option<SystemTime> {
+    let millis = u64::from_be_bytes(bytes.try_into().ok()?);
+    Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+// the clients to tell when a read finds a key past its ttl and removes it
+struct KvWatchContext<'a> {
+    conn: &'a Connection,
+    tcp_connection_map: &'a Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
     cid: u64,
     peer_cid: Option<u64>,
-    key: String,
+}
+
+// reads a key along with its expiry, removing it on the spot if the ttl has passed
+async fn get_unexpired(
+    remote: &impl BackendHandler,
+    key: &str,
+    watch: &KvWatchContext<'_>,
+) -> Result<Option<(Vec<u8>, Option<SystemTime>)>, NetworkError> {
+    let expires_at = remote
+        .get(&ttl_key(key))
+        .await?
+        .and_then(|bytes| decode_expiry(&bytes));
+    if expires_at.is_some_and(|expires_at| expires_at <= SystemTime::now()) {
+        remote.remove(key).await?;
+        remote.remove(&ttl_key(key)).await?;
+        notify_kv_watchers(
+            watch.conn,
+            watch.tcp_connection_map,
+            watch.cid,
+            watch.peer_cid,
+            vec![(key.to_string(), None)],
+        )
+        .await;
+        return Ok(None);
+    }
+
+    Ok(remote.get(key).await?.map(|value| (value, expires_at)))
+}
+
+// reads a key unless its ttl has passed, leaving the removal to the periodic sweep
+async fn get_live(
+    remote: &impl BackendHandler,
+    key: &str,
+) -> Result<Option<Vec<u8>>, NetworkError> {
+    let expired = remote
+        .get(&ttl_key(key))
+        .await?
+        .and_then(|bytes| decode_expiry(&bytes))
+        .is_some_and(|expires_at| expires_at <= SystemTime::now());
+    if expired {
+        return Ok(None);
+    }
+    remote.get(key).await
+}
+
+// drops expired keys from a get_all result; the periodic sweep deletes them for real
+fn retain_unexpired(map: &mut HashMap<String, Vec<u8>>) {
+    let now = SystemTime::now();
+    let expired: Vec<String> = map
+        .iter()
+        .filter_map(|(record, bytes)| {
+            let key = record.strip_prefix(KV_TTL_PREFIX)?;
+            decode_expiry(bytes)
+                .is_some_and(|expires_at| expires_at <= now)
+                .then(|| key.to_string())
+        })
+        .collect();
+    for key in expired {
+        map.remove(&key);
+    }
+}
+
+// presence is only known by asking the server, so a peer going offline without a signal we
+// see is noticed within this long
+pub(crate) const PEER_PRESENCE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Asks the server which registered peers are online, for every session with presence subscribers
+pub(crate) async fn poll_peer_presence(
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    remote: &NodeRemote,
 ) {
-    match remote.remove(&key).await {
-        Ok(_) => {
-            send_response_to_tcp_client(
-                tcp_connection_map,
-                InternalServiceResponse::LocalDBDeleteKVSuccess(LocalDBDeleteKVSuccess {
+    let cids: Vec<u64> = server_connection_map.lock().await.keys().copied().collect();
+    for cid in cids {
+        refresh_peer_presence(server_connection_map, tcp_connection_map, remote, cid).await;
+    }
+}
+
+// The online status comes from the server's view of each registered peer, so tearing down a
+// P2P channel does not make a peer that is still connected to the server look offline
+pub(crate) async fn refresh_peer_presence(
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    remote: &NodeRemote,
+    cid: u64,
+) {
+    let subscribed = server_connection_map
+        .lock()
+        .await
+        .get(&cid)
+        .is_some_and(|conn| !conn.presence_subscribers.is_empty());
+    if !subscribed {
+        return;
+    }
+    match remote.get_local_group_mutual_peers(cid).await {
+        Ok(peers) => {
+            for peer in peers {
+                report_peer_presence(
+                    server_connection_map,
+                    tcp_connection_map,
                     cid,
-                    peer_cid,
-                    key,
-                }),
-                uuid,
-            )
-            .await;
+                    peer.cid,
+                    peer.online_status,
+                )
+                .await;
+            }
         }
         Err(err) => {
-            send_response_to_tcp_client(
-                tcp_connection_map,
-                InternalServiceResponse::LocalDBDeleteKVFailure(LocalDBDeleteKVFailure {
-                    cid,
-                    peer_cid,
-                    message: err.into_string(),
-                }),
-                uuid,
-            )
-            .await;
+            warn!(target: "citadel", "Failed to get peer presence for {cid}: {err:?}");
         }
     }
 }
 
-// backend handler get_all
-async fn backend_handler_get_all(
-    remote: &impl BackendHandler,
-    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, UnboundedSender<InternalServiceResponse>>>>,
-    uuid: Uuid,
+// tells the session's presence subscribers about a peer, unless they were already told
+async fn report_peer_presence(
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
     cid: u64,
-    peer_cid: Option<u64>,
+    peer_cid: u64,
+    online: bool,
 ) {
-    match remote.get_all().await {
-        Ok(map) => {
-            send_response_to_tcp_client(
-                tcp_connection_map,
-                InternalServiceResponse::LocalDBGetAllKVSuccess(LocalDBGetAllKVSuccess {
-                    cid,
-                    peer_cid,
-                    map,
-                }),
-                uuid,
-            )
-            .await;
+    let subscribers = match server_connection_map.lock().await.get_mut(&cid) {
+        Some(conn) if !conn.presence_subscribers.is_empty() => {
+            if conn.peer_presence.insert(peer_cid, online) == Some(online) {
+                return;
+            }
+            conn.presence_subscribers.clone()
+        }
+        _ => return,
+    };
+
+    broadcast_response_to_tcp_clients(
+        tcp_connection_map,
+        InternalServiceResponse::PeerPresenceChanged(PeerPresenceChanged {
+            cid,
+            peer_cid,
+            online,
+        }),
+        subscribers,
+    )
+    .await;
+}
+
+// offers nobody accepted or rejected in time are declined, so the sender is not left waiting
+pub(crate) async fn expire_pending_file_transfers(
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+) {
+    let mut expired = Vec::new();
+    for (cid, conn) in server_connection_map.lock().await.iter_mut() {
+        let stale: Vec<u64> = conn
+            .pending_file_transfers
+            .iter()
+            .filter(|(_, pending)| pending.offered_at.elapsed() >= PENDING_FILE_TRANSFER_TIMEOUT)
+            .map(|(object_id, _)| *object_id)
+            .collect();
+        for object_id in stale {
+            let Some(mut pending) = conn.pending_file_transfers.remove(&object_id) else {
+                continue;
+            };
+            let peer_cid = conn
+                .peers
+                .contains_key(&pending.handle.source)
+                .then_some(pending.handle.source);
+            if let Err(err) = pending.handle.decline() {
+                warn!(target: "citadel", "Failed to decline file transfer {object_id}: {err:?}");
+            }
+            let response = InternalServiceResponse::FileReceiveFailure(FileReceiveFailure {
+                cid: *cid,
+                peer_cid,
+                object_id,
+                message: "The file transfer was not accepted in time".to_string(),
+            });
+            expired.push((conn.associated_tcp_connection, response));
+        }
+    }
+
+    for (uuid, response) in expired {
+        send_response_to_tcp_client(tcp_connection_map, response, uuid).await;
+    }
+}
+
+pub(crate) async fn sweep_expired_kv(
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+) {
+    // the remotes are cloned out so the backend is not read while holding the lock
+    let (server_remotes, peer_remotes) = {
+        let server_connection_map = server_connection_map.lock().await;
+        let server_remotes: Vec<_> = server_connection_map
+            .iter()
+            .map(|(cid, conn)| (*cid, conn.client_server_remote.clone()))
+            .collect();
+        let peer_remotes: Vec<_> = server_connection_map
+            .iter()
+            .flat_map(|(cid, conn)| {
+                conn.peers
+                    .iter()
+                    .map(|(peer_cid, peer)| (*cid, *peer_cid, peer.remote.clone()))
+            })
+            .collect();
+        (server_remotes, peer_remotes)
+    };
+
+    let mut expired = Vec::new();
+    for (cid, remote) in server_remotes {
+        expired.push((cid, None, remove_expired_kv(&remote).await));
+    }
+    for (cid, peer_cid, remote) in peer_remotes {
+        expired.push((cid, Some(peer_cid), remove_expired_kv(&remote).await));
+    }
+
+    let mut server_connection_map = server_connection_map.lock().await;
+    for (cid, peer_cid, changes) in expired {
+        if changes.is_empty() {
+            continue;
+        }
+        // the session may have ended while the sweep ran
+        if let Some(conn) = server_connection_map.get_mut(&cid) {
+            for (key, _) in &changes {
+                conn.index_kv_key(peer_cid, key, false);
+            }
+            notify_kv_watchers(conn, tcp_connection_map, cid, peer_cid, changes).await;
         }
+    }
+}
+
+// returns the removed keys as changes for the watchers
+async fn remove_expired_kv(remote: &impl BackendHandler) -> Vec<(String, Option<Vec<u8>>)> {
+    let map = match remote.get_all().await {
+        Ok(map) => map,
         Err(err) => {
-            send_response_to_tcp_client(
-                tcp_connection_map,
-                InternalServiceResponse::LocalDBGetAllKVFailure(LocalDBGetAllKVFailure {
-                    cid,
-                    peer_cid,
-                    message: err.into_string(),
-                }),
-                uuid,
-            )
-            .await;
+            warn!(target: "citadel", "Failed to sweep expired keys: {err:?}");
+            return vec![];
         }
+    };
+
+    let mut expired = Vec::new();
+    let now = SystemTime::now();
+    for (record, bytes) in map {
+        if let Some(staged) = record.strip_prefix(KV_CHUNK_STAGING_PREFIX) {
+            remove_expired_upload(remote, &record, staged, &bytes, now).await;
+            continue;
+        }
+        let Some(key) = record.strip_prefix(KV_TTL_PREFIX) else {
+            continue;
+        };
+        if !decode_expiry(&bytes).is_some_and(|expires_at| expires_at <= now) {
+            continue;
+        }
+
+        if let Err(err) = remote.remove(key).await {
+            warn!(target: "citadel", "Failed to remove expired key {key}: {err:?}");
+            continue;
+        }
+        if let Err(err) = remote.remove(&record).await {
+            warn!(target: "citadel", "Failed to remove the ttl of {key}: {err:?}");
+        }
+        expired.push((key.to_string(), None));
     }
+    expired
 }
 
-// backend handler clear all
-async fn backend_handler_clear_all(
+// drops the chunks of an upload that stopped before it was complete
+async fn remove_expired_upload(
     remote: &impl BackendHandler,
-    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, UnboundedSender<InternalServiceResponse>>>>,
+    record: &str,
+    staged_key: &str,
+    bytes: &[u8],
+    now: SystemTime,
+) {
+    let staged = StagedUpload::decode(bytes);
+    if staged
+        .as_ref()
+        .is_some_and(|staged| staged.expires_at > now)
+    {
+        return;
+    }
+
+    let upload = staged_key
+        .split_once('/')
+        .and_then(|(upload, key)| Some((Uuid::parse_str(upload).ok()?, key)));
+    if let (Some(staged), Some((upload, key))) = (staged, upload) {
+        for index in (0..staged.total).filter(|index| staged.is_received(*index)) {
+            if let Err(err) = remote.remove(&chunk_key(upload, index, key)).await {
+                warn!(target: "citadel", "Failed to remove an expired chunk of {key}: {err:?}");
+            }
+        }
+    }
+    if let Err(err) = remote.remove(record).await {
+        warn!(target: "citadel", "Failed to remove the expired upload {staged_key}: {err:?}");
+    }
+}
+
+// the UDP channel is established after the session, so outbound datagrams are queued until it arrives
+fn spawn_udp_channel(
+    udp_rx: tokio::sync::oneshot::Receiver<UdpChannel>,
+    tcp_connection_map: Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
     uuid: Uuid,
     cid: u64,
     peer_cid: Option<u64>,
+) -> (Sender<Vec<u8>>, JoinHandle<()>) {
+    let (tx, mut outbound) = tokio::sync::mpsc::channel::<Vec<u8>>(UDP_SEND_QUEUE_CAPACITY);
+    let task = tokio::spawn(async move {
+        let Ok(udp_channel) = udp_rx.await else {
+            warn!(target: "citadel", "UDP channel for {cid} (peer {peer_cid:?}) was never established");
+            return;
+        };
+        let (sink, mut stream) = udp_channel.split();
+        loop {
+            tokio::select! {
+                outgoing = outbound.recv() => match outgoing {
+                    Some(payload) => {
+                        if let Err(err) = sink.unbounded_send(payload) {
+                            warn!(target: "citadel", "Failed to send datagram for {cid}: {err:?}");
+                        }
+                    }
+                    // the owning connection was dropped
+                    None => break,
+                },
+                incoming = stream.next() => match incoming {
+                    Some(message) => {
+                        let response = InternalServiceResponse::UnreliableMessageReceived(
+                            UnreliableMessageReceived {
+                                message: message.into_buffer(),
+                                cid,
+                                peer_cid,
+                            },
+                        );
+                        send_response_to_tcp_client(&tcp_connection_map, response, uuid).await;
+                    }
+                    None => break,
+                },
+            }
+        }
+    });
+    (tx, task)
+}
+
+async fn connect_to_server(
+    remote: &mut NodeRemote,
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    tcp_connection_map: &Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    uuid: Uuid,
+    connect_parameters: ConnectParameters,
+) -> Result<(u64, PeerChannelRecvHalf), NetworkError> {
+    let conn_success = remote
+        .connect(
+            AuthenticationRequest::credentialed(
+                connect_parameters.username.clone(),
+                connect_parameters.password.clone(),
+            ),
+            connect_parameters.connect_mode,
+            connect_parameters.udp_mode,
+            connect_parameters.keep_alive_timeout,
+            connect_parameters.session_security_settings,
+        )
+        .await?;
+    let cid = conn_success.cid;
+
+    let (sink, stream) = conn_success.channel.split();
+    let client_server_remote = create_client_server_remote(stream.vconn_type, remote.clone());
+    let server_address = match remote.account_manager().get_client_by_cid(cid).await {
+        Ok(Some(cnac)) => Some(cnac.get_connect_info().addr),
+        _ => None,
+    };
+    let mut connection_struct = Connection::new(
+        sink,
+        client_server_remote,
+        uuid,
+        connect_parameters.username.clone(),
+        server_address,
+        connect_parameters.session_security_settings,
+        connect_parameters.udp_mode,
+    );
+    connection_struct.download_directory = connect_parameters.download_directory.clone();
+    if let Some(udp_rx) = conn_success.udp_rx_opt {
+        let (udp_sink, udp_task) =
+            spawn_udp_channel(udp_rx, tcp_connection_map.clone(), uuid, cid, None);
+        connection_struct.udp_sink = Some(udp_sink);
+        connection_struct.session_tasks.push(udp_task);
+    }
+    connection_struct.connect_parameters = connect_parameters
+        .reconnect_policy
+        .is_some()
+        .then_some(connect_parameters);
+    server_connection_map
+        .lock()
+        .await
+        .insert(cid, connection_struct);
+
+    Ok((cid, stream))
+}
+
+async fn spawn_server_read_stream(
+    mut stream: PeerChannelRecvHalf,
+    server_connection_map: &Arc<Mutex<HashMap<u64, Connection>>>,
+    tcp_connection_map: Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    uuid: Uuid,
+    cid: u64,
 ) {
-    match remote.remove_all().await {
-        Ok(_) => {
-            send_response_to_tcp_client(
-                tcp_connection_map,
-                InternalServiceResponse::LocalDBClearAllKVSuccess(LocalDBClearAllKVSuccess {
-                    cid,
-                    peer_cid,
-                }),
-                uuid,
-            )
-            .await;
+    let connection_read_stream = async move {
+        while let Some(message) = stream.next().await {
+            let message = InternalServiceResponse::MessageReceived(MessageReceived {
+                message: message.into_buffer(),
+                cid,
+                peer_cid: 0,
+            });
+            match tcp_connection_map.lock().await.get(&uuid) {
+                Some(entry) => match entry.send(message) {
+                    Ok(res) => res,
+                    Err(_) => info!(target: "citadel", "tx not sent"),
+                },
+                None => {
+                    info!(target:"citadel","Hash map connection not found")
+                }
+            }
         }
-        Err(err) => {
-            send_response_to_tcp_client(
-                tcp_connection_map,
-                InternalServiceResponse::LocalDBClearAllKVFailure(LocalDBClearAllKVFailure {
+    };
+    let task = tokio::spawn(connection_read_stream);
+    match server_connection_map.lock().await.get_mut(&cid) {
+        Some(conn) => conn.session_tasks.push(task),
+        None => task.abort(),
+    }
+}
+
+// restores a dropped session and its peer connections, giving up after the policy's max_attempts
+pub(crate) async fn reconnect_with_backoff(
+    mut remote: NodeRemote,
+    server_connection_map: Arc<Mutex<HashMap<u64, Connection>>>,
+    tcp_connection_map: Arc<Mutex<HashMap<Uuid, ResponseSender>>>,
+    defaults: ServiceDefaults,
+    session: DroppedSession,
+) {
+    let DroppedSession {
+        uuid,
+        cid,
+        connect_parameters,
+        peers,
+    } = session;
+    let Some(policy) = connect_parameters.reconnect_policy else {
+        return;
+    };
+
+    let mut backoff = policy.initial_backoff;
+    for attempt in 1..=policy.max_attempts {
+        if !tcp_connection_map.lock().await.contains_key(&uuid) {
+            info!(target: "citadel", "TCP client for {cid} is gone, not reconnecting");
+            return;
+        }
+
+        let response = InternalServiceResponse::Reconnecting(Reconnecting {
+            cid,
+            attempt,
+            max_attempts: policy.max_attempts,
+            backoff,
+        });
+        broadcast_response_to_tcp_clients(&tcp_connection_map, response, [uuid]).await;
+        tokio::time::sleep(backoff).await;
+        backoff = backoff.saturating_mul(2).min(policy.max_backoff);
+
+        match connect_to_server(
+            &mut remote,
+            &server_connection_map,
+            &tcp_connection_map,
+            uuid,
+            connect_parameters.clone(),
+        )
+        .await
+        {
+            Ok((cid, stream)) => {
+                let response = InternalServiceResponse::Reconnected(Reconnected { cid });
+                broadcast_response_to_tcp_clients(&tcp_connection_map, response, [uuid]).await;
+                spawn_server_read_stream(
+                    stream,
+                    &server_connection_map,
+                    tcp_connection_map.clone(),
+                    uuid,
                     cid,
-                    peer_cid,
-                    message: err.into_string(),
-                }),
-                uuid,
-            )
-            .await;
+                )
+                .await;
+
+                // each restored peer reports back through PeerConnectSuccess/PeerConnectFailure,
+                // and PeersRestored sums them up
+                let mut restored = Vec::new();
+                let mut failed = Vec::new();
+                let had_peers = !peers.is_empty();
+                for peer in peers {
+                    let peer_cid = peer.peer_cid;
+                    let command = InternalServicePayload::PeerConnect {
+                        uuid,
+                        cid,
+                        username: connect_parameters.username.clone(),
+                        peer_cid: peer.peer_cid,
+                        peer_username: peer.peer_username,
+                        udp_mode: peer.udp_mode,
+                        session_security_settings: peer.session_security_settings,
+                        force: false,
+                    };
+                    payload_handler(
+                        command,
+                        &server_connection_map,
+                        &mut remote,
+                        &tcp_connection_map,
+                        &defaults,
+                    )
+                    .await;
+
+                    let is_connected = server_connection_map
+                        .lock()
+                        .await
+                        .get(&cid)
+                        .map(|conn| conn.peers.contains_key(&peer_cid))
+                        .unwrap_or(false);
+                    if is_connected {
+                        restored.push(peer_cid);
+                    } else {
+                        failed.push(peer_cid);
+                    }
+                }
+
+                if had_peers {
+                    let response = InternalServiceResponse::PeersRestored(PeersRestored {
+                        cid,
+                        restored,
+                        failed,
+                    });
+                    broadcast_response_to_tcp_clients(&tcp_connection_map, response, [uuid]).await;
+                }
+                return;
+            }
+
+            Err(err) => {
+                warn!(target: "citadel", "Reconnect attempt {attempt} for {cid} failed: {err:?}");
+            }
         }
     }
+
+    let response = InternalServiceResponse::ReconnectFailure(ReconnectFailure {
+        cid,
+        message: format!("Failed to reconnect after {} attempts", policy.max_attempts),
+    });
+    broadcast_response_to_tcp_clients(&tcp_connection_map, response, [uuid]).await;
 }
@@ -0,0 +1,147 @@
+use citadel_workspace_types::InternalServiceResponse;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+// what happens when a TCP client stops reading and its response queue fills up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResponseOverflowPolicy {
+    #[default]
+    DropOldest,
+    Disconnect,
+}
+
+struct ResponseQueue {
+    state: Mutex<ResponseQueueState>,
+    notify: Notify,
+    capacity: usize,
+    overflow_policy: ResponseOverflowPolicy,
+    dropped: AtomicU64,
+}
+
+struct ResponseQueueState {
+    responses: VecDeque<InternalServiceResponse>,
+    closed: bool,
+    sender_dropped: bool,
+}
+
+pub struct ResponseSender {
+    queue: Arc<ResponseQueue>,
+}
+
+pub struct ResponseReceiver {
+    queue: Arc<ResponseQueue>,
+}
+
+pub fn response_queue(
+    capacity: usize,
+    overflow_policy: ResponseOverflowPolicy,
+) -> (ResponseSender, ResponseReceiver) {
+    let queue = Arc::new(ResponseQueue {
+        state: Mutex::new(ResponseQueueState {
+            responses: VecDeque::with_capacity(capacity),
+            closed: false,
+            sender_dropped: false,
+        }),
+        notify: Notify::new(),
+        capacity: capacity.max(1),
+        overflow_policy,
+        dropped: AtomicU64::new(0),
+    });
+
+    (
+        ResponseSender {
+            queue: queue.clone(),
+        },
+        ResponseReceiver { queue },
+    )
+}
+
+impl ResponseSender {
+    // mirrors UnboundedSender::send: the response is handed back if the client is gone
+    pub fn send(&self, response: InternalServiceResponse) -> Result<(), InternalServiceResponse> {
+        let mut state = self.queue.state.lock();
+        if state.closed {
+            return Err(response);
+        }
+
+        if state.responses.len() >= self.queue.capacity {
+            match self.queue.overflow_policy {
+                ResponseOverflowPolicy::DropOldest => {
+                    let _ = state.responses.pop_front();
+                    self.queue.dropped.fetch_add(1, Ordering::Relaxed);
+                    citadel_logging::warn!(target: "citadel", "Response queue full, dropped the oldest response");
+                }
+                ResponseOverflowPolicy::Disconnect => {
+                    citadel_logging::warn!(target: "citadel", "Response queue full, disconnecting client");
+                    state.closed = true;
+                    state.responses.clear();
+                    drop(state);
+                    self.queue.notify.notify_one();
+                    return Err(response);
+                }
+            }
+        }
+
+        state.responses.push_back(response);
+        drop(state);
+        self.queue.notify.notify_one();
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.state.lock().responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.queue.capacity
+    }
+
+    // responses discarded by the DropOldest policy since the client connected
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl ResponseReceiver {
+    // returns None once the client is disconnected, or once the sender is gone and the queue drained
+    pub async fn recv(&mut self) -> Option<InternalServiceResponse> {
+        loop {
+            {
+                let mut state = self.queue.state.lock();
+                if state.closed {
+                    return None;
+                }
+
+                if let Some(response) = state.responses.pop_front() {
+                    return Some(response);
+                }
+
+                if state.sender_dropped {
+                    return None;
+                }
+            }
+
+            self.queue.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for ResponseSender {
+    fn drop(&mut self) {
+        self.queue.state.lock().sender_dropped = true;
+        self.queue.notify.notify_one();
+    }
+}
+
+impl Drop for ResponseReceiver {
+    fn drop(&mut self) {
+        self.queue.state.lock().closed = true;
+    }
+}
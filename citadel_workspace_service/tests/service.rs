@@ -2,20 +2,35 @@
 mod tests {
     use bytes::Bytes;
     use citadel_logging::info;
+    use citadel_sdk::prefabs::ClientServerRemote;
     use citadel_sdk::prelude::*;
+    use citadel_workspace_lib::settings::{
+        decode_value, get_settings, set_settings, settings_from_response, SettingsError,
+    };
     use citadel_workspace_lib::wrap_tcp_conn;
-    use citadel_workspace_service::kernel::CitadelWorkspaceService;
+    use citadel_workspace_service::kernel::response_queue::{
+        response_queue, ResponseOverflowPolicy,
+    };
+    use citadel_workspace_service::kernel::{CitadelWorkspaceService, TcpClientSettings};
     use citadel_workspace_types::{
-        InternalServicePayload, InternalServiceResponse, MessageReceived, MessageSent,
-        PeerConnectSuccess, PeerRegisterSuccess, ServiceConnectionAccepted,
+        Disconnected, FileReceived, IncomingFileTransfer, InternalServicePayload,
+        InternalServiceResponse, ListFileTransfersSuccess, LocalDBBatchOperation, LocalDBErrorCode,
+        LocalDBGetKVSuccess, LocalDBImportMode, LocalDBImportSource, MessageReceived, MessageSent,
+        NotificationSettings, PeerConnectSuccess, PeerDisconnectSuccess, PeerPresenceChanged,
+        PeerRegisterSuccess, Pong, ReconnectPolicy, SendFileFailure, SendFileProgress,
+        SendFileSuccess, ServiceConnectionAccepted, Settings, ThemeMode, ThemeSettings,
     };
     use core::panic;
     use futures::stream::SplitSink;
     use futures::{SinkExt, StreamExt};
+    use serde::{Deserialize, Serialize};
     use std::collections::HashMap;
     use std::error::Error;
     use std::future::Future;
     use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
     use std::time::Duration;
     use tokio::net::TcpStream;
     use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
@@ -116,6 +131,37 @@ mod tests {
             u64,
         ),
         Box<dyn Error>,
+    > {
+        register_and_connect_to_server_with_reconnect_policy(
+            internal_service_addr,
+            server_addr,
+            full_name,
+            username,
+            password,
+            None,
+        )
+        .await
+    }
+
+    async fn register_and_connect_to_server_with_reconnect_policy<
+        T: Into<String>,
+        R: Into<String>,
+        S: Into<SecBuffer>,
+    >(
+        internal_service_addr: SocketAddr,
+        server_addr: SocketAddr,
+        full_name: T,
+        username: R,
+        password: S,
+        reconnect_policy: Option<ReconnectPolicy>,
+    ) -> Result<
+        (
+            UnboundedSender<InternalServicePayload>,
+            UnboundedReceiver<InternalServiceResponse>,
+            Uuid,
+            u64,
+        ),
+        Box<dyn Error>,
     > {
         let conn = TcpStream::connect(internal_service_addr).await?;
         info!(target: "citadel", "connected to the TCP stream");
@@ -164,6 +210,7 @@ mod tests {
                     keep_alive_timeout: None,
                     uuid: id,
                     session_security_settings: Default::default(),
+                    reconnect_policy,
                 };
 
                 send(&mut sink, command).await?;
@@ -268,7 +315,7 @@ mod tests {
             message: serialized_message,
             cid,
             peer_cid: None,
-            security_level: SecurityLevel::Standard,
+            security_level: Some(SecurityLevel::Standard),
         };
         to_service.send(message_command).unwrap();
         let deserialized_message_response = from_service.recv().await.unwrap();
@@ -400,10 +447,128 @@ mod tests {
         u64,
     );
 
+    // hands the node remote to the test, so it can look into the local database
+    struct RemoteCapturingKernel {
+        inner: CitadelWorkspaceService,
+        remote: Arc<std::sync::Mutex<Option<NodeRemote>>>,
+    }
+
+    #[async_trait]
+    impl NetKernel for RemoteCapturingKernel {
+        fn load_remote(&mut self, node_remote: NodeRemote) -> Result<(), NetworkError> {
+            *self.remote.lock().unwrap() = Some(node_remote.clone());
+            self.inner.load_remote(node_remote)
+        }
+
+        async fn on_start(&self) -> Result<(), NetworkError> {
+            self.inner.on_start().await
+        }
+
+        async fn on_node_event_received(&self, message: NodeResult) -> Result<(), NetworkError> {
+            self.inner.on_node_event_received(message).await
+        }
+
+        async fn on_stop(&mut self) -> Result<(), NetworkError> {
+            self.inner.on_stop().await
+        }
+    }
+
     async fn register_and_connect_to_server_then_peers(
         a_int_svc_addr: SocketAddr,
         b_int_svc_addr: SocketAddr,
     ) -> Result<PeerReturnHandle, Box<dyn Error>> {
+        let (handle, _remote_a) =
+            register_and_connect_to_server_then_peers_with_remote(a_int_svc_addr, b_int_svc_addr)
+                .await?;
+        Ok(handle)
+    }
+
+    // also returns the node remote of peer A
+    async fn register_and_connect_to_server_then_peers_with_remote(
+        a_int_svc_addr: SocketAddr,
+        b_int_svc_addr: SocketAddr,
+    ) -> Result<(PeerReturnHandle, NodeRemote), Box<dyn Error>> {
+        let (
+            (
+                to_service_a,
+                mut from_service_a,
+                to_service_b,
+                mut from_service_b,
+                uuid_a,
+                uuid_b,
+                cid_a,
+                cid_b,
+            ),
+            remote_a,
+        ) = register_to_server_then_peers_with_remote(a_int_svc_addr, b_int_svc_addr).await?;
+
+        to_service_a
+            .send(InternalServicePayload::PeerConnect {
+                uuid: uuid_a,
+                cid: cid_a,
+                username: String::from("peer.a"),
+                peer_cid: cid_b,
+                peer_username: String::from("peer.b"),
+                udp_mode: Default::default(),
+                session_security_settings: Default::default(),
+                force: false,
+            })
+            .unwrap();
+
+        to_service_b
+            .send(InternalServicePayload::PeerConnect {
+                uuid: uuid_b,
+                cid: cid_b,
+                username: String::from("peer.b"),
+                peer_cid: cid_a,
+                peer_username: String::from("peer.a"),
+                udp_mode: Default::default(),
+                session_security_settings: Default::default(),
+                force: false,
+            })
+            .unwrap();
+
+        let item = from_service_b.recv().await.unwrap();
+        match item {
+            InternalServiceResponse::PeerConnectSuccess(PeerConnectSuccess { cid, .. }) => {
+                assert_eq!(cid, cid_b);
+            }
+            _ => {
+                info!(target = "citadel", "{:?}", item);
+                panic!("Didn't get the PeerConnectSuccess");
+            }
+        }
+
+        let item = from_service_a.recv().await.unwrap();
+        match item {
+            InternalServiceResponse::PeerConnectSuccess(PeerConnectSuccess { cid, .. }) => {
+                assert_eq!(cid, cid_a);
+                Ok((
+                    (
+                        to_service_a,
+                        from_service_a,
+                        to_service_b,
+                        from_service_b,
+                        uuid_a,
+                        uuid_b,
+                        cid_a,
+                        cid_b,
+                    ),
+                    remote_a,
+                ))
+            }
+            _ => {
+                info!(target = "citadel", "{:?}", item);
+                panic!("Didn't get the PeerConnectSuccess");
+            }
+        }
+    }
+
+    // registers both peers to each other without connecting them
+    async fn register_to_server_then_peers_with_remote(
+        a_int_svc_addr: SocketAddr,
+        b_int_svc_addr: SocketAddr,
+    ) -> Result<(PeerReturnHandle, NodeRemote), Box<dyn Error>> {
         // internal service for peer A
         let bind_address_internal_service_a = a_int_svc_addr;
         // internal service for peer B
@@ -414,8 +579,11 @@ mod tests {
 
         tokio::task::spawn(server);
         info!(target: "citadel", "sub server spawn");
-        let internal_service_kernel_a =
-            CitadelWorkspaceService::new(bind_address_internal_service_a);
+        let remote_a = Arc::new(std::sync::Mutex::new(None));
+        let internal_service_kernel_a = RemoteCapturingKernel {
+            inner: CitadelWorkspaceService::new(bind_address_internal_service_a),
+            remote: remote_a.clone(),
+        };
         let internal_service_a = NodeBuilder::default()
             .with_node_type(NodeType::Peer)
             .with_backend(BackendType::InMemory)
@@ -463,6 +631,8 @@ mod tests {
                 cid: cid_a,
                 peer_id: cid_b.into(),
                 connect_after_register: false,
+                udp_mode: Default::default(),
+                session_security_settings: Default::default(),
             })
             .unwrap();
 
@@ -472,6 +642,8 @@ mod tests {
                 cid: cid_b,
                 peer_id: cid_a.into(),
                 connect_after_register: false,
+                udp_mode: Default::default(),
+                session_security_settings: Default::default(),
             })
             .unwrap();
 
@@ -484,7 +656,7 @@ mod tests {
                 username,
             }) => {
                 assert_eq!(cid, cid_b);
-                assert_eq!(peer_cid, cid_b);
+                assert_eq!(peer_cid, cid_a);
                 assert_eq!(username, "peer.a");
             }
             _ => {
@@ -500,7 +672,7 @@ mod tests {
                 username,
             }) => {
                 assert_eq!(cid, cid_a);
-                assert_eq!(peer_cid, cid_a);
+                assert_eq!(peer_cid, cid_b);
                 assert_eq!(username, "peer.b");
             }
             _ => {
@@ -508,61 +680,120 @@ mod tests {
             }
         }
 
-        to_service_a
-            .send(InternalServicePayload::PeerConnect {
-                uuid: uuid_a,
-                cid: cid_a,
-                username: String::from("peer.a"),
-                peer_cid: cid_b,
-                peer_username: String::from("peer.b"),
-                udp_mode: Default::default(),
-                session_security_settings: Default::default(),
-            })
-            .unwrap();
+        let remote_a = remote_a.lock().unwrap().take().unwrap();
+        Ok((
+            (
+                to_service_a,
+                from_service_a,
+                to_service_b,
+                from_service_b,
+                uuid_a,
+                uuid_b,
+                cid_a,
+                cid_b,
+            ),
+            remote_a,
+        ))
+    }
 
-        to_service_b
-            .send(InternalServicePayload::PeerConnect {
-                uuid: uuid_b,
-                cid: cid_b,
-                username: String::from("peer.b"),
-                peer_cid: cid_a,
-                peer_username: String::from("peer.a"),
-                udp_mode: Default::default(),
-                session_security_settings: Default::default(),
-            })
+    #[tokio::test]
+    async fn test_citadel_workspace_service_peer_register_connect_after_register(
+    ) -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let bind_address_internal_service_a: SocketAddr = "127.0.0.1:55606".parse().unwrap();
+        let bind_address_internal_service_b: SocketAddr = "127.0.0.1:55607".parse().unwrap();
+
+        let (server, server_bind_address) = citadel_sdk::test_common::server_info();
+        tokio::task::spawn(server);
+
+        let internal_service_a = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(CitadelWorkspaceService::new(
+                bind_address_internal_service_a,
+            ))
+            .unwrap();
+        let internal_service_b = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(CitadelWorkspaceService::new(
+                bind_address_internal_service_b,
+            ))
             .unwrap();
+        spawn_services(internal_service_a, internal_service_b);
+        tokio::time::sleep(Duration::from_millis(2000)).await;
 
-        let item = from_service_b.recv().await.unwrap();
-        match item {
-            InternalServiceResponse::PeerConnectSuccess(PeerConnectSuccess { cid }) => {
-                assert_eq!(cid, cid_b);
+        let (to_service_a, mut from_service_a, uuid_a, cid_a) = register_and_connect_to_server(
+            bind_address_internal_service_a,
+            server_bind_address,
+            "Peer A",
+            "peer.a",
+            "secret_a",
+        )
+        .await?;
+        let (to_service_b, mut from_service_b, uuid_b, cid_b) = register_and_connect_to_server(
+            bind_address_internal_service_b,
+            server_bind_address,
+            "Peer B",
+            "peer.b",
+            "secret_b",
+        )
+        .await?;
+
+        to_service_a.send(InternalServicePayload::PeerRegister {
+            uuid: uuid_a,
+            cid: cid_a,
+            peer_id: cid_b.into(),
+            connect_after_register: true,
+            udp_mode: Default::default(),
+            session_security_settings: Default::default(),
+        })?;
+        to_service_b.send(InternalServicePayload::PeerRegister {
+            uuid: uuid_b,
+            cid: cid_b,
+            peer_id: cid_a.into(),
+            connect_after_register: true,
+            udp_mode: Default::default(),
+            session_security_settings: Default::default(),
+        })?;
+
+        for (from_service, own_cid, peer_cid) in [
+            (&mut from_service_a, cid_a, cid_b),
+            (&mut from_service_b, cid_b, cid_a),
+        ] {
+            match from_service.recv().await.unwrap() {
+                InternalServiceResponse::PeerRegisterSuccess(PeerRegisterSuccess {
+                    cid,
+                    peer_cid: registered_cid,
+                    ..
+                }) => {
+                    assert_eq!(cid, own_cid);
+                    assert_eq!(registered_cid, peer_cid);
+                }
+                item => panic!("Didn't get the PeerRegisterSuccess: {item:?}"),
             }
-            _ => {
-                info!(target = "citadel", "{:?}", item);
-                panic!("Didn't get the PeerConnectSuccess");
+            match from_service.recv().await.unwrap() {
+                InternalServiceResponse::PeerConnectSuccess(PeerConnectSuccess { cid, .. }) => {
+                    assert_eq!(cid, own_cid);
+                }
+                item => panic!("Didn't get the PeerConnectSuccess: {item:?}"),
             }
         }
 
-        let item = from_service_a.recv().await.unwrap();
-        match item {
-            InternalServiceResponse::PeerConnectSuccess(PeerConnectSuccess { cid }) => {
-                assert_eq!(cid, cid_a);
-                Ok((
-                    to_service_a,
-                    from_service_a,
-                    to_service_b,
-                    from_service_b,
-                    uuid_a,
-                    uuid_b,
-                    cid_a,
-                    cid_b,
-                ))
-            }
-            _ => {
-                info!(target = "citadel", "{:?}", item);
-                panic!("Didn't get the PeerConnectSuccess");
+        to_service_a.send(InternalServicePayload::ListPeers {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        match from_service_a.recv().await.unwrap() {
+            InternalServiceResponse::ListPeersSuccess(resp) => {
+                assert_eq!(resp.peers.len(), 1);
+                assert_eq!(resp.peers[0].peer_cid, cid_b);
+                assert_eq!(resp.peers[0].peer_username, "peer.b");
             }
+            item => panic!("Didn't get the ListPeersSuccess: {item:?}"),
         }
+
+        Ok(())
     }
 
     #[tokio::test]
@@ -576,6 +807,58 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_citadel_workspace_service_peer_connect_is_idempotent(
+    ) -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (
+            to_service_a,
+            mut from_service_a,
+            _to_service_b,
+            _from_service_b,
+            uuid_a,
+            _uuid_b,
+            cid_a,
+            cid_b,
+        ) = register_and_connect_to_server_then_peers(
+            "127.0.0.1:55616".parse().unwrap(),
+            "127.0.0.1:55617".parse().unwrap(),
+        )
+        .await?;
+
+        to_service_a.send(InternalServicePayload::PeerConnect {
+            uuid: uuid_a,
+            cid: cid_a,
+            username: String::from("peer.a"),
+            peer_cid: cid_b,
+            peer_username: String::from("peer.b"),
+            udp_mode: Default::default(),
+            session_security_settings: Default::default(),
+            force: false,
+        })?;
+        match from_service_a.recv().await.unwrap() {
+            InternalServiceResponse::PeerConnectSuccess(PeerConnectSuccess {
+                cid,
+                already_connected,
+            }) => {
+                assert_eq!(cid, cid_a);
+                assert!(already_connected);
+            }
+            item => panic!("Didn't get the PeerConnectSuccess: {item:?}"),
+        }
+
+        to_service_a.send(InternalServicePayload::ListPeers {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        match from_service_a.recv().await.unwrap() {
+            InternalServiceResponse::ListPeersSuccess(resp) => assert_eq!(resp.peers.len(), 1),
+            item => panic!("Didn't get the ListPeersSuccess: {item:?}"),
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_citadel_workspace_service_peer_message_test() -> Result<(), Box<dyn Error>> {
         citadel_logging::setup_log();
@@ -635,42 +918,8 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_c2s_kv() -> Result<(), Box<dyn Error>> {
-        citadel_logging::setup_log();
-        let (server, server_bind_address) = citadel_sdk::test_common::server_info();
-
-        let bind_address_internal_service_a: SocketAddr = "127.0.0.1:55537".parse().unwrap();
-        let internal_service = NodeBuilder::default()
-            .with_node_type(NodeType::Peer)
-            .with_backend(BackendType::InMemory)
-            .build(CitadelWorkspaceService::new(
-                bind_address_internal_service_a,
-            ))
-            .unwrap();
-
-        spawn_services(internal_service, server);
-        tokio::time::sleep(Duration::from_millis(2000)).await;
-
-        let (to_service_a, mut from_service_a, uuid, cid) = register_and_connect_to_server(
-            bind_address_internal_service_a,
-            server_bind_address,
-            "peer a",
-            "peer.a",
-            "password",
-        )
-        .await?;
-
-        test_kv_for_service(&to_service_a, &mut from_service_a, uuid, cid, None).await
-    }
-
-    #[tokio::test]
-    async fn test_p2p_kv() -> Result<(), Box<dyn Error>> {
+    async fn test_citadel_workspace_service_p2p_file_transfer() -> Result<(), Box<dyn Error>> {
         citadel_logging::setup_log();
-        // internal service for peer A
-        let bind_address_internal_service_a: SocketAddr = "127.0.0.1:55536".parse().unwrap();
-        // internal service for peer B
-        let bind_address_internal_service_b: SocketAddr = "127.0.0.1:55537".parse().unwrap();
-
         let (
             to_service_a,
             mut from_service_a,
@@ -681,8 +930,1367 @@ mod tests {
             cid_a,
             cid_b,
         ) = register_and_connect_to_server_then_peers(
-            bind_address_internal_service_a,
-            bind_address_internal_service_b,
+            "127.0.0.1:55546".parse().unwrap(),
+            "127.0.0.1:55547".parse().unwrap(),
+        )
+        .await?;
+
+        let source = std::env::temp_dir().join("citadel_p2p_file_transfer.txt");
+        // several chunks, so progress is reported before the transfer completes
+        let contents = b"Hello, peer!".repeat(512);
+        std::fs::write(&source, &contents)?;
+        let download_directory = std::env::temp_dir().join("citadel_p2p_downloads");
+
+        to_service_b.send(InternalServicePayload::SetDownloadDirectory {
+            uuid: uuid_b,
+            cid: cid_b,
+            path: download_directory.clone(),
+        })?;
+        assert!(matches!(
+            from_service_b.recv().await.unwrap(),
+            InternalServiceResponse::SetDownloadDirectorySuccess(..)
+        ));
+
+        to_service_a.send(InternalServicePayload::SendFile {
+            uuid: uuid_a,
+            source,
+            cid: cid_a,
+            peer_cid: Some(cid_b),
+            chunk_size: 1024,
+            transfer_type: TransferType::FileTransfer,
+        })?;
+
+        let object_id = match from_service_b.recv().await.unwrap() {
+            InternalServiceResponse::IncomingFileTransfer(IncomingFileTransfer {
+                cid,
+                peer_cid,
+                object_id,
+                ..
+            }) => {
+                assert_eq!(cid, cid_b);
+                assert_eq!(peer_cid, Some(cid_a));
+                object_id
+            }
+            item => panic!("Didn't get the IncomingFileTransfer: {item:?}"),
+        };
+
+        to_service_b.send(InternalServicePayload::AcceptFileTransfer {
+            uuid: uuid_b,
+            cid: cid_b,
+            object_id,
+        })?;
+        assert!(matches!(
+            from_service_b.recv().await.unwrap(),
+            InternalServiceResponse::AcceptFileTransferSuccess(..)
+        ));
+
+        match from_service_b.recv().await.unwrap() {
+            InternalServiceResponse::FileReceived(FileReceived { path, .. }) => {
+                assert!(path.starts_with(&download_directory));
+                assert_eq!(std::fs::read(path)?, contents);
+            }
+            item => panic!("Didn't get the FileReceived: {item:?}"),
+        }
+
+        let mut last_progress = 0;
+        loop {
+            match from_service_a.recv().await.unwrap() {
+                InternalServiceResponse::SendFileProgress(SendFileProgress {
+                    cid,
+                    peer_cid,
+                    chunks_sent,
+                    total_chunks,
+                    ..
+                }) => {
+                    assert_eq!(cid, cid_a);
+                    assert_eq!(peer_cid, Some(cid_b));
+                    assert!(chunks_sent >= last_progress);
+                    assert!(chunks_sent <= total_chunks);
+                    last_progress = chunks_sent;
+                }
+                InternalServiceResponse::SendFileSuccess(SendFileSuccess {
+                    cid, peer_cid, ..
+                }) => {
+                    assert_eq!(cid, cid_a);
+                    assert_eq!(peer_cid, Some(cid_b));
+                    assert!(
+                        last_progress > 0,
+                        "no SendFileProgress before the SendFileSuccess"
+                    );
+                    break;
+                }
+                item => panic!("Didn't get the SendFileSuccess: {item:?}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_revfs_browse_and_delete() -> Result<(), Box<dyn Error>>
+    {
+        citadel_logging::setup_log();
+        let (server, server_bind_address) = citadel_sdk::test_common::server_info();
+
+        let bind_address_internal_service: SocketAddr = "127.0.0.1:55558".parse().unwrap();
+        let internal_service = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(CitadelWorkspaceService::new(bind_address_internal_service))
+            .unwrap();
+
+        spawn_services(internal_service, server);
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        let (to_service, mut from_service, uuid, cid) = register_and_connect_to_server(
+            bind_address_internal_service,
+            server_bind_address,
+            "John Doe",
+            "john.doe",
+            "secret",
+        )
+        .await?;
+
+        let source = std::env::temp_dir().join("citadel_revfs_upload.txt");
+        std::fs::write(&source, b"Hello, RE-VFS!")?;
+        let virtual_path = PathBuf::from("/home/john.doe/notes/upload.txt");
+
+        to_service.send(InternalServicePayload::SendFile {
+            uuid,
+            source,
+            cid,
+            peer_cid: None,
+            chunk_size: 1024,
+            transfer_type: TransferType::RemoteEncryptedVirtualFilesystem {
+                virtual_path: virtual_path.clone(),
+                security_level: Default::default(),
+            },
+        })?;
+
+        loop {
+            match from_service.recv().await.unwrap() {
+                InternalServiceResponse::SendFileProgress(..) => {}
+                InternalServiceResponse::SendFileSuccess(..) => break,
+                item => panic!("Didn't get the SendFileSuccess: {item:?}"),
+            }
+        }
+
+        to_service.send(InternalServicePayload::ListVirtualDirectory {
+            uuid,
+            cid,
+            virtual_directory: PathBuf::from("/home/john.doe"),
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::ListVirtualDirectorySuccess(resp) => {
+                assert_eq!(
+                    resp.directories,
+                    vec![PathBuf::from("/home/john.doe/notes")]
+                );
+                assert!(resp.files.is_empty());
+            }
+            item => panic!("Didn't get the ListVirtualDirectorySuccess: {item:?}"),
+        }
+
+        to_service.send(InternalServicePayload::StatVirtualFile {
+            uuid,
+            cid,
+            virtual_path: virtual_path.clone(),
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::StatVirtualFileSuccess(resp) => {
+                assert_eq!(resp.metadata.virtual_path, virtual_path);
+                assert_eq!(resp.metadata.size, 14);
+            }
+            item => panic!("Didn't get the StatVirtualFileSuccess: {item:?}"),
+        }
+
+        to_service.send(InternalServicePayload::DownloadFile {
+            virtual_path: virtual_path.clone(),
+            transfer_security_level: Default::default(),
+            delete_on_pull: false,
+            cid,
+            uuid,
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::DownloadFileSuccess(resp) => {
+                assert_eq!(resp.virtual_path, virtual_path);
+                assert_eq!(std::fs::read(resp.path)?, b"Hello, RE-VFS!");
+            }
+            item => panic!("Didn't get the DownloadFileSuccess: {item:?}"),
+        }
+
+        to_service.send(InternalServicePayload::DeleteVirtualFile {
+            uuid,
+            cid,
+            virtual_path: virtual_path.clone(),
+        })?;
+        assert!(matches!(
+            from_service.recv().await.unwrap(),
+            InternalServiceResponse::DeleteVirtualFileSuccess(..)
+        ));
+
+        to_service.send(InternalServicePayload::ListVirtualDirectory {
+            uuid,
+            cid,
+            virtual_directory: PathBuf::from("/home/john.doe/notes"),
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::ListVirtualDirectorySuccess(resp) => {
+                assert!(resp.directories.is_empty());
+                assert!(resp.files.is_empty());
+            }
+            item => panic!("Didn't get the ListVirtualDirectorySuccess: {item:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_session_queries() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (
+            to_service_a,
+            mut from_service_a,
+            _to_service_b,
+            _from_service_b,
+            uuid_a,
+            _uuid_b,
+            cid_a,
+            cid_b,
+        ) = register_and_connect_to_server_then_peers(
+            "127.0.0.1:55566".parse().unwrap(),
+            "127.0.0.1:55567".parse().unwrap(),
+        )
+        .await?;
+
+        to_service_a.send(InternalServicePayload::ListConnections { uuid: uuid_a })?;
+        match from_service_a.recv().await.unwrap() {
+            InternalServiceResponse::ListConnectionsSuccess(resp) => {
+                assert_eq!(resp.sessions.len(), 1);
+                let session = &resp.sessions[0];
+                assert_eq!(session.cid, cid_a);
+                assert_eq!(session.username, "peer.a");
+                assert_eq!(session.peers.len(), 1);
+                assert_eq!(session.peers[0].peer_cid, cid_b);
+            }
+            item => panic!("Didn't get the ListConnectionsSuccess: {item:?}"),
+        }
+
+        to_service_a.send(InternalServicePayload::ListPeers {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        match from_service_a.recv().await.unwrap() {
+            InternalServiceResponse::ListPeersSuccess(resp) => {
+                assert_eq!(resp.cid, cid_a);
+                assert_eq!(resp.peers.len(), 1);
+                assert_eq!(resp.peers[0].peer_username, "peer.b");
+            }
+            item => panic!("Didn't get the ListPeersSuccess: {item:?}"),
+        }
+
+        to_service_a.send(InternalServicePayload::GetSessionInfo {
+            uuid: uuid_a,
+            cid: cid_b,
+        })?;
+        assert!(matches!(
+            from_service_a.recv().await.unwrap(),
+            InternalServiceResponse::GetSessionInfoFailure(..)
+        ));
+
+        to_service_a.send(InternalServicePayload::ListRegisteredPeers {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        match from_service_a.recv().await.unwrap() {
+            InternalServiceResponse::ListRegisteredPeersSuccess(resp) => {
+                assert_eq!(resp.cid, cid_a);
+                assert_eq!(resp.peers.len(), 1);
+                assert_eq!(resp.peers[0].peer_cid, cid_b);
+                assert_eq!(resp.peers[0].username.as_deref(), Some("peer.b"));
+                assert!(resp.peers[0].online);
+            }
+            item => panic!("Didn't get the ListRegisteredPeersSuccess: {item:?}"),
+        }
+
+        to_service_a.send(InternalServicePayload::ListAllHypernodePeers {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        match from_service_a.recv().await.unwrap() {
+            InternalServiceResponse::ListAllHypernodePeersSuccess(resp) => {
+                assert!(resp.peers.iter().any(|peer| peer.peer_cid == cid_b));
+            }
+            item => panic!("Didn't get the ListAllHypernodePeersSuccess: {item:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_peer_presence() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (
+            to_service_a,
+            mut from_service_a,
+            to_service_b,
+            mut from_service_b,
+            uuid_a,
+            uuid_b,
+            cid_a,
+            cid_b,
+        ) = register_and_connect_to_server_then_peers(
+            "127.0.0.1:55576".parse().unwrap(),
+            "127.0.0.1:55577".parse().unwrap(),
+        )
+        .await?;
+
+        to_service_a.send(InternalServicePayload::SubscribePeerPresence {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        assert!(matches!(
+            from_service_a.recv().await.unwrap(),
+            InternalServiceResponse::SubscribePeerPresenceSuccess(..)
+        ));
+
+        match from_service_a.recv().await.unwrap() {
+            InternalServiceResponse::PeerPresenceChanged(PeerPresenceChanged {
+                cid,
+                peer_cid,
+                online,
+            }) => {
+                assert_eq!(cid, cid_a);
+                assert_eq!(peer_cid, cid_b);
+                assert!(online);
+            }
+            item => panic!("Didn't get the PeerPresenceChanged: {item:?}"),
+        }
+
+        // closing the P2P channel leaves both peers connected to the server
+        to_service_a.send(InternalServicePayload::PeerDisconnect {
+            uuid: uuid_a,
+            cid: cid_a,
+            peer_cid: cid_b,
+        })?;
+        assert!(matches!(
+            recv_ignoring_disconnects(&mut from_service_a).await,
+            InternalServiceResponse::PeerDisconnectSuccess(..)
+        ));
+        let next = tokio::time::timeout(
+            Duration::from_secs(7),
+            recv_ignoring_disconnects(&mut from_service_a),
+        )
+        .await;
+        assert!(next.is_err(), "Expected no presence change, got {next:?}");
+
+        to_service_b.send(InternalServicePayload::Disconnect {
+            uuid: uuid_b,
+            cid: cid_b,
+        })?;
+        assert!(matches!(
+            from_service_b.recv().await.unwrap(),
+            InternalServiceResponse::Disconnected(..)
+        ));
+
+        loop {
+            match from_service_a.recv().await.unwrap() {
+                InternalServiceResponse::PeerPresenceChanged(PeerPresenceChanged {
+                    peer_cid,
+                    online,
+                    ..
+                }) => {
+                    assert_eq!(peer_cid, cid_b);
+                    assert!(!online);
+                    break;
+                }
+                InternalServiceResponse::Disconnected(..) => {}
+                item => panic!("Didn't get the PeerPresenceChanged: {item:?}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_peer_presence_without_p2p_connection(
+    ) -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (
+            (
+                to_service_a,
+                mut from_service_a,
+                to_service_b,
+                mut from_service_b,
+                uuid_a,
+                uuid_b,
+                cid_a,
+                cid_b,
+            ),
+            _remote_a,
+        ) = register_to_server_then_peers_with_remote(
+            "127.0.0.1:55766".parse().unwrap(),
+            "127.0.0.1:55767".parse().unwrap(),
+        )
+        .await?;
+
+        to_service_a.send(InternalServicePayload::SubscribePeerPresence {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        assert!(matches!(
+            from_service_a.recv().await.unwrap(),
+            InternalServiceResponse::SubscribePeerPresenceSuccess(..)
+        ));
+
+        match from_service_a.recv().await.unwrap() {
+            InternalServiceResponse::PeerPresenceChanged(PeerPresenceChanged {
+                peer_cid,
+                online,
+                ..
+            }) => {
+                assert_eq!(peer_cid, cid_b);
+                assert!(online);
+            }
+            item => panic!("Didn't get the PeerPresenceChanged: {item:?}"),
+        }
+
+        // there is no P2P channel to close, so only the server can tell that B left
+        to_service_b.send(InternalServicePayload::Disconnect {
+            uuid: uuid_b,
+            cid: cid_b,
+        })?;
+        assert!(matches!(
+            from_service_b.recv().await.unwrap(),
+            InternalServiceResponse::Disconnected(..)
+        ));
+
+        match recv_ignoring_disconnects(&mut from_service_a).await {
+            InternalServiceResponse::PeerPresenceChanged(PeerPresenceChanged {
+                peer_cid,
+                online,
+                ..
+            }) => {
+                assert_eq!(peer_cid, cid_b);
+                assert!(!online);
+            }
+            item => panic!("Didn't get the PeerPresenceChanged: {item:?}"),
+        }
+
+        Ok(())
+    }
+
+    // skips the Disconnected notifications that tearing down a peer session may produce
+    async fn recv_ignoring_disconnects(
+        from_service: &mut UnboundedReceiver<InternalServiceResponse>,
+    ) -> InternalServiceResponse {
+        loop {
+            match from_service.recv().await.unwrap() {
+                InternalServiceResponse::Disconnected(..) => {}
+                item => return item,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_peer_disconnect_and_reconnect(
+    ) -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (
+            to_service_a,
+            mut from_service_a,
+            to_service_b,
+            mut from_service_b,
+            uuid_a,
+            uuid_b,
+            cid_a,
+            cid_b,
+        ) = register_and_connect_to_server_then_peers(
+            "127.0.0.1:55586".parse().unwrap(),
+            "127.0.0.1:55587".parse().unwrap(),
+        )
+        .await?;
+
+        to_service_a.send(InternalServicePayload::PeerDisconnect {
+            uuid: uuid_a,
+            cid: cid_a,
+            peer_cid: cid_b,
+        })?;
+        match recv_ignoring_disconnects(&mut from_service_a).await {
+            InternalServiceResponse::PeerDisconnectSuccess(PeerDisconnectSuccess {
+                cid,
+                peer_cid,
+                ticket,
+            }) => {
+                assert_eq!(cid, cid_a);
+                assert_eq!(peer_cid, cid_b);
+                assert_ne!(ticket, 0);
+            }
+            item => panic!("Didn't get the PeerDisconnectSuccess: {item:?}"),
+        }
+
+        match from_service_b.recv().await.unwrap() {
+            InternalServiceResponse::Disconnected(Disconnected { cid, peer_cid }) => {
+                assert_eq!(cid, cid_b);
+                assert_eq!(peer_cid, Some(cid_a));
+            }
+            item => panic!("Didn't get the Disconnected: {item:?}"),
+        }
+
+        // the peer is gone, so a second disconnect must fail
+        to_service_a.send(InternalServicePayload::PeerDisconnect {
+            uuid: uuid_a,
+            cid: cid_a,
+            peer_cid: cid_b,
+        })?;
+        assert!(matches!(
+            recv_ignoring_disconnects(&mut from_service_a).await,
+            InternalServiceResponse::PeerDisconnectFailure(..)
+        ));
+
+        to_service_a.send(InternalServicePayload::PeerConnect {
+            uuid: uuid_a,
+            cid: cid_a,
+            username: String::from("peer.a"),
+            peer_cid: cid_b,
+            peer_username: String::from("peer.b"),
+            udp_mode: Default::default(),
+            session_security_settings: Default::default(),
+            force: false,
+        })?;
+        to_service_b.send(InternalServicePayload::PeerConnect {
+            uuid: uuid_b,
+            cid: cid_b,
+            username: String::from("peer.b"),
+            peer_cid: cid_a,
+            peer_username: String::from("peer.a"),
+            udp_mode: Default::default(),
+            session_security_settings: Default::default(),
+            force: false,
+        })?;
+        assert!(matches!(
+            recv_ignoring_disconnects(&mut from_service_a).await,
+            InternalServiceResponse::PeerConnectSuccess(..)
+        ));
+        assert!(matches!(
+            recv_ignoring_disconnects(&mut from_service_b).await,
+            InternalServiceResponse::PeerConnectSuccess(..)
+        ));
+
+        let message = Vec::from("Hello again");
+        to_service_a.send(InternalServicePayload::Message {
+            uuid: uuid_a,
+            message: message.clone(),
+            cid: cid_a,
+            peer_cid: Some(cid_b),
+            security_level: Default::default(),
+        })?;
+        assert!(matches!(
+            recv_ignoring_disconnects(&mut from_service_a).await,
+            InternalServiceResponse::MessageSent(..)
+        ));
+        match recv_ignoring_disconnects(&mut from_service_b).await {
+            InternalServiceResponse::MessageReceived(MessageReceived {
+                message: received, ..
+            }) => assert_eq!(&*message, &*received),
+            item => panic!("Didn't get the MessageReceived: {item:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_reconnect() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let bind_address_internal_service: SocketAddr = "127.0.0.1:55676".parse().unwrap();
+
+        // the server drops the first session, the reconnected one is kept
+        let session_dropped = Arc::new(AtomicBool::new(false));
+        let (server, server_bind_address) = citadel_sdk::test_common::server_info_reactive(
+            move |conn, mut remote| {
+                let session_dropped = session_dropped.clone();
+                async move {
+                    let (_sink, mut stream) = conn.channel.split();
+                    if !session_dropped.swap(true, Ordering::SeqCst) {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        return remote.disconnect().await;
+                    }
+                    while stream.next().await.is_some() {}
+                    Ok(())
+                }
+            },
+            |_| (),
+        );
+
+        tokio::task::spawn(server);
+        let internal_service_kernel = CitadelWorkspaceService::new(bind_address_internal_service);
+        let internal_service = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(internal_service_kernel)?;
+
+        tokio::task::spawn(internal_service);
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        let policy = ReconnectPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(1),
+        };
+        let (to_service, mut from_service, uuid, _cid) =
+            register_and_connect_to_server_with_reconnect_policy(
+                bind_address_internal_service,
+                server_bind_address,
+                "John Doe",
+                "john.doe",
+                "secret",
+                Some(policy),
+            )
+            .await
+            .unwrap();
+
+        if let InternalServiceResponse::Reconnecting(resp) = from_service.recv().await.unwrap() {
+            assert_eq!(resp.attempt, 1);
+            assert_eq!(resp.max_attempts, policy.max_attempts);
+        } else {
+            panic!("Didn't get the Reconnecting");
+        }
+        let cid = if let InternalServiceResponse::Reconnected(resp) =
+            from_service.recv().await.unwrap()
+        {
+            resp.cid
+        } else {
+            panic!("Didn't get the Reconnected");
+        };
+
+        // the restored session is usable
+        to_service.send(InternalServicePayload::GetSessionInfo { uuid, cid })?;
+        if let InternalServiceResponse::GetSessionInfoSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.session.cid, cid);
+        } else {
+            panic!("Didn't get the GetSessionInfoSuccess");
+        }
+
+        to_service.send(InternalServicePayload::Disconnect { uuid, cid })?;
+        assert!(matches!(
+            from_service.recv().await.unwrap(),
+            InternalServiceResponse::Disconnected(..)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_resume_file_transfer() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (
+            to_service_a,
+            mut from_service_a,
+            to_service_b,
+            mut from_service_b,
+            uuid_a,
+            uuid_b,
+            cid_a,
+            cid_b,
+        ) = register_and_connect_to_server_then_peers(
+            "127.0.0.1:55666".parse().unwrap(),
+            "127.0.0.1:55667".parse().unwrap(),
+        )
+        .await?;
+
+        let source = std::env::temp_dir().join("citadel_resume_file_transfer.txt");
+        let contents = b"Hello again, peer!".to_vec();
+        std::fs::write(&source, &contents)?;
+        let download_directory = std::env::temp_dir().join("citadel_resume_downloads");
+
+        to_service_b.send(InternalServicePayload::SetDownloadDirectory {
+            uuid: uuid_b,
+            cid: cid_b,
+            path: download_directory.clone(),
+        })?;
+        assert!(matches!(
+            from_service_b.recv().await.unwrap(),
+            InternalServiceResponse::SetDownloadDirectorySuccess(..)
+        ));
+
+        to_service_a.send(InternalServicePayload::SendFile {
+            uuid: uuid_a,
+            source,
+            cid: cid_a,
+            peer_cid: Some(cid_b),
+            chunk_size: 1024,
+            transfer_type: TransferType::FileTransfer,
+        })?;
+        assert!(matches!(
+            from_service_b.recv().await.unwrap(),
+            InternalServiceResponse::IncomingFileTransfer(..)
+        ));
+
+        // dropping the peer before B accepts interrupts the transfer
+        to_service_a.send(InternalServicePayload::PeerDisconnect {
+            uuid: uuid_a,
+            cid: cid_a,
+            peer_cid: cid_b,
+        })?;
+        let mut disconnected = false;
+        let mut transfer_id = None;
+        while !disconnected || transfer_id.is_none() {
+            match recv_ignoring_disconnects(&mut from_service_a).await {
+                InternalServiceResponse::PeerDisconnectSuccess(..) => disconnected = true,
+                InternalServiceResponse::SendFileFailure(SendFileFailure {
+                    transfer_id: failed,
+                    ..
+                }) => {
+                    assert!(failed.is_some());
+                    transfer_id = failed;
+                }
+                InternalServiceResponse::SendFileProgress(..) => {}
+                item => panic!("Didn't get the SendFileFailure: {item:?}"),
+            }
+        }
+        let transfer_id = transfer_id.unwrap();
+
+        to_service_a.send(InternalServicePayload::ListFileTransfers {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        match recv_ignoring_disconnects(&mut from_service_a).await {
+            InternalServiceResponse::ListFileTransfersSuccess(ListFileTransfersSuccess {
+                transfers,
+                ..
+            }) => {
+                assert_eq!(transfers.len(), 1);
+                assert_eq!(transfers[0].transfer_id, transfer_id);
+                assert!(transfers[0].interrupted);
+            }
+            item => panic!("Didn't get the ListFileTransfersSuccess: {item:?}"),
+        }
+
+        to_service_a.send(InternalServicePayload::PeerConnect {
+            uuid: uuid_a,
+            cid: cid_a,
+            username: String::from("peer.a"),
+            peer_cid: cid_b,
+            peer_username: String::from("peer.b"),
+            udp_mode: Default::default(),
+            session_security_settings: Default::default(),
+            force: false,
+        })?;
+        to_service_b.send(InternalServicePayload::PeerConnect {
+            uuid: uuid_b,
+            cid: cid_b,
+            username: String::from("peer.b"),
+            peer_cid: cid_a,
+            peer_username: String::from("peer.a"),
+            udp_mode: Default::default(),
+            session_security_settings: Default::default(),
+            force: false,
+        })?;
+        assert!(matches!(
+            recv_ignoring_disconnects(&mut from_service_a).await,
+            InternalServiceResponse::PeerConnectSuccess(..)
+        ));
+        assert!(matches!(
+            recv_ignoring_disconnects(&mut from_service_b).await,
+            InternalServiceResponse::PeerConnectSuccess(..)
+        ));
+
+        to_service_a.send(InternalServicePayload::ResumeFileTransfer {
+            uuid: uuid_a,
+            cid: cid_a,
+            transfer_id,
+        })?;
+        let object_id = match recv_ignoring_disconnects(&mut from_service_b).await {
+            InternalServiceResponse::IncomingFileTransfer(IncomingFileTransfer {
+                object_id,
+                file_name,
+                resume_offset,
+                ..
+            }) => {
+                assert_eq!(file_name, "citadel_resume_file_transfer.txt");
+                assert!(resume_offset.is_some());
+                object_id
+            }
+            item => panic!("Didn't get the IncomingFileTransfer: {item:?}"),
+        };
+        to_service_b.send(InternalServicePayload::AcceptFileTransfer {
+            uuid: uuid_b,
+            cid: cid_b,
+            object_id,
+        })?;
+        assert!(matches!(
+            recv_ignoring_disconnects(&mut from_service_b).await,
+            InternalServiceResponse::AcceptFileTransferSuccess(..)
+        ));
+        match recv_ignoring_disconnects(&mut from_service_b).await {
+            InternalServiceResponse::FileReceived(FileReceived { path, .. }) => {
+                assert_eq!(std::fs::read(path)?, contents);
+            }
+            item => panic!("Didn't get the FileReceived: {item:?}"),
+        }
+
+        loop {
+            match recv_ignoring_disconnects(&mut from_service_a).await {
+                InternalServiceResponse::SendFileProgress(..) => {}
+                InternalServiceResponse::SendFileSuccess(SendFileSuccess {
+                    transfer_id: sent,
+                    ..
+                }) => {
+                    assert_eq!(sent, transfer_id);
+                    break;
+                }
+                item => panic!("Didn't get the SendFileSuccess: {item:?}"),
+            }
+        }
+
+        // a finished transfer leaves nothing behind to resume
+        to_service_a.send(InternalServicePayload::ListFileTransfers {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        match recv_ignoring_disconnects(&mut from_service_a).await {
+            InternalServiceResponse::ListFileTransfersSuccess(ListFileTransfersSuccess {
+                transfers,
+                ..
+            }) => assert!(transfers.is_empty()),
+            item => panic!("Didn't get the ListFileTransfersSuccess: {item:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_deregister() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (handle, remote_a) = register_and_connect_to_server_then_peers_with_remote(
+            "127.0.0.1:55596".parse().unwrap(),
+            "127.0.0.1:55597".parse().unwrap(),
+        )
+        .await?;
+        let (
+            to_service_a,
+            mut from_service_a,
+            _to_service_b,
+            _from_service_b,
+            uuid_a,
+            _uuid_b,
+            cid_a,
+            cid_b,
+        ) = handle;
+
+        to_service_a.send(InternalServicePayload::PeerDeregister {
+            uuid: uuid_a,
+            cid: cid_a,
+            peer_cid: cid_b,
+        })?;
+        match recv_ignoring_disconnects(&mut from_service_a).await {
+            InternalServiceResponse::PeerDeregisterSuccess(resp) => {
+                assert_eq!(resp.cid, cid_a);
+                assert_eq!(resp.peer_cid, cid_b);
+            }
+            item => panic!("Didn't get the PeerDeregisterSuccess: {item:?}"),
+        }
+
+        to_service_a.send(InternalServicePayload::ListRegisteredPeers {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        match recv_ignoring_disconnects(&mut from_service_a).await {
+            InternalServiceResponse::ListRegisteredPeersSuccess(resp) => {
+                assert!(resp.peers.is_empty());
+            }
+            item => panic!("Didn't get the ListRegisteredPeersSuccess: {item:?}"),
+        }
+
+        to_service_a.send(InternalServicePayload::LocalDBSetKV {
+            uuid: uuid_a,
+            cid: cid_a,
+            peer_cid: None,
+            key: "theme".to_string(),
+            value: b"dark".to_vec(),
+            ttl: Some(Duration::from_secs(60)),
+        })?;
+        assert!(matches!(
+            recv_ignoring_disconnects(&mut from_service_a).await,
+            InternalServiceResponse::LocalDBSetKVSuccess(..)
+        ));
+
+        to_service_a.send(InternalServicePayload::Deregister {
+            uuid: uuid_a,
+            cid: cid_a,
+        })?;
+        assert!(matches!(
+            recv_ignoring_disconnects(&mut from_service_a).await,
+            InternalServiceResponse::DeregisterSuccess(..)
+        ));
+
+        // neither the value nor its ttl record outlive the account
+        let client_to_server_remote = ClientServerRemote::new(
+            VirtualTargetType::LocalGroupServer {
+                implicated_cid: cid_a,
+            },
+            remote_a,
+        );
+        let remaining = client_to_server_remote.get_all().await.unwrap_or_default();
+        assert!(
+            remaining.is_empty(),
+            "local data left behind: {remaining:?}"
+        );
+
+        to_service_a.send(InternalServicePayload::ListConnections { uuid: uuid_a })?;
+        match recv_ignoring_disconnects(&mut from_service_a).await {
+            InternalServiceResponse::ListConnectionsSuccess(resp) => {
+                assert!(resp.sessions.is_empty());
+            }
+            item => panic!("Didn't get the ListConnectionsSuccess: {item:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_unreliable_requires_udp() -> Result<(), Box<dyn Error>>
+    {
+        citadel_logging::setup_log();
+        let (server, server_bind_address) = citadel_sdk::test_common::server_info();
+
+        let bind_address_internal_service: SocketAddr = "127.0.0.1:55626".parse().unwrap();
+        let internal_service = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(CitadelWorkspaceService::new(bind_address_internal_service))
+            .unwrap();
+
+        spawn_services(internal_service, server);
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        let (to_service, mut from_service, uuid, cid) = register_and_connect_to_server(
+            bind_address_internal_service,
+            server_bind_address,
+            "peer a",
+            "peer.a",
+            "password",
+        )
+        .await?;
+
+        to_service.send(InternalServicePayload::SendUnreliable {
+            uuid,
+            cid,
+            peer_cid: None,
+            payload: b"ping".to_vec(),
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::SendUnreliableFailure(resp) => {
+                assert_eq!(resp.cid, cid);
+                assert_eq!(resp.peer_cid, None);
+            }
+            item => panic!("Didn't get the SendUnreliableFailure: {item:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_ping_and_idle_timeout() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let bind_address_internal_service: SocketAddr = "127.0.0.1:55636".parse().unwrap();
+        let internal_service = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(
+                CitadelWorkspaceService::new(bind_address_internal_service).with_client_settings(
+                    TcpClientSettings {
+                        idle_timeout: Some(Duration::from_secs(1)),
+                        ..Default::default()
+                    },
+                ),
+            )?;
+        tokio::task::spawn(internal_service);
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        let conn = TcpStream::connect(bind_address_internal_service).await?;
+        let (mut sink, mut stream) = wrap_tcp_conn(conn).split();
+        let greeter_packet: InternalServiceResponse =
+            bincode2::deserialize(&stream.next().await.unwrap()?)?;
+        let InternalServiceResponse::ServiceConnectionAccepted(ServiceConnectionAccepted { id }) =
+            greeter_packet
+        else {
+            panic!("Didn't get the ServiceConnectionAccepted: {greeter_packet:?}");
+        };
+
+        send(
+            &mut sink,
+            InternalServicePayload::Ping { uuid: id, nonce: 7 },
+        )
+        .await?;
+        let response: InternalServiceResponse =
+            bincode2::deserialize(&stream.next().await.unwrap()?)?;
+        match response {
+            InternalServiceResponse::Pong(pong) => {
+                assert_eq!(pong.id, id);
+                assert_eq!(pong.nonce, 7);
+            }
+            item => panic!("Didn't get the Pong: {item:?}"),
+        }
+
+        send(
+            &mut sink,
+            InternalServicePayload::GetServiceMetrics { uuid: id },
+        )
+        .await?;
+        let response: InternalServiceResponse =
+            bincode2::deserialize(&stream.next().await.unwrap()?)?;
+        match response {
+            InternalServiceResponse::ServiceMetrics(metrics) => {
+                assert!(metrics.kernel_queue_depth <= metrics.kernel_queue_capacity);
+                let client = metrics
+                    .clients
+                    .iter()
+                    .find(|client| client.id == id)
+                    .unwrap();
+                assert_eq!(client.dropped, 0);
+            }
+            item => panic!("Didn't get the ServiceMetrics: {item:?}"),
+        }
+
+        // the service closes the connection once the client stays silent past the idle timeout
+        let closed = tokio::time::timeout(Duration::from_secs(5), stream.next()).await?;
+        assert!(!matches!(closed, Some(Ok(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_kernel_queue_backpressure() -> Result<(), Box<dyn Error>>
+    {
+        citadel_logging::setup_log();
+        let bind_address_internal_service: SocketAddr = "127.0.0.1:55686".parse().unwrap();
+        let (server, server_bind_address) = citadel_sdk::test_common::server_info_reactive(
+            |conn, _remote| async move {
+                let (sink, mut stream) = conn.channel.split();
+                while let Some(_message) = stream.next().await {
+                    sink.send_message("pong".into()).await?;
+                }
+                Ok(())
+            },
+            |_| (),
+        );
+
+        let kernel_queue_capacity = 4;
+        let internal_service = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(
+                CitadelWorkspaceService::new(bind_address_internal_service).with_client_settings(
+                    TcpClientSettings {
+                        kernel_queue_capacity,
+                        ..Default::default()
+                    },
+                ),
+            )?;
+        spawn_services(internal_service, server);
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        let (to_service, mut from_service, uuid, cid) = register_and_connect_to_server(
+            bind_address_internal_service,
+            server_bind_address,
+            "John Doe",
+            "john.doe",
+            "secret",
+        )
+        .await?;
+
+        // a second client watches the metrics, since the flooding one is only read as fast as
+        // the kernel drains its queue
+        let conn = TcpStream::connect(bind_address_internal_service).await?;
+        let (mut metrics_sink, mut metrics_stream) = wrap_tcp_conn(conn).split();
+        let greeter_packet: InternalServiceResponse =
+            bincode2::deserialize(&metrics_stream.next().await.unwrap()?)?;
+        let InternalServiceResponse::ServiceConnectionAccepted(ServiceConnectionAccepted {
+            id: metrics_id,
+        }) = greeter_packet
+        else {
+            panic!("Didn't get the ServiceConnectionAccepted: {greeter_packet:?}");
+        };
+
+        let message_count = 256;
+        for _ in 0..message_count {
+            to_service.send(InternalServicePayload::Message {
+                uuid,
+                message: vec![0u8; 16 * 1024],
+                cid,
+                peer_cid: None,
+                security_level: None,
+            })?;
+        }
+
+        let mut max_depth = 0;
+        for _ in 0..200 {
+            send(
+                &mut metrics_sink,
+                InternalServicePayload::GetServiceMetrics { uuid: metrics_id },
+            )
+            .await?;
+            let response: InternalServiceResponse =
+                bincode2::deserialize(&metrics_stream.next().await.unwrap()?)?;
+            let InternalServiceResponse::ServiceMetrics(metrics) = response else {
+                panic!("Didn't get the ServiceMetrics: {response:?}");
+            };
+            assert_eq!(metrics.kernel_queue_capacity, kernel_queue_capacity);
+            assert!(metrics.kernel_queue_depth <= kernel_queue_capacity);
+            max_depth = max_depth.max(metrics.kernel_queue_depth);
+            if max_depth > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(max_depth > 0, "the kernel queue never filled up");
+
+        // payloads wait for the kernel instead of being dropped
+        let mut sent = 0;
+        while sent < message_count {
+            match from_service.recv().await.unwrap() {
+                InternalServiceResponse::MessageSent(..) => sent += 1,
+                InternalServiceResponse::MessageReceived(..) => {}
+                item => panic!("Didn't get the MessageSent: {item:?}"),
+            }
+        }
+
+        send(
+            &mut metrics_sink,
+            InternalServicePayload::GetServiceMetrics { uuid: metrics_id },
+        )
+        .await?;
+        let response: InternalServiceResponse =
+            bincode2::deserialize(&metrics_stream.next().await.unwrap()?)?;
+        match response {
+            InternalServiceResponse::ServiceMetrics(metrics) => {
+                assert_eq!(metrics.kernel_queue_depth, 0);
+                assert!(metrics.clients.iter().all(|client| client.dropped == 0));
+            }
+            item => panic!("Didn't get the ServiceMetrics: {item:?}"),
+        }
+
+        Ok(())
+    }
+
+    fn pong(nonce: u64) -> InternalServiceResponse {
+        InternalServiceResponse::Pong(Pong {
+            id: Uuid::nil(),
+            nonce,
+        })
+    }
+
+    fn pong_nonce(response: Option<InternalServiceResponse>) -> Option<u64> {
+        match response {
+            Some(InternalServiceResponse::Pong(pong)) => Some(pong.nonce),
+            Some(item) => panic!("Didn't get the Pong: {item:?}"),
+            None => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_queue_drop_oldest() {
+        let (tx, mut rx) = response_queue(2, ResponseOverflowPolicy::DropOldest);
+        for nonce in 0..3 {
+            assert!(tx.send(pong(nonce)).is_ok());
+        }
+        assert_eq!(tx.len(), 2);
+        assert_eq!(tx.dropped(), 1);
+
+        // the first response made room for the last one
+        assert_eq!(pong_nonce(rx.recv().await), Some(1));
+        assert_eq!(pong_nonce(rx.recv().await), Some(2));
+        drop(tx);
+        assert_eq!(pong_nonce(rx.recv().await), None);
+    }
+
+    #[tokio::test]
+    async fn test_response_queue_disconnect() {
+        let (tx, mut rx) = response_queue(2, ResponseOverflowPolicy::Disconnect);
+        assert!(tx.send(pong(0)).is_ok());
+        assert!(tx.send(pong(1)).is_ok());
+
+        // overflowing closes the queue and discards what the client had not read yet
+        assert!(tx.send(pong(2)).is_err());
+        assert!(tx.is_empty());
+        assert_eq!(tx.dropped(), 0);
+        assert_eq!(pong_nonce(rx.recv().await), None);
+        assert!(tx.send(pong(3)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_citadel_workspace_service_rejects_disallowed_clients(
+    ) -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let bind_address_internal_service: SocketAddr = "127.0.0.1:55656".parse().unwrap();
+        let internal_service = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(
+                CitadelWorkspaceService::new(bind_address_internal_service)
+                    .with_allowed_clients(vec!["10.0.0.1".parse().unwrap()]),
+            )?;
+        tokio::task::spawn(internal_service);
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        // the connection is closed without a ServiceConnectionAccepted
+        let conn = TcpStream::connect(bind_address_internal_service).await?;
+        let (_sink, mut stream) = wrap_tcp_conn(conn).split();
+        let closed = tokio::time::timeout(Duration::from_secs(5), stream.next()).await?;
+        assert!(!matches!(closed, Some(Ok(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_c2s_kv_watch() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (server, server_bind_address) = citadel_sdk::test_common::server_info();
+
+        let bind_address_internal_service: SocketAddr = "127.0.0.1:55646".parse().unwrap();
+        let internal_service = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(CitadelWorkspaceService::new(bind_address_internal_service))
+            .unwrap();
+
+        spawn_services(internal_service, server);
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        let (to_service, mut from_service, uuid, cid) = register_and_connect_to_server(
+            bind_address_internal_service,
+            server_bind_address,
+            "peer a",
+            "peer.a",
+            "password",
+        )
+        .await?;
+
+        // a second window sharing the same session
+        let conn = TcpStream::connect(bind_address_internal_service).await?;
+        let (mut watcher_sink, mut watcher_stream) = wrap_tcp_conn(conn).split();
+        let greeter_packet: InternalServiceResponse =
+            bincode2::deserialize(&watcher_stream.next().await.unwrap()?)?;
+        let InternalServiceResponse::ServiceConnectionAccepted(ServiceConnectionAccepted {
+            id: watcher_uuid,
+        }) = greeter_packet
+        else {
+            panic!("Didn't get the ServiceConnectionAccepted: {greeter_packet:?}");
+        };
+
+        send(
+            &mut watcher_sink,
+            InternalServicePayload::LocalDBWatch {
+                uuid: watcher_uuid,
+                cid,
+                peer_cid: None,
+                prefix: "settings/".to_string(),
+            },
+        )
+        .await?;
+        let response: InternalServiceResponse =
+            bincode2::deserialize(&watcher_stream.next().await.unwrap()?)?;
+        assert!(matches!(
+            response,
+            InternalServiceResponse::LocalDBWatchSuccess(..)
+        ));
+
+        for key in ["drafts/a", "settings/theme"] {
+            to_service.send(InternalServicePayload::LocalDBSetKV {
+                uuid,
+                cid,
+                peer_cid: None,
+                key: key.to_string(),
+                value: b"dark".to_vec(),
+                ttl: None,
+            })?;
+            assert!(matches!(
+                from_service.recv().await.unwrap(),
+                InternalServiceResponse::LocalDBSetKVSuccess(..)
+            ));
+        }
+
+        to_service.send(InternalServicePayload::LocalDBDeleteKV {
+            uuid,
+            cid,
+            peer_cid: None,
+            key: "settings/theme".to_string(),
+        })?;
+        assert!(matches!(
+            from_service.recv().await.unwrap(),
+            InternalServiceResponse::LocalDBDeleteKVSuccess(..)
+        ));
+
+        // drafts/a is outside the watched prefix, so only the settings changes arrive
+        for expected_value in [Some(b"dark".to_vec()), None] {
+            let response: InternalServiceResponse =
+                bincode2::deserialize(&watcher_stream.next().await.unwrap()?)?;
+            match response {
+                InternalServiceResponse::LocalDBChanged(changed) => {
+                    assert_eq!(changed.cid, cid);
+                    assert_eq!(changed.peer_cid, None);
+                    assert_eq!(changed.key, "settings/theme");
+                    assert_eq!(changed.new_value, expected_value);
+                }
+                item => panic!("Didn't get the LocalDBChanged: {item:?}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_c2s_kv() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (server, server_bind_address) = citadel_sdk::test_common::server_info();
+
+        let bind_address_internal_service_a: SocketAddr = "127.0.0.1:55537".parse().unwrap();
+        let internal_service = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(CitadelWorkspaceService::new(
+                bind_address_internal_service_a,
+            ))
+            .unwrap();
+
+        spawn_services(internal_service, server);
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        let (to_service_a, mut from_service_a, uuid, cid) = register_and_connect_to_server(
+            bind_address_internal_service_a,
+            server_bind_address,
+            "peer a",
+            "peer.a",
+            "password",
+        )
+        .await?;
+
+        test_kv_for_service(&to_service_a, &mut from_service_a, uuid, cid, None).await
+    }
+
+    #[tokio::test]
+    async fn test_p2p_kv() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        // internal service for peer A
+        let bind_address_internal_service_a: SocketAddr = "127.0.0.1:55536".parse().unwrap();
+        // internal service for peer B
+        let bind_address_internal_service_b: SocketAddr = "127.0.0.1:55537".parse().unwrap();
+
+        let (
+            to_service_a,
+            mut from_service_a,
+            to_service_b,
+            mut from_service_b,
+            uuid_a,
+            uuid_b,
+            cid_a,
+            cid_b,
+        ) = register_and_connect_to_server_then_peers(
+            bind_address_internal_service_a,
+            bind_address_internal_service_b,
         )
         .await?;
 
@@ -705,6 +2313,25 @@ mod tests {
         Ok(())
     }
 
+    #[derive(Serialize, Deserialize, Default)]
+    struct ThemeSettingsV2 {
+        mode: ThemeMode,
+        accent_colors: Vec<String>,
+    }
+
+    impl Settings for ThemeSettingsV2 {
+        const NAMESPACE: &'static str = ThemeSettings::NAMESPACE;
+        const VERSION: u32 = 2;
+
+        fn migrate(version: u32, stored: &[u8]) -> Option<Self> {
+            let v1: ThemeSettings = (version == 1).then(|| decode_value(stored).ok())??;
+            Some(Self {
+                mode: v1.mode,
+                accent_colors: v1.accent_color.into_iter().collect(),
+            })
+        }
+    }
+
     async fn test_kv_for_service(
         to_service: &UnboundedSender<InternalServicePayload>,
         from_service: &mut UnboundedReceiver<InternalServiceResponse>,
@@ -719,92 +2346,943 @@ mod tests {
             peer_cid,
         })?;
 
-        if let InternalServiceResponse::LocalDBGetAllKVSuccess(resp) =
+        if let InternalServiceResponse::LocalDBGetAllKVSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.cid, cid);
+            assert_eq!(resp.map.len(), 0);
+            assert_eq!(peer_cid, resp.peer_cid);
+        } else {
+            panic!("Didn't get the LocalDBGetAllKVSuccess");
+        }
+
+        // test set_kv
+        let value = Vec::from("Hello, World!");
+        to_service.send(InternalServicePayload::LocalDBSetKV {
+            uuid,
+            cid,
+            peer_cid,
+            key: "tmp".to_string(),
+            value: value.clone(),
+            ttl: None,
+        })?;
+
+        if let InternalServiceResponse::LocalDBSetKVSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.cid, cid);
+            assert_eq!(peer_cid, resp.peer_cid);
+            assert_eq!(resp.key, "tmp");
+        } else {
+            panic!("Didn't get the LocalDBSetKVSuccess");
+        }
+
+        // test get_kv
+        to_service.send(InternalServicePayload::LocalDBGetKV {
+            uuid,
+            cid,
+            peer_cid,
+            key: "tmp".to_string(),
+        })?;
+
+        if let InternalServiceResponse::LocalDBGetKVSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.cid, cid);
+            assert_eq!(peer_cid, resp.peer_cid);
+            assert_eq!(resp.key, "tmp");
+            assert_eq!(resp.value.as_ref(), Some(&value));
+        } else {
+            panic!("Didn't get the LocalDBGetKVSuccess");
+        }
+
+        // test get_all_kv
+        to_service.send(InternalServicePayload::LocalDBGetAllKV {
+            uuid,
+            cid,
+            peer_cid,
+        })?;
+
+        if let InternalServiceResponse::LocalDBGetAllKVSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.cid, cid);
+            assert_eq!(resp.map.len(), 1);
+            assert_eq!(peer_cid, resp.peer_cid);
+            assert_eq!(
+                resp.map,
+                HashMap::from([("tmp".to_string(), value.clone())])
+            );
+        } else {
+            panic!("Didn't get the LocalDBGetAllKVSuccess");
+        }
+
+        // test delete_kv
+        to_service.send(InternalServicePayload::LocalDBDeleteKV {
+            uuid,
+            cid,
+            peer_cid,
+            key: "tmp".to_string(),
+        })?;
+
+        if let InternalServiceResponse::LocalDBDeleteKVSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.cid, cid);
+            assert_eq!(peer_cid, resp.peer_cid);
+            assert_eq!(resp.key, "tmp");
+        } else {
+            panic!("Didn't get the LocalDBDeleteKVSuccess");
+        }
+
+        // test scan_kv
+        for key in ["settings/a", "settings/b", "settings/c", "drafts/a"] {
+            to_service.send(InternalServicePayload::LocalDBSetKV {
+                uuid,
+                cid,
+                peer_cid,
+                key: key.to_string(),
+                value: value.clone(),
+                ttl: None,
+            })?;
+            assert!(matches!(
+                from_service.recv().await.unwrap(),
+                InternalServiceResponse::LocalDBSetKVSuccess(..)
+            ));
+        }
+
+        let mut start_after = None;
+        let mut pages = vec![];
+        loop {
+            to_service.send(InternalServicePayload::LocalDBScanKV {
+                uuid,
+                cid,
+                peer_cid,
+                prefix: "settings/".to_string(),
+                start_after: start_after.clone(),
+                limit: 2,
+            })?;
+
+            if let InternalServiceResponse::LocalDBScanKVSuccess(resp) =
+                from_service.recv().await.unwrap()
+            {
+                assert_eq!(resp.cid, cid);
+                assert_eq!(peer_cid, resp.peer_cid);
+                pages.push(
+                    resp.entries
+                        .into_iter()
+                        .map(|(key, _)| key)
+                        .collect::<Vec<_>>(),
+                );
+                start_after = resp.next_start_after;
+            } else {
+                panic!("Didn't get the LocalDBScanKVSuccess");
+            }
+
+            if start_after.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![
+                vec!["settings/a".to_string(), "settings/b".to_string()],
+                vec!["settings/c".to_string()],
+            ]
+        );
+
+        // test batch_kv
+        to_service.send(InternalServicePayload::LocalDBBatch {
+            uuid,
+            cid,
+            peer_cid,
+            operations: vec![
+                LocalDBBatchOperation::Set {
+                    key: "drafts/b".to_string(),
+                    value: value.clone(),
+                },
+                LocalDBBatchOperation::Delete {
+                    key: "drafts/a".to_string(),
+                },
+            ],
+        })?;
+
+        if let InternalServiceResponse::LocalDBBatchSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.cid, cid);
+            assert_eq!(peer_cid, resp.peer_cid);
+            assert_eq!(resp.applied, 2);
+        } else {
+            panic!("Didn't get the LocalDBBatchSuccess");
+        }
+
+        // test compare_and_swap_kv
+        let new_value = Vec::from("Goodbye, World!");
+        for (expected_swap, expected_current) in [(true, None), (false, Some(new_value.clone()))] {
+            to_service.send(InternalServicePayload::LocalDBCompareAndSwap {
+                uuid,
+                cid,
+                peer_cid,
+                key: "drafts/b".to_string(),
+                expected: Some(value.clone()),
+                new: new_value.clone(),
+            })?;
+
+            if let InternalServiceResponse::LocalDBCompareAndSwapSuccess(resp) =
+                from_service.recv().await.unwrap()
+            {
+                assert_eq!(resp.cid, cid);
+                assert_eq!(peer_cid, resp.peer_cid);
+                assert_eq!(resp.swapped, expected_swap);
+                assert_eq!(resp.current, expected_current);
+            } else {
+                panic!("Didn't get the LocalDBCompareAndSwapSuccess");
+            }
+        }
+
+        // failures carry a code and echo the key
+        to_service.send(InternalServicePayload::LocalDBGetKV {
+            uuid,
+            cid: cid.wrapping_add(1),
+            peer_cid,
+            key: "tmp".to_string(),
+        })?;
+        if let InternalServiceResponse::LocalDBGetKVFailure(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.key, "tmp");
+            assert_eq!(resp.code, LocalDBErrorCode::ConnectionNotFound);
+        } else {
+            panic!("Didn't get the LocalDBGetKVFailure");
+        }
+
+        // the service's own records cannot be written by clients
+        to_service.send(InternalServicePayload::LocalDBSetKV {
+            uuid,
+            cid,
+            peer_cid,
+            key: "__citadel/ttl/tmp".to_string(),
+            value: Vec::from("forever"),
+            ttl: None,
+        })?;
+        if let InternalServiceResponse::LocalDBSetKVFailure(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.code, LocalDBErrorCode::ReservedKey);
+        } else {
+            panic!("Didn't get the LocalDBSetKVFailure");
+        }
+
+        to_service.send(InternalServicePayload::LocalDBClearAllKV {
+            uuid,
+            cid,
+            peer_cid,
+        })?;
+        assert!(matches!(
+            from_service.recv().await.unwrap(),
+            InternalServiceResponse::LocalDBClearAllKVSuccess(..)
+        ));
+
+        Ok(())
+    }
+
+    // a service with a single session to a fresh server, for tests of the C2S store
+    async fn connect_to_fresh_server(
+        bind_address_internal_service: SocketAddr,
+    ) -> Result<
+        (
+            UnboundedSender<InternalServicePayload>,
+            UnboundedReceiver<InternalServiceResponse>,
+            Uuid,
+            u64,
+        ),
+        Box<dyn Error>,
+    > {
+        let (server, server_bind_address) = citadel_sdk::test_common::server_info();
+        let internal_service = NodeBuilder::default()
+            .with_node_type(NodeType::Peer)
+            .with_backend(BackendType::InMemory)
+            .build(CitadelWorkspaceService::new(bind_address_internal_service))?;
+
+        spawn_services(internal_service, server);
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        register_and_connect_to_server(
+            bind_address_internal_service,
+            server_bind_address,
+            "peer a",
+            "peer.a",
+            "password",
+        )
+        .await
+    }
+
+    async fn set_kv(
+        to_service: &UnboundedSender<InternalServicePayload>,
+        from_service: &mut UnboundedReceiver<InternalServiceResponse>,
+        uuid: Uuid,
+        cid: u64,
+        key: &str,
+        value: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<(), Box<dyn Error>> {
+        to_service.send(InternalServicePayload::LocalDBSetKV {
+            uuid,
+            cid,
+            peer_cid: None,
+            key: key.to_string(),
+            value: value.to_vec(),
+            ttl,
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::LocalDBSetKVSuccess(..) => Ok(()),
+            item => panic!("Didn't get the LocalDBSetKVSuccess: {item:?}"),
+        }
+    }
+
+    async fn get_kv(
+        to_service: &UnboundedSender<InternalServicePayload>,
+        from_service: &mut UnboundedReceiver<InternalServiceResponse>,
+        uuid: Uuid,
+        cid: u64,
+        key: &str,
+    ) -> Result<LocalDBGetKVSuccess, Box<dyn Error>> {
+        to_service.send(InternalServicePayload::LocalDBGetKV {
+            uuid,
+            cid,
+            peer_cid: None,
+            key: key.to_string(),
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::LocalDBGetKVSuccess(resp) => Ok(resp),
+            item => panic!("Didn't get the LocalDBGetKVSuccess: {item:?}"),
+        }
+    }
+
+    async fn scan_kv(
+        to_service: &UnboundedSender<InternalServicePayload>,
+        from_service: &mut UnboundedReceiver<InternalServiceResponse>,
+        uuid: Uuid,
+        cid: u64,
+        prefix: &str,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        to_service.send(InternalServicePayload::LocalDBScanKV {
+            uuid,
+            cid,
+            peer_cid: None,
+            prefix: prefix.to_string(),
+            start_after: None,
+            limit: 100,
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::LocalDBScanKVSuccess(resp) => Ok(resp
+                .entries
+                .into_iter()
+                .map(|(key, _)| key)
+                .chain(resp.chunked.into_iter().map(|(key, _)| key))
+                .collect()),
+            item => panic!("Didn't get the LocalDBScanKVSuccess: {item:?}"),
+        }
+    }
+
+    async fn put_chunks(
+        to_service: &UnboundedSender<InternalServicePayload>,
+        from_service: &mut UnboundedReceiver<InternalServiceResponse>,
+        uuid: Uuid,
+        cid: u64,
+        key: &str,
+        chunks: &[Vec<u8>],
+    ) -> Result<(), Box<dyn Error>> {
+        let upload_id = Uuid::new_v4();
+        for (index, data) in chunks.iter().enumerate() {
+            to_service.send(InternalServicePayload::LocalDBPutChunk {
+                uuid,
+                cid,
+                peer_cid: None,
+                key: key.to_string(),
+                upload_id,
+                index,
+                total: chunks.len(),
+                data: data.clone(),
+            })?;
+            match from_service.recv().await.unwrap() {
+                InternalServiceResponse::LocalDBPutChunkSuccess(..) => {}
+                item => panic!("Didn't get the LocalDBPutChunkSuccess: {item:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kv_ttl() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (to_service, mut from_service, uuid, cid) =
+            connect_to_fresh_server("127.0.0.1:55696".parse().unwrap()).await?;
+        let from_service = &mut from_service;
+        let to_service = &to_service;
+        let peer_cid = None;
+        let value = Vec::from("Hello, World!");
+
+        // test set_kv with a ttl
+        to_service.send(InternalServicePayload::LocalDBSetKV {
+            uuid,
+            cid,
+            peer_cid,
+            key: "tokens/session".to_string(),
+            value: value.clone(),
+            ttl: Some(Duration::from_secs(1)),
+        })?;
+        assert!(matches!(
+            from_service.recv().await.unwrap(),
+            InternalServiceResponse::LocalDBSetKVSuccess(..)
+        ));
+
+        to_service.send(InternalServicePayload::LocalDBGetKV {
+            uuid,
+            cid,
+            peer_cid,
+            key: "tokens/session".to_string(),
+        })?;
+        if let InternalServiceResponse::LocalDBGetKVSuccess(resp) =
             from_service.recv().await.unwrap()
         {
-            assert_eq!(resp.cid, cid);
-            assert_eq!(resp.map.len(), 0);
-            assert_eq!(peer_cid, resp.peer_cid);
+            assert!(resp.expires_at.is_some());
         } else {
-            panic!("Didn't get the LocalDBGetAllKVSuccess");
+            panic!("Didn't get the LocalDBGetKVSuccess");
         }
 
-        // test set_kv
-        let value = Vec::from("Hello, World!");
-        to_service.send(InternalServicePayload::LocalDBSetKV {
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        to_service.send(InternalServicePayload::LocalDBGetKV {
             uuid,
             cid,
             peer_cid,
-            key: "tmp".to_string(),
-            value: value.clone(),
+            key: "tokens/session".to_string(),
         })?;
-
-        if let InternalServiceResponse::LocalDBSetKVSuccess(resp) =
+        if let InternalServiceResponse::LocalDBGetKVSuccess(resp) =
             from_service.recv().await.unwrap()
         {
-            assert_eq!(resp.cid, cid);
-            assert_eq!(peer_cid, resp.peer_cid);
-            assert_eq!(resp.key, "tmp");
+            assert_eq!(resp.value, None);
+            assert_eq!(resp.expires_at, None);
         } else {
-            panic!("Didn't get the LocalDBSetKVSuccess");
+            panic!("Didn't get the LocalDBGetKVSuccess");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kv_chunks() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (to_service, mut from_service, uuid, cid) =
+            connect_to_fresh_server("127.0.0.1:55706".parse().unwrap()).await?;
+        let from_service = &mut from_service;
+        let to_service = &to_service;
+        let peer_cid = None;
+
+        // test chunked put and get
+        let chunks = [
+            Vec::from("large "),
+            Vec::from("chunked "),
+            Vec::from("value"),
+        ];
+        // chunks may arrive in any order, the value appears once all of them are stored
+        let upload_id = Uuid::new_v4();
+        for (index, data) in chunks.iter().enumerate().rev() {
+            to_service.send(InternalServicePayload::LocalDBPutChunk {
+                uuid,
+                cid,
+                peer_cid,
+                key: "blobs/a".to_string(),
+                upload_id,
+                index,
+                total: chunks.len(),
+                data: data.clone(),
+            })?;
+            assert!(matches!(
+                from_service.recv().await.unwrap(),
+                InternalServiceResponse::LocalDBPutChunkSuccess(..)
+            ));
         }
 
-        // test get_kv
         to_service.send(InternalServicePayload::LocalDBGetKV {
             uuid,
             cid,
             peer_cid,
-            key: "tmp".to_string(),
+            key: "blobs/a".to_string(),
         })?;
-
         if let InternalServiceResponse::LocalDBGetKVSuccess(resp) =
             from_service.recv().await.unwrap()
         {
-            assert_eq!(resp.cid, cid);
-            assert_eq!(peer_cid, resp.peer_cid);
-            assert_eq!(resp.key, "tmp");
-            assert_eq!(&resp.value, &value);
+            assert_eq!(resp.value, None);
+            assert_eq!(resp.chunks, Some(chunks.len()));
         } else {
             panic!("Didn't get the LocalDBGetKVSuccess");
         }
 
-        // test get_all_kv
-        to_service.send(InternalServicePayload::LocalDBGetAllKV {
+        for (index, data) in chunks.iter().enumerate() {
+            to_service.send(InternalServicePayload::LocalDBGetChunk {
+                uuid,
+                cid,
+                peer_cid,
+                key: "blobs/a".to_string(),
+                index,
+            })?;
+            if let InternalServiceResponse::LocalDBGetChunkSuccess(resp) =
+                from_service.recv().await.unwrap()
+            {
+                assert_eq!(resp.total, chunks.len());
+                assert_eq!(resp.data.as_ref(), Some(data));
+            } else {
+                panic!("Didn't get the LocalDBGetChunkSuccess");
+            }
+        }
+
+        to_service.send(InternalServicePayload::LocalDBGetChunk {
             uuid,
             cid,
             peer_cid,
+            key: "blobs/a".to_string(),
+            index: chunks.len(),
         })?;
+        if let InternalServiceResponse::LocalDBGetChunkFailure(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.code, LocalDBErrorCode::InvalidChunk);
+        } else {
+            panic!("Didn't get the LocalDBGetChunkFailure");
+        }
 
-        if let InternalServiceResponse::LocalDBGetAllKVSuccess(resp) =
+        to_service.send(InternalServicePayload::LocalDBDeleteKV {
+            uuid,
+            cid,
+            peer_cid,
+            key: "blobs/a".to_string(),
+        })?;
+        assert!(matches!(
+            from_service.recv().await.unwrap(),
+            InternalServiceResponse::LocalDBDeleteKVSuccess(..)
+        ));
+
+        to_service.send(InternalServicePayload::LocalDBGetChunk {
+            uuid,
+            cid,
+            peer_cid,
+            key: "blobs/a".to_string(),
+            index: 0,
+        })?;
+        if let InternalServiceResponse::LocalDBGetChunkSuccess(resp) =
             from_service.recv().await.unwrap()
         {
-            assert_eq!(resp.cid, cid);
-            assert_eq!(resp.map.len(), 1);
-            assert_eq!(peer_cid, resp.peer_cid);
-            assert_eq!(
-                resp.map,
-                HashMap::from([("tmp".to_string(), value.clone())])
-            );
+            assert_eq!(resp.total, 0);
+            assert_eq!(resp.data, None);
         } else {
-            panic!("Didn't get the LocalDBGetAllKVSuccess");
+            panic!("Didn't get the LocalDBGetChunkSuccess");
         }
 
-        // test delete_kv
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kv_plain_value_replaces_chunked_value() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (to_service, mut from_service, uuid, cid) =
+            connect_to_fresh_server("127.0.0.1:55716".parse().unwrap()).await?;
+        let chunks = [Vec::from("large "), Vec::from("value")];
+
+        // a plain set
+        put_chunks(
+            &to_service,
+            &mut from_service,
+            uuid,
+            cid,
+            "blobs/a",
+            &chunks,
+        )
+        .await?;
+        set_kv(
+            &to_service,
+            &mut from_service,
+            uuid,
+            cid,
+            "blobs/a",
+            b"plain",
+            None,
+        )
+        .await?;
+        let resp = get_kv(&to_service, &mut from_service, uuid, cid, "blobs/a").await?;
+        assert_eq!(resp.value, Some(Vec::from("plain")));
+        assert_eq!(resp.chunks, None);
+
+        // a batch
+        put_chunks(
+            &to_service,
+            &mut from_service,
+            uuid,
+            cid,
+            "blobs/b",
+            &chunks,
+        )
+        .await?;
+        to_service.send(InternalServicePayload::LocalDBBatch {
+            uuid,
+            cid,
+            peer_cid: None,
+            operations: vec![LocalDBBatchOperation::Set {
+                key: "blobs/b".to_string(),
+                value: Vec::from("plain"),
+            }],
+        })?;
+        assert!(matches!(
+            from_service.recv().await.unwrap(),
+            InternalServiceResponse::LocalDBBatchSuccess(..)
+        ));
+        let resp = get_kv(&to_service, &mut from_service, uuid, cid, "blobs/b").await?;
+        assert_eq!(resp.value, Some(Vec::from("plain")));
+        assert_eq!(resp.chunks, None);
+
+        // a compare-and-swap, which compares against the whole chunked value
+        put_chunks(
+            &to_service,
+            &mut from_service,
+            uuid,
+            cid,
+            "blobs/c",
+            &chunks,
+        )
+        .await?;
+        to_service.send(InternalServicePayload::LocalDBCompareAndSwap {
+            uuid,
+            cid,
+            peer_cid: None,
+            key: "blobs/c".to_string(),
+            expected: Some(Vec::from("large value")),
+            new: Vec::from("plain"),
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::LocalDBCompareAndSwapSuccess(resp) => assert!(resp.swapped),
+            item => panic!("Didn't get the LocalDBCompareAndSwapSuccess: {item:?}"),
+        }
+        let resp = get_kv(&to_service, &mut from_service, uuid, cid, "blobs/c").await?;
+        assert_eq!(resp.value, Some(Vec::from("plain")));
+        assert_eq!(resp.chunks, None);
+
+        // the old chunks are gone, so the value reads back as a single chunk
+        to_service.send(InternalServicePayload::LocalDBGetChunk {
+            uuid,
+            cid,
+            peer_cid: None,
+            key: "blobs/a".to_string(),
+            index: 0,
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::LocalDBGetChunkSuccess(resp) => {
+                assert_eq!(resp.total, 1);
+                assert_eq!(resp.data, Some(Vec::from("plain")));
+            }
+            item => panic!("Didn't get the LocalDBGetChunkSuccess: {item:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kv_batch_rollback() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (to_service, mut from_service, uuid, cid) =
+            connect_to_fresh_server("127.0.0.1:55726".parse().unwrap()).await?;
+        set_kv(
+            &to_service,
+            &mut from_service,
+            uuid,
+            cid,
+            "drafts/a",
+            b"kept",
+            None,
+        )
+        .await?;
+
+        // the reserved key fails the batch, so neither write before it is applied
+        to_service.send(InternalServicePayload::LocalDBBatch {
+            uuid,
+            cid,
+            peer_cid: None,
+            operations: vec![
+                LocalDBBatchOperation::Set {
+                    key: "drafts/a".to_string(),
+                    value: Vec::from("replaced"),
+                },
+                LocalDBBatchOperation::Set {
+                    key: "drafts/b".to_string(),
+                    value: Vec::from("added"),
+                },
+                LocalDBBatchOperation::Delete {
+                    key: "__citadel/ttl/drafts/a".to_string(),
+                },
+            ],
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::LocalDBBatchFailure(resp) => {
+                assert_eq!(resp.code, LocalDBErrorCode::ReservedKey);
+                assert!(resp.rolled_back);
+            }
+            item => panic!("Didn't get the LocalDBBatchFailure: {item:?}"),
+        }
+
+        let resp = get_kv(&to_service, &mut from_service, uuid, cid, "drafts/a").await?;
+        assert_eq!(resp.value, Some(Vec::from("kept")));
+        let resp = get_kv(&to_service, &mut from_service, uuid, cid, "drafts/b").await?;
+        assert_eq!(resp.value, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kv_scan_skips_deleted_and_expired_keys() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (to_service, mut from_service, uuid, cid) =
+            connect_to_fresh_server("127.0.0.1:55736".parse().unwrap()).await?;
+        for key in ["notes/a", "notes/b", "notes/c"] {
+            set_kv(
+                &to_service,
+                &mut from_service,
+                uuid,
+                cid,
+                key,
+                b"note",
+                None,
+            )
+            .await?;
+        }
+        let ttl = Some(Duration::from_secs(1));
+        set_kv(
+            &to_service,
+            &mut from_service,
+            uuid,
+            cid,
+            "notes/d",
+            b"note",
+            ttl,
+        )
+        .await?;
+
+        // the first scan builds the index that later writes keep up to date
+        let keys = scan_kv(&to_service, &mut from_service, uuid, cid, "notes/").await?;
+        assert_eq!(keys, ["notes/a", "notes/b", "notes/c", "notes/d"]);
+
         to_service.send(InternalServicePayload::LocalDBDeleteKV {
+            uuid,
+            cid,
+            peer_cid: None,
+            key: "notes/b".to_string(),
+        })?;
+        assert!(matches!(
+            from_service.recv().await.unwrap(),
+            InternalServiceResponse::LocalDBDeleteKVSuccess(..)
+        ));
+        set_kv(
+            &to_service,
+            &mut from_service,
+            uuid,
+            cid,
+            "notes/aa",
+            b"note",
+            None,
+        )
+        .await?;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        let keys = scan_kv(&to_service, &mut from_service, uuid, cid, "notes/").await?;
+        assert_eq!(keys, ["notes/a", "notes/aa", "notes/c"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kv_export_import() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (to_service, mut from_service, uuid, cid) =
+            connect_to_fresh_server("127.0.0.1:55746".parse().unwrap()).await?;
+        let from_service = &mut from_service;
+        let to_service = &to_service;
+        let peer_cid = None;
+        let value = Vec::from("Hello, World!");
+        for key in ["settings/a", "drafts/b"] {
+            set_kv(to_service, from_service, uuid, cid, key, &value, None).await?;
+        }
+
+        // test export and import
+        to_service.send(InternalServicePayload::LocalDBGetAllKV {
             uuid,
             cid,
             peer_cid,
-            key: "tmp".to_string(),
         })?;
+        let exported_map = match from_service.recv().await.unwrap() {
+            InternalServiceResponse::LocalDBGetAllKVSuccess(resp) => resp.map,
+            _ => panic!("Didn't get the LocalDBGetAllKVSuccess"),
+        };
+
+        to_service.send(InternalServicePayload::LocalDBExport {
+            uuid,
+            cid,
+            peer_cid,
+            password: SecBuffer::from("backup"),
+            path: None,
+        })?;
+        let mut archive = vec![];
+        loop {
+            match from_service.recv().await.unwrap() {
+                InternalServiceResponse::LocalDBExportChunk(chunk) => {
+                    archive.extend_from_slice(&chunk.data)
+                }
+                InternalServiceResponse::LocalDBExportSuccess(resp) => {
+                    assert_eq!(resp.entries, exported_map.len());
+                    assert_eq!(resp.size, archive.len());
+                    break;
+                }
+                _ => panic!("Didn't get the LocalDBExportSuccess"),
+            }
+        }
 
-        if let InternalServiceResponse::LocalDBDeleteKVSuccess(resp) =
+        to_service.send(InternalServicePayload::LocalDBImport {
+            uuid,
+            cid,
+            peer_cid,
+            password: SecBuffer::from("wrong"),
+            source: LocalDBImportSource::Archive(archive.clone()),
+            mode: LocalDBImportMode::Overwrite,
+        })?;
+        match from_service.recv().await.unwrap() {
+            InternalServiceResponse::LocalDBImportFailure(resp) => {
+                assert_eq!(resp.code, LocalDBErrorCode::InvalidArchive);
+                assert!(resp.rolled_back);
+            }
+            item => panic!("Didn't get the LocalDBImportFailure: {item:?}"),
+        }
+
+        to_service.send(InternalServicePayload::LocalDBSetKV {
+            uuid,
+            cid,
+            peer_cid,
+            key: "not-exported".to_string(),
+            value: value.clone(),
+            ttl: None,
+        })?;
+        assert!(matches!(
+            from_service.recv().await.unwrap(),
+            InternalServiceResponse::LocalDBSetKVSuccess(..)
+        ));
+
+        // the archive is sent in two pieces, the import only runs once the last one arrived
+        let upload_id = Uuid::new_v4();
+        let (head, tail) = archive.split_at(archive.len() / 2);
+        for (index, data) in [head, tail].into_iter().enumerate() {
+            to_service.send(InternalServicePayload::LocalDBImport {
+                uuid,
+                cid,
+                peer_cid,
+                password: SecBuffer::from("backup"),
+                source: LocalDBImportSource::Chunk {
+                    upload_id,
+                    index,
+                    total: 2,
+                    data: data.to_vec(),
+                },
+                mode: LocalDBImportMode::Overwrite,
+            })?;
+            if index == 0 {
+                assert!(matches!(
+                    from_service.recv().await.unwrap(),
+                    InternalServiceResponse::LocalDBImportChunkReceived(..)
+                ));
+            }
+        }
+        if let InternalServiceResponse::LocalDBImportSuccess(resp) =
             from_service.recv().await.unwrap()
         {
-            assert_eq!(resp.cid, cid);
-            assert_eq!(peer_cid, resp.peer_cid);
-            assert_eq!(resp.key, "tmp");
+            assert_eq!(resp.imported, exported_map.len());
         } else {
-            panic!("Didn't get the LocalDBDeleteKVSuccess");
+            panic!("Didn't get the LocalDBImportSuccess");
+        }
+
+        to_service.send(InternalServicePayload::LocalDBGetAllKV {
+            uuid,
+            cid,
+            peer_cid,
+        })?;
+        if let InternalServiceResponse::LocalDBGetAllKVSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert_eq!(resp.map, exported_map);
+        } else {
+            panic!("Didn't get the LocalDBGetAllKVSuccess");
+        }
+
+        Ok(())
+    }
+
+    #[derive(Serialize, Deserialize, Default)]
+    struct ThemeSettingsV3 {
+        mode: ThemeMode,
+    }
+
+    // keeps the default migrate, which cannot convert any older layout
+    impl Settings for ThemeSettingsV3 {
+        const NAMESPACE: &'static str = ThemeSettings::NAMESPACE;
+        const VERSION: u32 = 3;
+    }
+
+    #[tokio::test]
+    async fn test_kv_settings() -> Result<(), Box<dyn Error>> {
+        citadel_logging::setup_log();
+        let (to_service, mut from_service, uuid, cid) =
+            connect_to_fresh_server("127.0.0.1:55756".parse().unwrap()).await?;
+        let from_service = &mut from_service;
+        let to_service = &to_service;
+        let peer_cid = None;
+
+        // test typed settings, including a migration to a newer schema
+        to_service.send(get_settings::<NotificationSettings>(uuid, cid, peer_cid))?;
+        if let InternalServiceResponse::LocalDBGetKVSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            let settings = settings_from_response::<NotificationSettings>(&resp)?;
+            assert_eq!(settings, NotificationSettings::default());
+        } else {
+            panic!("Didn't get the LocalDBGetKVSuccess");
+        }
+
+        let theme = ThemeSettings {
+            mode: ThemeMode::Dark,
+            accent_color: Some("#3b82f6".to_string()),
+        };
+        to_service.send(set_settings(uuid, cid, peer_cid, &theme)?)?;
+        assert!(matches!(
+            from_service.recv().await.unwrap(),
+            InternalServiceResponse::LocalDBSetKVSuccess(..)
+        ));
+
+        to_service.send(get_settings::<ThemeSettingsV2>(uuid, cid, peer_cid))?;
+        if let InternalServiceResponse::LocalDBGetKVSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            let settings = settings_from_response::<ThemeSettingsV2>(&resp)?;
+            assert_eq!(settings.mode, ThemeMode::Dark);
+            assert_eq!(settings.accent_colors, vec!["#3b82f6".to_string()]);
+        } else {
+            panic!("Didn't get the LocalDBGetKVSuccess");
+        }
+
+        to_service.send(get_settings::<ThemeSettingsV3>(uuid, cid, peer_cid))?;
+        if let InternalServiceResponse::LocalDBGetKVSuccess(resp) =
+            from_service.recv().await.unwrap()
+        {
+            assert!(matches!(
+                settings_from_response::<ThemeSettingsV3>(&resp),
+                Err(SettingsError::MigrationFailed(1))
+            ));
+        } else {
+            panic!("Didn't get the LocalDBGetKVSuccess");
         }
 
         Ok(())
@@ -3,11 +3,12 @@ pub use citadel_sdk::prelude::{
     ConnectMode, SecBuffer, SecurityLevel, SessionSecuritySettings, TransferType, UdpMode,
     UserIdentifier,
 };
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -20,6 +21,42 @@ pub struct ConnectionFailure {
     pub message: String,
 }
 
+// the delay doubles after every failed attempt, up to max_backoff
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reconnecting {
+    pub cid: u64,
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub backoff: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reconnected {
+    pub cid: u64,
+}
+
+// follows Reconnected when the dropped session had peer connections; the peers in failed were
+// not restored and have to be connected again with PeerConnect
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeersRestored {
+    pub cid: u64,
+    pub restored: Vec<u64>,
+    pub failed: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReconnectFailure {
+    pub cid: u64,
+    pub message: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RegisterSuccess {
     pub id: Uuid,
@@ -30,11 +67,43 @@ pub struct RegisterFailure {
     pub message: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeregisterSuccess {
+    pub cid: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeregisterFailure {
+    pub cid: u64,
+    pub message: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServiceConnectionAccepted {
     pub id: Uuid,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientQueueMetrics {
+    pub id: Uuid,
+    pub depth: usize,
+    pub capacity: usize,
+    pub dropped: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceMetrics {
+    pub kernel_queue_depth: usize,
+    pub kernel_queue_capacity: usize,
+    pub clients: Vec<ClientQueueMetrics>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pong {
+    pub id: Uuid,
+    pub nonce: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MessageSent {
     pub cid: u64,
@@ -54,6 +123,26 @@ pub struct MessageReceived {
     pub peer_cid: u64,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnreliableMessageSent {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SendUnreliableFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnreliableMessageReceived {
+    pub message: BytesMut,
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Disconnected {
     pub cid: u64,
@@ -69,10 +158,242 @@ pub struct DisconnectFailure {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SendFileSuccess {
     pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub transfer_id: Uuid,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SendFileFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    // set when the transfer was interrupted and can be continued with ResumeFileTransfer
+    pub transfer_id: Option<Uuid>,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SendFileProgress {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub transfer_id: Uuid,
+    pub chunks_sent: usize,
+    pub total_chunks: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileTransferInfo {
+    pub transfer_id: Uuid,
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub source: PathBuf,
+    pub chunk_size: usize,
+    pub transfer_type: TransferType,
+    // progress is counted in units of chunk_size
+    pub chunks_confirmed: usize,
+    pub total_chunks: usize,
+    // false while the transfer is running in this service
+    pub interrupted: bool,
+}
+
+// The SDK sends a file under its own name, so a resumed transfer to a peer carries its
+// byte offset in the name instead: "<file_name>.citadel-resume-<offset>"
+pub const RESUMED_FILE_MARKER: &str = ".citadel-resume-";
+
+pub fn resumed_file_name(file_name: &str, offset: u64) -> String {
+    format!("{file_name}{RESUMED_FILE_MARKER}{offset}")
+}
+
+// Splits the name of a resumed transfer into the original file name and its byte offset
+pub fn parse_resumed_file_name(file_name: &str) -> Option<(&str, u64)> {
+    let (file_name, offset) = file_name.rsplit_once(RESUMED_FILE_MARKER)?;
+    Some((file_name, offset.parse().ok()?))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IncomingFileTransfer {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub object_id: u64,
+    pub file_name: String,
+    // set when the peer resumes an interrupted transfer; file_size only counts the bytes
+    // from this offset on
+    pub resume_offset: Option<u64>,
+    pub file_size: usize,
+    pub transfer_type: TransferType,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AcceptFileTransferSuccess {
+    pub cid: u64,
+    pub object_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AcceptFileTransferFailure {
+    pub cid: u64,
+    pub object_id: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RejectFileTransferSuccess {
+    pub cid: u64,
+    pub object_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RejectFileTransferFailure {
+    pub cid: u64,
+    pub object_id: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileReceived {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub object_id: u64,
+    pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileReceiveFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub object_id: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetDownloadDirectorySuccess {
+    pub cid: u64,
+    pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetDownloadDirectoryFailure {
+    pub cid: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VirtualFileMetadata {
+    pub virtual_path: PathBuf,
+    pub size: u64,
+    pub security_level: SecurityLevel,
+    pub uploaded_at: SystemTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListVirtualDirectorySuccess {
+    pub cid: u64,
+    pub virtual_directory: PathBuf,
+    pub directories: Vec<PathBuf>,
+    pub files: Vec<VirtualFileMetadata>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListVirtualDirectoryFailure {
+    pub cid: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatVirtualFileSuccess {
+    pub cid: u64,
+    pub metadata: VirtualFileMetadata,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatVirtualFileFailure {
+    pub cid: u64,
+    pub virtual_path: PathBuf,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteVirtualFileSuccess {
+    pub cid: u64,
+    pub virtual_path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteVirtualFileFailure {
+    pub cid: u64,
+    pub virtual_path: PathBuf,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DownloadFileSuccess {
+    pub cid: u64,
+    pub virtual_path: PathBuf,
+    // in the download directory if one is set
+    pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DownloadFileFailure {
+    pub cid: u64,
+    pub virtual_path: PathBuf,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerSessionInformation {
+    pub cid: u64,
+    pub peer_cid: u64,
+    pub peer_username: String,
+    pub session_security_settings: SessionSecuritySettings,
+    pub udp_mode: UdpMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionInformation {
+    pub cid: u64,
+    pub username: String,
+    pub server_address: Option<SocketAddr>,
+    pub session_security_settings: SessionSecuritySettings,
+    pub udp_mode: UdpMode,
+    pub peers: Vec<PeerSessionInformation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListConnectionsSuccess {
+    pub sessions: Vec<SessionInformation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListPeersSuccess {
+    pub cid: u64,
+    pub peers: Vec<PeerSessionInformation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListPeersFailure {
+    pub cid: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetSessionInfoSuccess {
+    pub session: SessionInformation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetSessionInfoFailure {
+    pub cid: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListFileTransfersSuccess {
+    pub cid: u64,
+    pub transfers: Vec<FileTransferInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListFileTransfersFailure {
     pub cid: u64,
     pub message: String,
 }
@@ -80,6 +401,7 @@ pub struct SendFileFailure {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PeerConnectSuccess {
     pub cid: u64,
+    pub already_connected: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -91,12 +413,14 @@ pub struct PeerConnectFailure {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PeerDisconnectSuccess {
     pub cid: u64,
+    pub peer_cid: u64,
     pub ticket: u128,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PeerDisconnectFailure {
     pub cid: u64,
+    pub peer_cid: u64,
     pub message: String,
 }
 
@@ -105,7 +429,6 @@ pub struct PeerRegisterSuccess {
     pub cid: u64,
     pub peer_cid: u64,
     pub username: String,
-    // TODO: add access to MutualPeer
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -114,19 +437,117 @@ pub struct PeerRegisterFailure {
     pub message: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerDeregisterSuccess {
+    pub cid: u64,
+    pub peer_cid: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerDeregisterFailure {
+    pub cid: u64,
+    pub peer_cid: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HypernodePeerInformation {
+    pub peer_cid: u64,
+    pub username: Option<String>,
+    pub full_name: Option<String>,
+    pub online: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListRegisteredPeersSuccess {
+    pub cid: u64,
+    pub peers: Vec<HypernodePeerInformation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListRegisteredPeersFailure {
+    pub cid: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListAllHypernodePeersSuccess {
+    pub cid: u64,
+    pub peers: Vec<HypernodePeerInformation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListAllHypernodePeersFailure {
+    pub cid: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerPresenceChanged {
+    pub cid: u64,
+    pub peer_cid: u64,
+    pub online: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubscribePeerPresenceSuccess {
+    pub cid: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubscribePeerPresenceFailure {
+    pub cid: u64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnsubscribePeerPresenceSuccess {
+    pub cid: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnsubscribePeerPresenceFailure {
+    pub cid: u64,
+    pub message: String,
+}
+
+// lets clients react to a LocalDB failure without matching on its message
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalDBErrorCode {
+    ConnectionNotFound,
+    PeerConnectionNotFound,
+    // the storage backend rejected the operation
+    Backend,
+    // the chunk index is out of range for the value, or an import piece arrived out of order
+    InvalidChunk,
+    // keys under the __citadel/ prefix hold the service's own records
+    ReservedKey,
+    // the archive could not be opened, e.g. because of a wrong password or corrupted data
+    InvalidArchive,
+    // reading or writing the archive file failed
+    Io,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LocalDBGetKVSuccess {
     pub cid: u64,
     pub peer_cid: Option<u64>,
     pub key: String,
-    pub value: Vec<u8>,
+    // None if the key does not exist or holds a chunked value
+    pub value: Option<Vec<u8>>,
+    // set if the key was stored with a ttl
+    pub expires_at: Option<SystemTime>,
+    // the number of chunks of a value stored with LocalDBPutChunk, read with LocalDBGetChunk
+    pub chunks: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LocalDBGetKVFailure {
     pub cid: u64,
     pub peer_cid: Option<u64>,
+    pub key: String,
     pub message: String,
+    pub code: LocalDBErrorCode,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -140,7 +561,9 @@ pub struct LocalDBSetKVSuccess {
 pub struct LocalDBSetKVFailure {
     pub cid: u64,
     pub peer_cid: Option<u64>,
+    pub key: String,
     pub message: String,
+    pub code: LocalDBErrorCode,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -154,7 +577,9 @@ pub struct LocalDBDeleteKVSuccess {
 pub struct LocalDBDeleteKVFailure {
     pub cid: u64,
     pub peer_cid: Option<u64>,
+    pub key: String,
     pub message: String,
+    pub code: LocalDBErrorCode,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -162,6 +587,8 @@ pub struct LocalDBGetAllKVSuccess {
     pub cid: u64,
     pub peer_cid: Option<u64>,
     pub map: HashMap<String, Vec<u8>>,
+    // keys holding a chunked value, with the number of chunks
+    pub chunked: HashMap<String, usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -169,6 +596,7 @@ pub struct LocalDBGetAllKVFailure {
     pub cid: u64,
     pub peer_cid: Option<u64>,
     pub message: String,
+    pub code: LocalDBErrorCode,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -182,28 +610,294 @@ pub struct LocalDBClearAllKVFailure {
     pub cid: u64,
     pub peer_cid: Option<u64>,
     pub message: String,
+    pub code: LocalDBErrorCode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBScanKVSuccess {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub prefix: String,
+    // sorted by key
+    pub entries: Vec<(String, Vec<u8>)>,
+    // keys in this page holding a chunked value, with the number of chunks, sorted by key
+    pub chunked: Vec<(String, usize)>,
+    // pass as start_after to fetch the next page; None once the scan is complete
+    pub next_start_after: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBScanKVFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub message: String,
+    pub code: LocalDBErrorCode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LocalDBBatchOperation {
+    Set { key: String, value: Vec<u8> },
+    Delete { key: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBBatchSuccess {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub applied: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBBatchFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub message: String,
+    pub code: LocalDBErrorCode,
+    // false if restoring the previous values also failed, leaving the batch partially applied
+    pub rolled_back: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBCompareAndSwapSuccess {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub key: String,
+    pub swapped: bool,
+    // the value found when the swap did not happen
+    pub current: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBCompareAndSwapFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub message: String,
+    pub code: LocalDBErrorCode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBWatchSuccess {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub prefix: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBWatchFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub message: String,
+    pub code: LocalDBErrorCode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBUnwatchSuccess {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub prefix: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBUnwatchFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub message: String,
+    pub code: LocalDBErrorCode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBChanged {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub key: String,
+    // None if the key was deleted or cleared
+    pub new_value: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBPutChunkSuccess {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub key: String,
+    pub index: usize,
+    pub total: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBPutChunkFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub key: String,
+    pub index: usize,
+    pub message: String,
+    pub code: LocalDBErrorCode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBGetChunkSuccess {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub key: String,
+    pub index: usize,
+    // 0 if the key does not exist
+    pub total: usize,
+    // None if the key does not exist
+    pub data: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBGetChunkFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub key: String,
+    pub index: usize,
+    pub message: String,
+    pub code: LocalDBErrorCode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalDBImportMode {
+    // archived keys replace existing ones, keys missing from the archive are kept
+    Merge,
+    // the store is replaced by the archive
+    Overwrite,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LocalDBImportSource {
+    Path(PathBuf),
+    // the reassembled data of the LocalDBExportChunks
+    Archive(Vec<u8>),
+    // one piece of an archive too large for a single frame, sent in order. The import runs once
+    // the last piece arrives, earlier ones are answered with LocalDBImportChunkReceived
+    Chunk {
+        upload_id: Uuid,
+        index: usize,
+        total: usize,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBExportChunk {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub index: usize,
+    pub total: usize,
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBExportSuccess {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub entries: usize,
+    pub size: usize,
+    // None if the archive was sent as LocalDBExportChunks
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBExportFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub message: String,
+    pub code: LocalDBErrorCode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBImportChunkReceived {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub upload_id: Uuid,
+    pub index: usize,
+    pub total: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBImportSuccess {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub imported: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalDBImportFailure {
+    pub cid: u64,
+    pub peer_cid: Option<u64>,
+    pub message: String,
+    pub code: LocalDBErrorCode,
+    // false if restoring the previous values also failed, leaving the import partially applied
+    pub rolled_back: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum InternalServiceResponse {
     ConnectSuccess(ConnectSuccess),
     ConnectionFailure(ConnectionFailure),
+    Reconnecting(Reconnecting),
+    Reconnected(Reconnected),
+    PeersRestored(PeersRestored),
+    ReconnectFailure(ReconnectFailure),
     RegisterSuccess(RegisterSuccess),
     RegisterFailure(RegisterFailure),
+    DeregisterSuccess(DeregisterSuccess),
+    DeregisterFailure(DeregisterFailure),
     ServiceConnectionAccepted(ServiceConnectionAccepted),
+    Pong(Pong),
+    ServiceMetrics(ServiceMetrics),
     MessageSent(MessageSent),
     MessageSendError(MessageSendError),
     MessageReceived(MessageReceived),
+    UnreliableMessageSent(UnreliableMessageSent),
+    SendUnreliableFailure(SendUnreliableFailure),
+    UnreliableMessageReceived(UnreliableMessageReceived),
     Disconnected(Disconnected),
     DisconnectFailure(DisconnectFailure),
     SendFileSuccess(SendFileSuccess),
     SendFileFailure(SendFileFailure),
+    SendFileProgress(SendFileProgress),
+    IncomingFileTransfer(IncomingFileTransfer),
+    AcceptFileTransferSuccess(AcceptFileTransferSuccess),
+    AcceptFileTransferFailure(AcceptFileTransferFailure),
+    RejectFileTransferSuccess(RejectFileTransferSuccess),
+    RejectFileTransferFailure(RejectFileTransferFailure),
+    FileReceived(FileReceived),
+    FileReceiveFailure(FileReceiveFailure),
+    SetDownloadDirectorySuccess(SetDownloadDirectorySuccess),
+    SetDownloadDirectoryFailure(SetDownloadDirectoryFailure),
+    ListVirtualDirectorySuccess(ListVirtualDirectorySuccess),
+    ListVirtualDirectoryFailure(ListVirtualDirectoryFailure),
+    StatVirtualFileSuccess(StatVirtualFileSuccess),
+    StatVirtualFileFailure(StatVirtualFileFailure),
+    DeleteVirtualFileSuccess(DeleteVirtualFileSuccess),
+    DeleteVirtualFileFailure(DeleteVirtualFileFailure),
+    DownloadFileSuccess(DownloadFileSuccess),
+    DownloadFileFailure(DownloadFileFailure),
+    ListConnectionsSuccess(ListConnectionsSuccess),
+    ListPeersSuccess(ListPeersSuccess),
+    ListPeersFailure(ListPeersFailure),
+    GetSessionInfoSuccess(GetSessionInfoSuccess),
+    GetSessionInfoFailure(GetSessionInfoFailure),
+    ListFileTransfersSuccess(ListFileTransfersSuccess),
+    ListFileTransfersFailure(ListFileTransfersFailure),
     PeerConnectSuccess(PeerConnectSuccess),
     PeerConnectFailure(PeerConnectFailure),
     PeerDisconnectSuccess(PeerDisconnectSuccess),
     PeerDisconnectFailure(PeerDisconnectFailure),
     PeerRegisterSuccess(PeerRegisterSuccess),
     PeerRegisterFailure(PeerRegisterFailure),
+    PeerDeregisterSuccess(PeerDeregisterSuccess),
+    PeerDeregisterFailure(PeerDeregisterFailure),
+    ListRegisteredPeersSuccess(ListRegisteredPeersSuccess),
+    ListRegisteredPeersFailure(ListRegisteredPeersFailure),
+    ListAllHypernodePeersSuccess(ListAllHypernodePeersSuccess),
+    ListAllHypernodePeersFailure(ListAllHypernodePeersFailure),
+    PeerPresenceChanged(PeerPresenceChanged),
+    SubscribePeerPresenceSuccess(SubscribePeerPresenceSuccess),
+    SubscribePeerPresenceFailure(SubscribePeerPresenceFailure),
+    UnsubscribePeerPresenceSuccess(UnsubscribePeerPresenceSuccess),
+    UnsubscribePeerPresenceFailure(UnsubscribePeerPresenceFailure),
     LocalDBGetKVSuccess(LocalDBGetKVSuccess),
     LocalDBGetKVFailure(LocalDBGetKVFailure),
     LocalDBSetKVSuccess(LocalDBSetKVSuccess),
@@ -214,6 +908,27 @@ pub enum InternalServiceResponse {
     LocalDBGetAllKVFailure(LocalDBGetAllKVFailure),
     LocalDBClearAllKVSuccess(LocalDBClearAllKVSuccess),
     LocalDBClearAllKVFailure(LocalDBClearAllKVFailure),
+    LocalDBScanKVSuccess(LocalDBScanKVSuccess),
+    LocalDBScanKVFailure(LocalDBScanKVFailure),
+    LocalDBBatchSuccess(LocalDBBatchSuccess),
+    LocalDBBatchFailure(LocalDBBatchFailure),
+    LocalDBCompareAndSwapSuccess(LocalDBCompareAndSwapSuccess),
+    LocalDBCompareAndSwapFailure(LocalDBCompareAndSwapFailure),
+    LocalDBWatchSuccess(LocalDBWatchSuccess),
+    LocalDBWatchFailure(LocalDBWatchFailure),
+    LocalDBUnwatchSuccess(LocalDBUnwatchSuccess),
+    LocalDBUnwatchFailure(LocalDBUnwatchFailure),
+    LocalDBChanged(LocalDBChanged),
+    LocalDBPutChunkSuccess(LocalDBPutChunkSuccess),
+    LocalDBPutChunkFailure(LocalDBPutChunkFailure),
+    LocalDBGetChunkSuccess(LocalDBGetChunkSuccess),
+    LocalDBGetChunkFailure(LocalDBGetChunkFailure),
+    LocalDBExportChunk(LocalDBExportChunk),
+    LocalDBExportSuccess(LocalDBExportSuccess),
+    LocalDBExportFailure(LocalDBExportFailure),
+    LocalDBImportChunkReceived(LocalDBImportChunkReceived),
+    LocalDBImportSuccess(LocalDBImportSuccess),
+    LocalDBImportFailure(LocalDBImportFailure),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -225,7 +940,10 @@ pub enum InternalServicePayload {
         connect_mode: ConnectMode,
         udp_mode: UdpMode,
         keep_alive_timeout: Option<Duration>,
-        session_security_settings: SessionSecuritySettings,
+        // None takes the defaults the service was configured with
+        session_security_settings: Option<SessionSecuritySettings>,
+        // if set, the password is kept in memory to re-authenticate when the session drops
+        reconnect_policy: Option<ReconnectPolicy>,
     },
     Register {
         uuid: Uuid,
@@ -234,7 +952,13 @@ pub enum InternalServicePayload {
         username: String,
         proposed_password: SecBuffer,
         connect_after_register: bool,
-        default_security_settings: SessionSecuritySettings,
+        // None takes the defaults the service was configured with
+        default_security_settings: Option<SessionSecuritySettings>,
+    },
+    // removes our account from the server along with its local data
+    Deregister {
+        uuid: Uuid,
+        cid: u64,
     },
     Message {
         uuid: Uuid,
@@ -242,7 +966,16 @@ pub enum InternalServicePayload {
         cid: u64,
         // if None, send to server, otherwise, send to p2p
         peer_cid: Option<u64>,
-        security_level: SecurityLevel,
+        // if None, the session's default security level is used
+        security_level: Option<SecurityLevel>,
+    },
+    // only available on sessions created with UdpMode::Enabled
+    SendUnreliable {
+        uuid: Uuid,
+        cid: u64,
+        // if None, send to server, otherwise, send to p2p
+        peer_cid: Option<u64>,
+        payload: Vec<u8>,
     },
     Disconnect {
         uuid: Uuid,
@@ -252,9 +985,36 @@ pub enum InternalServicePayload {
         uuid: Uuid,
         source: PathBuf,
         cid: u64,
+        // if None, send to server, otherwise, send to p2p
+        peer_cid: Option<u64>,
         chunk_size: usize,
         transfer_type: TransferType,
     },
+    // continues a transfer to a peer from the last chunk it acknowledged. The SDK cannot append
+    // to a file on the server, so resuming an upload to it fails instead of starting over
+    ResumeFileTransfer {
+        uuid: Uuid,
+        cid: u64,
+        transfer_id: Uuid,
+    },
+    AcceptFileTransfer {
+        uuid: Uuid,
+        cid: u64,
+        object_id: u64,
+    },
+    RejectFileTransfer {
+        uuid: Uuid,
+        cid: u64,
+        object_id: u64,
+    },
+    // received files are moved here once complete. If unset, they stay where the SDK wrote them
+    SetDownloadDirectory {
+        uuid: Uuid,
+        cid: u64,
+        path: PathBuf,
+    },
+    // pulls a file from the RE-VFS. The SDK cannot continue an interrupted pull, so a failed
+    // download has to be requested again
     DownloadFile {
         virtual_path: PathBuf,
         transfer_security_level: SecurityLevel,
@@ -262,6 +1022,24 @@ pub enum InternalServicePayload {
         cid: u64,
         uuid: Uuid,
     },
+    // The server has no listing API, so this reads the service's local log of uploads instead.
+    // Only files uploaded through this service with TransferType::RemoteEncryptedVirtualFilesystem
+    // are visible here, and the log is not updated by changes made to the RE-VFS elsewhere
+    ListVirtualDirectory {
+        uuid: Uuid,
+        cid: u64,
+        virtual_directory: PathBuf,
+    },
+    StatVirtualFile {
+        uuid: Uuid,
+        cid: u64,
+        virtual_path: PathBuf,
+    },
+    DeleteVirtualFile {
+        uuid: Uuid,
+        cid: u64,
+        virtual_path: PathBuf,
+    },
     StartGroup {
         initial_users_to_invite: Option<Vec<UserIdentifier>>,
         cid: u64,
@@ -275,6 +1053,8 @@ pub enum InternalServicePayload {
         peer_username: String,
         udp_mode: UdpMode,
         session_security_settings: SessionSecuritySettings,
+        // tear down an existing connection to this peer instead of reusing it
+        force: bool,
     },
     PeerDisconnect {
         uuid: Uuid,
@@ -286,6 +1066,57 @@ pub enum InternalServicePayload {
         cid: u64,
         peer_id: UserIdentifier,
         connect_after_register: bool,
+        // only used when connect_after_register is true
+        udp_mode: UdpMode,
+        session_security_settings: SessionSecuritySettings,
+    },
+    ListConnections {
+        uuid: Uuid,
+    },
+    // answered with a Pong carrying the same nonce; any payload resets the idle timeout
+    Ping {
+        uuid: Uuid,
+        nonce: u64,
+    },
+    GetServiceMetrics {
+        uuid: Uuid,
+    },
+    // PeerPresenceChanged is sent for every registered peer once subscribed, then whenever the
+    // server reports it going online or offline, whether or not a P2P channel is open to it
+    SubscribePeerPresence {
+        uuid: Uuid,
+        cid: u64,
+    },
+    UnsubscribePeerPresence {
+        uuid: Uuid,
+        cid: u64,
+    },
+    // peers we are mutually registered with
+    ListRegisteredPeers {
+        uuid: Uuid,
+        cid: u64,
+    },
+    // every peer registered to the server, whether or not we are registered with them
+    ListAllHypernodePeers {
+        uuid: Uuid,
+        cid: u64,
+    },
+    ListPeers {
+        uuid: Uuid,
+        cid: u64,
+    },
+    GetSessionInfo {
+        uuid: Uuid,
+        cid: u64,
+    },
+    ListFileTransfers {
+        uuid: Uuid,
+        cid: u64,
+    },
+    PeerDeregister {
+        uuid: Uuid,
+        cid: u64,
+        peer_cid: u64,
     },
     LocalDBGetKV {
         uuid: Uuid,
@@ -299,6 +1130,8 @@ pub enum InternalServicePayload {
         peer_cid: Option<u64>,
         key: String,
         value: Vec<u8>,
+        // the key is deleted once this elapses; None keeps it until deleted
+        ttl: Option<Duration>,
     },
     LocalDBDeleteKV {
         uuid: Uuid,
@@ -316,4 +1149,137 @@ pub enum InternalServicePayload {
         cid: u64,
         peer_cid: Option<u64>,
     },
+    // returns up to limit keys starting with prefix, in key order, after start_after if given
+    LocalDBScanKV {
+        uuid: Uuid,
+        cid: u64,
+        peer_cid: Option<u64>,
+        prefix: String,
+        start_after: Option<String>,
+        limit: usize,
+    },
+    // either every operation is applied or none are
+    LocalDBBatch {
+        uuid: Uuid,
+        cid: u64,
+        peer_cid: Option<u64>,
+        operations: Vec<LocalDBBatchOperation>,
+    },
+    // sets key to new only if its current value equals expected (None meaning absent)
+    LocalDBCompareAndSwap {
+        uuid: Uuid,
+        cid: u64,
+        peer_cid: Option<u64>,
+        key: String,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+    },
+    // LocalDBChanged is sent for every set, delete or clear of a key starting with prefix
+    LocalDBWatch {
+        uuid: Uuid,
+        cid: u64,
+        peer_cid: Option<u64>,
+        prefix: String,
+    },
+    LocalDBUnwatch {
+        uuid: Uuid,
+        cid: u64,
+        peer_cid: Option<u64>,
+        prefix: String,
+    },
+    // stores a value too large for a single frame, one chunk at a time and in any order.
+    // Chunks sharing an upload_id make up one value, which replaces the current one once every
+    // chunk is stored. Uploads that stop receiving chunks are dropped after an hour
+    LocalDBPutChunk {
+        uuid: Uuid,
+        cid: u64,
+        peer_cid: Option<u64>,
+        key: String,
+        upload_id: Uuid,
+        index: usize,
+        total: usize,
+        data: Vec<u8>,
+    },
+    // a value set with LocalDBSetKV is returned as a single chunk
+    LocalDBGetChunk {
+        uuid: Uuid,
+        cid: u64,
+        peer_cid: Option<u64>,
+        key: String,
+        index: usize,
+    },
+    // the archive is encrypted with password, then written to path or sent back in chunks
+    LocalDBExport {
+        uuid: Uuid,
+        cid: u64,
+        peer_cid: Option<u64>,
+        password: SecBuffer,
+        path: Option<PathBuf>,
+    },
+    LocalDBImport {
+        uuid: Uuid,
+        cid: u64,
+        peer_cid: Option<u64>,
+        password: SecBuffer,
+        source: LocalDBImportSource,
+        mode: LocalDBImportMode,
+    },
+}
+
+// a group of client preferences stored as one LocalDB value, see citadel_workspace_lib::settings
+pub trait Settings: Serialize + DeserializeOwned + Default {
+    // the key under the settings prefix; shared by every client reading these settings
+    const NAMESPACE: &'static str;
+    // bump whenever the stored layout changes, and convert the old layouts in migrate
+    const VERSION: u32;
+
+    // converts the value written by an older version; None fails the decode with MigrationFailed
+    fn migrate(_version: u32, _stored: &[u8]) -> Option<Self> {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThemeMode {
+    #[default]
+    System,
+    Light,
+    Dark,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThemeSettings {
+    pub mode: ThemeMode,
+    // e.g. "#3b82f6"; None uses the client's own accent
+    pub accent_color: Option<String>,
+}
+
+impl Settings for ThemeSettings {
+    const NAMESPACE: &'static str = "theme";
+    const VERSION: u32 = 1;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NotificationSettings {
+    pub enabled: bool,
+    pub sound: bool,
+    // show that a message arrived without its contents
+    pub hide_message_contents: bool,
+    pub muted_peers: Vec<u64>,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sound: true,
+            hide_message_contents: false,
+            muted_peers: vec![],
+        }
+    }
+}
+
+impl Settings for NotificationSettings {
+    const NAMESPACE: &'static str = "notifications";
+    const VERSION: u32 = 1;
 }
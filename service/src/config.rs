@@ -0,0 +1,439 @@
+use citadel_sdk::prelude::{BackendType, SecrecyMode, SecurityLevel};
+use citadel_workspace_service::kernel::response_queue::ResponseOverflowPolicy;
+use citadel_workspace_service::kernel::{ServiceDefaults, TcpClientSettings};
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+
+// the TOML file; every value is optional and can be overridden from the command line
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub log_level: Option<String>,
+    pub download_directory: Option<PathBuf>,
+    pub listeners: ListenersConfig,
+    pub backend: BackendConfig,
+    pub auth: AuthConfig,
+    pub security: SecurityConfig,
+    pub limits: LimitsConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenersConfig {
+    // where local applications connect to the service
+    pub service: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    pub kind: BackendKind,
+    // required by the filesystem backend
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    #[default]
+    InMemory,
+    Filesystem,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // addresses allowed to connect to the service listener; unset accepts any
+    pub allowed_clients: Option<Vec<IpAddr>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    // used by sessions whose client does not send its own security settings
+    pub default_security_level: Option<SecurityLevel>,
+    pub default_secrecy_mode: Option<SecrecyMode>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub response_queue_capacity: Option<usize>,
+    pub kernel_queue_capacity: Option<usize>,
+    pub idle_timeout_secs: Option<u64>,
+    // disconnect clients whose response queue overflows instead of dropping their oldest responses
+    pub disconnect_on_overflow: Option<bool>,
+}
+
+// values passed on the command line, which take precedence over the config file
+#[derive(Debug, Default)]
+pub struct Overrides {
+    pub bind: Option<SocketAddr>,
+    pub log_level: Option<String>,
+    pub download_directory: Option<PathBuf>,
+    pub backend: Option<BackendKind>,
+    pub backend_path: Option<PathBuf>,
+    pub allowed_clients: Option<Vec<IpAddr>>,
+    pub default_security_level: Option<SecurityLevel>,
+    pub default_secrecy_mode: Option<SecrecyMode>,
+    pub response_queue_capacity: Option<usize>,
+    pub kernel_queue_capacity: Option<usize>,
+    pub idle_timeout_secs: Option<u64>,
+    pub disconnect_on_overflow: Option<bool>,
+}
+
+// the validated configuration, with command line overrides applied
+pub struct ResolvedConfig {
+    pub bind: SocketAddr,
+    pub backend: BackendType,
+    pub log_level: Option<String>,
+    pub client_settings: TcpClientSettings,
+    pub defaults: ServiceDefaults,
+    pub allowed_clients: Option<Vec<IpAddr>>,
+}
+
+impl ServiceConfig {
+    // reads the config file if there is one, then applies the command line on top of it
+    pub fn from_sources(
+        path: Option<&Path>,
+        overrides: Overrides,
+    ) -> Result<ResolvedConfig, String> {
+        let mut config = match path {
+            Some(path) => ServiceConfig::load(path)?,
+            None => ServiceConfig::default(),
+        };
+        config.apply(overrides);
+        config.resolve()
+    }
+
+    pub fn apply(&mut self, overrides: Overrides) {
+        if let Some(bind) = overrides.bind {
+            self.listeners.service = Some(bind);
+        }
+        if let Some(log_level) = overrides.log_level {
+            self.log_level = Some(log_level);
+        }
+        if let Some(download_directory) = overrides.download_directory {
+            self.download_directory = Some(download_directory);
+        }
+        if let Some(kind) = overrides.backend {
+            self.backend.kind = kind;
+        }
+        if let Some(path) = overrides.backend_path {
+            self.backend.path = Some(path);
+        }
+        if let Some(allowed_clients) = overrides.allowed_clients {
+            self.auth.allowed_clients = Some(allowed_clients);
+        }
+        if let Some(level) = overrides.default_security_level {
+            self.security.default_security_level = Some(level);
+        }
+        if let Some(mode) = overrides.default_secrecy_mode {
+            self.security.default_secrecy_mode = Some(mode);
+        }
+        if let Some(capacity) = overrides.response_queue_capacity {
+            self.limits.response_queue_capacity = Some(capacity);
+        }
+        if let Some(capacity) = overrides.kernel_queue_capacity {
+            self.limits.kernel_queue_capacity = Some(capacity);
+        }
+        if let Some(secs) = overrides.idle_timeout_secs {
+            self.limits.idle_timeout_secs = Some(secs);
+        }
+        if let Some(disconnect) = overrides.disconnect_on_overflow {
+            self.limits.disconnect_on_overflow = Some(disconnect);
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+        toml::from_str(&contents).map_err(|err| format!("Invalid config {}: {err}", path.display()))
+    }
+
+    pub fn resolve(self) -> Result<ResolvedConfig, String> {
+        let bind = self
+            .listeners
+            .service
+            .ok_or("No service listener: set listeners.service or pass --bind")?;
+
+        let backend = match (self.backend.kind, self.backend.path) {
+            (BackendKind::InMemory, None) => BackendType::InMemory,
+            (BackendKind::InMemory, Some(_)) => {
+                return Err("backend.path is only used by the filesystem backend".to_string())
+            }
+            (BackendKind::Filesystem, Some(path)) => {
+                BackendType::filesystem(path.to_string_lossy())
+            }
+            (BackendKind::Filesystem, None) => {
+                return Err("The filesystem backend requires backend.path".to_string())
+            }
+        };
+
+        if let Some(level) = &self.log_level {
+            if !LOG_LEVELS.contains(&level.to_lowercase().as_str()) {
+                return Err(format!(
+                    "Invalid log_level {level}, expected one of {}",
+                    LOG_LEVELS.join(", ")
+                ));
+            }
+        }
+
+        if let Some(directory) = &self.download_directory {
+            if directory.is_file() {
+                return Err(format!(
+                    "download_directory {} is a file",
+                    directory.display()
+                ));
+            }
+        }
+
+        let mut client_settings = TcpClientSettings::default();
+        if let Some(capacity) = self.limits.response_queue_capacity {
+            client_settings.response_queue_capacity =
+                non_zero("limits.response_queue_capacity", capacity)?;
+        }
+        if let Some(capacity) = self.limits.kernel_queue_capacity {
+            client_settings.kernel_queue_capacity =
+                non_zero("limits.kernel_queue_capacity", capacity)?;
+        }
+        if let Some(secs) = self.limits.idle_timeout_secs {
+            if secs == 0 {
+                return Err("limits.idle_timeout_secs must be greater than 0".to_string());
+            }
+            client_settings.idle_timeout = Some(Duration::from_secs(secs));
+        }
+        if self.limits.disconnect_on_overflow == Some(true) {
+            client_settings.overflow_policy = ResponseOverflowPolicy::Disconnect;
+        }
+
+        Ok(ResolvedConfig {
+            bind,
+            backend,
+            log_level: self.log_level,
+            client_settings,
+            defaults: ServiceDefaults {
+                download_directory: self.download_directory,
+                security_level: self.security.default_security_level,
+                secrecy_mode: self.security.default_secrecy_mode,
+            },
+            allowed_clients: self.auth.allowed_clients,
+        })
+    }
+}
+
+impl ResolvedConfig {
+    // printed by --check-config
+    pub fn summary(&self) -> String {
+        format!(
+            "Configuration is valid, the service would listen on {}",
+            self.bind
+        )
+    }
+}
+
+// parses a flag the way the same value is read from the file, e.g. "filesystem" or "High"
+pub fn parse_flag<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    T::deserialize(value.into_deserializer())
+        .map_err(|err: serde::de::value::Error| err.to_string())
+}
+
+fn non_zero(name: &str, value: usize) -> Result<usize, String> {
+    if value == 0 {
+        Err(format!("{name} must be greater than 0"))
+    } else {
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("citadel_service_config_{name}.toml"));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_reads_every_section() {
+        let path = write_config(
+            "load",
+            r#"
+            log_level = "debug"
+            download_directory = "/tmp/citadel-downloads"
+
+            [listeners]
+            service = "127.0.0.1:12345"
+
+            [backend]
+            kind = "filesystem"
+            path = "/tmp/citadel-backend"
+
+            [auth]
+            allowed_clients = ["127.0.0.1"]
+
+            [security]
+            default_security_level = "High"
+            default_secrecy_mode = "Perfect"
+
+            [limits]
+            response_queue_capacity = 16
+            kernel_queue_capacity = 32
+            idle_timeout_secs = 60
+            disconnect_on_overflow = true
+            "#,
+        );
+
+        let config = ServiceConfig::load(&path).unwrap();
+        assert_eq!(config.log_level.as_deref(), Some("debug"));
+        assert_eq!(
+            config.listeners.service,
+            Some("127.0.0.1:12345".parse().unwrap())
+        );
+        assert_eq!(config.backend.kind, BackendKind::Filesystem);
+        assert_eq!(
+            config.auth.allowed_clients,
+            Some(vec!["127.0.0.1".parse().unwrap()])
+        );
+        assert_eq!(
+            config.security.default_security_level,
+            Some(SecurityLevel::High)
+        );
+        assert_eq!(
+            config.security.default_secrecy_mode,
+            Some(SecrecyMode::Perfect)
+        );
+        assert_eq!(config.limits.kernel_queue_capacity, Some(32));
+    }
+
+    #[test]
+    fn load_rejects_unknown_fields() {
+        let path = write_config("unknown", "[listeners]\nservise = \"127.0.0.1:12345\"\n");
+        assert!(ServiceConfig::load(&path).is_err());
+    }
+
+    #[test]
+    fn resolve_applies_limits() {
+        let mut config = ServiceConfig::default();
+        config.listeners.service = Some("127.0.0.1:12345".parse().unwrap());
+        config.limits.response_queue_capacity = Some(16);
+        config.limits.idle_timeout_secs = Some(60);
+        config.limits.disconnect_on_overflow = Some(true);
+        config.security.default_security_level = Some(SecurityLevel::High);
+
+        let resolved = config.resolve().unwrap();
+        assert_eq!(resolved.client_settings.response_queue_capacity, 16);
+        assert_eq!(
+            resolved.client_settings.idle_timeout,
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            resolved.client_settings.overflow_policy,
+            ResponseOverflowPolicy::Disconnect
+        );
+        assert_eq!(
+            resolved.defaults.session_security_settings().security_level,
+            SecurityLevel::High
+        );
+        assert!(matches!(resolved.backend, BackendType::InMemory));
+    }
+
+    #[test]
+    fn resolve_rejects_invalid_values() {
+        let listener = || {
+            let mut config = ServiceConfig::default();
+            config.listeners.service = Some("127.0.0.1:12345".parse().unwrap());
+            config
+        };
+
+        assert!(ServiceConfig::default().resolve().is_err());
+
+        let mut config = listener();
+        config.backend.kind = BackendKind::Filesystem;
+        assert!(config.resolve().is_err());
+
+        let mut config = listener();
+        config.log_level = Some("loud".to_string());
+        assert!(config.resolve().is_err());
+
+        let mut config = listener();
+        config.limits.kernel_queue_capacity = Some(0);
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_file() {
+        let path = write_config(
+            "precedence",
+            r#"
+            log_level = "warn"
+            download_directory = "/tmp/citadel-downloads"
+
+            [listeners]
+            service = "127.0.0.1:12345"
+            "#,
+        );
+
+        let resolved = ServiceConfig::from_sources(
+            Some(&path),
+            Overrides {
+                bind: Some("127.0.0.1:23456".parse().unwrap()),
+                log_level: Some("debug".to_string()),
+                default_security_level: Some(SecurityLevel::High),
+                kernel_queue_capacity: Some(8),
+                disconnect_on_overflow: Some(true),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(resolved.bind, "127.0.0.1:23456".parse().unwrap());
+        assert_eq!(resolved.log_level.as_deref(), Some("debug"));
+        assert_eq!(resolved.defaults.security_level, Some(SecurityLevel::High));
+        assert_eq!(resolved.client_settings.kernel_queue_capacity, 8);
+        assert_eq!(
+            resolved.client_settings.overflow_policy,
+            ResponseOverflowPolicy::Disconnect
+        );
+        // values without a flag still come from the file
+        assert_eq!(
+            resolved.defaults.download_directory,
+            Some(PathBuf::from("/tmp/citadel-downloads"))
+        );
+    }
+
+    #[test]
+    fn flags_parse_like_the_file() {
+        assert_eq!(
+            parse_flag::<BackendKind>("filesystem"),
+            Ok(BackendKind::Filesystem)
+        );
+        assert_eq!(parse_flag::<SecurityLevel>("High"), Ok(SecurityLevel::High));
+        assert!(parse_flag::<BackendKind>("cloud").is_err());
+    }
+
+    #[test]
+    fn check_config_reports_the_listener() {
+        let path = write_config("check", "[listeners]\nservice = \"127.0.0.1:12345\"\n");
+        let resolved = ServiceConfig::from_sources(Some(&path), Overrides::default()).unwrap();
+        assert!(resolved.summary().contains("127.0.0.1:12345"));
+
+        let path = write_config("check_invalid", "[backend]\nkind = \"filesystem\"\n");
+        let err = ServiceConfig::from_sources(
+            Some(&path),
+            Overrides {
+                bind: Some("127.0.0.1:12345".parse().unwrap()),
+                ..Default::default()
+            },
+        )
+        .err()
+        .unwrap();
+        assert!(err.contains("backend.path"));
+    }
+}
@@ -1,16 +1,50 @@
-use citadel_sdk::prelude::{BackendType, NodeBuilder, NodeType};
+use citadel_sdk::prelude::{NodeBuilder, NodeType, SecrecyMode, SecurityLevel};
 use citadel_workspace_service::kernel::CitadelWorkspaceService;
+use config::{parse_flag, BackendKind, Overrides, ServiceConfig};
 use std::error::Error;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use structopt::StructOpt;
+use tracing_subscriber::EnvFilter;
+
+mod config;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    citadel_logging::setup_log();
     let opts: Options = Options::from_args();
-    let service = CitadelWorkspaceService::new(opts.bind);
+    let config = ServiceConfig::from_sources(
+        opts.config.as_deref(),
+        Overrides {
+            bind: opts.bind,
+            log_level: opts.log_level,
+            download_directory: opts.download_directory,
+            backend: opts.backend,
+            backend_path: opts.backend_path,
+            allowed_clients: (!opts.allowed_clients.is_empty()).then_some(opts.allowed_clients),
+            default_security_level: opts.default_security_level,
+            default_secrecy_mode: opts.default_secrecy_mode,
+            response_queue_capacity: opts.response_queue_capacity,
+            kernel_queue_capacity: opts.kernel_queue_capacity,
+            idle_timeout_secs: opts.idle_timeout_secs,
+            disconnect_on_overflow: opts.disconnect_on_overflow,
+        },
+    )?;
+    if opts.check_config {
+        println!("{}", config.summary());
+        return Ok(());
+    }
+
+    setup_log(config.log_level.as_deref());
+
+    let mut service = CitadelWorkspaceService::new(config.bind)
+        .with_client_settings(config.client_settings)
+        .with_defaults(config.defaults);
+    if let Some(allowed_clients) = config.allowed_clients {
+        service = service.with_allowed_clients(allowed_clients);
+    }
+
     NodeBuilder::default()
-        .with_backend(BackendType::InMemory) // TODO: parameterize this in the opts
+        .with_backend(config.backend)
         .with_node_type(NodeType::Peer) // We will only use the service to create outbound protocol connections
         .build(service)?
         .await?;
@@ -18,12 +52,56 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+// a configured level replaces RUST_LOG, which is only read when none is set
+fn setup_log(log_level: Option<&str>) {
+    match log_level {
+        Some(log_level) => {
+            let _ = tracing_subscriber::fmt()
+                .with_env_filter(EnvFilter::new(log_level))
+                .try_init();
+        }
+        None => citadel_logging::setup_log(),
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "citadel-service-bin",
     about = "Used for running a local service for citadel applications"
 )]
 struct Options {
+    #[structopt(short, long, env = "CITADEL_SERVICE_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
     #[structopt(short, long)]
-    bind: SocketAddr,
+    bind: Option<SocketAddr>,
+    #[structopt(long)]
+    log_level: Option<String>,
+    #[structopt(long, parse(from_os_str))]
+    download_directory: Option<PathBuf>,
+    #[structopt(long, parse(try_from_str = parse_flag), help = "in-memory or filesystem")]
+    backend: Option<BackendKind>,
+    #[structopt(long, parse(from_os_str))]
+    backend_path: Option<PathBuf>,
+    #[structopt(
+        long = "allowed-client",
+        help = "May be repeated; replaces auth.allowed_clients"
+    )]
+    allowed_clients: Vec<IpAddr>,
+    #[structopt(long, parse(try_from_str = parse_flag))]
+    default_security_level: Option<SecurityLevel>,
+    #[structopt(long, parse(try_from_str = parse_flag))]
+    default_secrecy_mode: Option<SecrecyMode>,
+    #[structopt(long)]
+    response_queue_capacity: Option<usize>,
+    #[structopt(long)]
+    kernel_queue_capacity: Option<usize>,
+    #[structopt(long)]
+    idle_timeout_secs: Option<u64>,
+    #[structopt(long)]
+    disconnect_on_overflow: Option<bool>,
+    #[structopt(
+        long,
+        help = "Validate the configuration and exit without starting the service"
+    )]
+    check_config: bool,
 }